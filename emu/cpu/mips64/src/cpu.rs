@@ -187,6 +187,37 @@ impl CpuContext {
     pub fn get_pc(&self) -> u64 {
         self.pc
     }
+
+    /// Create a context with the specified GPRs set, as (index, value)
+    /// pairs. This is only meant to drive coprocessors in tests; writes to
+    /// $zero are discarded like set_gpr does.
+    #[doc(hidden)]
+    pub fn with_gprs(gprs: &[(usize, u64)]) -> Self {
+        let mut ctx = Self::default();
+        for &(idx, val) in gprs {
+            ctx.set_gpr(idx, val);
+        }
+        ctx
+    }
+
+    /// Read a GPR on behalf of a coprocessor. $zero is hardwired, so
+    /// it always reads as 0.
+    #[inline]
+    pub fn gpr(&self, idx: usize) -> u64 {
+        if idx == 0 {
+            0
+        } else {
+            self.regs[idx]
+        }
+    }
+
+    /// Write a GPR on behalf of a coprocessor. Writes to $zero are discarded.
+    #[inline]
+    pub fn set_gpr(&mut self, idx: usize, val: u64) {
+        if idx != 0 {
+            self.regs[idx] = val;
+        }
+    }
 }
 
 macro_rules! branch {
@@ -1,15 +1,16 @@
 use super::decode::{MEMOP_FMT, REG_NAMES};
-use super::{Cop, CpuContext};
+use super::{Cop, CopError, CpuContext};
 
 use emu::dbg::{DebuggerRenderer, DecodedInsn, Operand, RegisterSize, RegisterView, Result, Tracer};
 use emu::int::Numerics;
 use emu::state::Field;
 
-use num::Float;
+use num::{Float, PrimInt};
 use serde_derive::{Deserialize, Serialize};
 use slog;
 use slog::*;
 use std::marker::PhantomData;
+use std::mem;
 
 const FPU_REG_NAMES: [&'static str; 32] = [
     "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7", "f8", "f9", "f10", "f11", "f12", "f13", "f14",
@@ -23,10 +24,37 @@ const FPU_CREG_NAMES: [&'static str; 32] = [
     "?25?", "?26?", "?27?", "?28?", "?29?", "?30?", "FCSR",
 ];
 
+// FPU exceptions, as laid out in the Flag (bits 2-6), Enable (bits 7-11) and
+// Cause (bits 12-17) fields of FCSR. Unimplemented Operation only exists in
+// Cause, and always traps.
+const FPE_INEXACT: u64 = 1 << 0;
+const FPE_UNDERFLOW: u64 = 1 << 1;
+const FPE_OVERFLOW: u64 = 1 << 2;
+const FPE_DIVZERO: u64 = 1 << 3;
+const FPE_INVALID: u64 = 1 << 4;
+const FPE_UNIMPLEMENTED: u64 = 1 << 5;
+
+// FCSR bits
+const FCSR_FLAG_SHIFT: u64 = 2;
+const FCSR_ENABLE_SHIFT: u64 = 7;
+const FCSR_CAUSE_SHIFT: u64 = 12;
+const FCSR_CAUSE_MASK: u64 = 0x3F << FCSR_CAUSE_SHIFT;
+
+// FIR (FCR0) of the VR4300: implementation 0x0A, revision 0x00.
+const FIR_VALUE: u64 = 0x0A00;
+
+// Functions (func field) of S/D-format opcodes implemented by Fpu::fop. Keep
+// in sync with its match arms.
+const FOP_FUNCS: [u8; 36] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+    0x0C, 0x0D, 0x0E, 0x0F, 0x20, 0x21, 0x24, 0x25, 0x30, 0x31, 0x32, 0x33,
+    0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+];
+
 #[derive(Default, Copy, Clone, Serialize, Deserialize)]
 struct FpuContext {
     regs: [u64; 32],
-    _fir: u64,
+    fir: u64,
     fccr: u64,
     _fexr: u64,
     _fenr: u64,
@@ -50,27 +78,85 @@ impl FpuContext {
             self.regs[idx + 1] = val >> 32;
         }
     }
+    // Single-precision values are always held in the low word of regs[idx]:
+    // in FR=0 mode, that is exactly the 32-bit register, so an odd register
+    // is the high half of the double in the even/odd pair, and writing it
+    // must not touch the other register of the pair.
     fn get_fpr<F: FloatRawConvert>(&self, idx: usize) -> F {
-        F::from_u64bits(self.get_fgr(idx))
+        if mem::size_of::<F>() == 4 {
+            F::from_u64bits(self.regs[idx] & 0xFFFF_FFFF)
+        } else {
+            F::from_u64bits(self.get_fgr(idx))
+        }
     }
     fn set_fpr<F: FloatRawConvert>(&mut self, idx: usize, val: F) {
-        self.set_fgr(idx, val.to_u64bits());
+        if mem::size_of::<F>() == 4 {
+            self.regs[idx] = val.to_u64bits();
+        } else {
+            self.set_fgr(idx, val.to_u64bits());
+        }
+    }
+
+    // Signal the exceptions exc (FPE_* bits) in the Cause field. The sticky
+    // Flag field accumulates them only if they don't trap.
+    fn raise(&mut self, exc: u64) {
+        self.fcsr |= exc << FCSR_CAUSE_SHIFT;
+        if !self.trapped() {
+            self.fcsr |= exc << FCSR_FLAG_SHIFT;
+        }
+    }
+
+    // Finish rounding an inexact result v, which the host computed rounding to
+    // nearest; up tells whether the exact result is above v. This signals the
+    // exceptions, and for the other rounding modes in FCSR moves v by one ulp
+    // if it is on the wrong side.
+    fn round_inexact<F: Float + FloatRawConvert>(&mut self, v: F, up: bool) -> F {
+        let exc = if v.is_infinite() {
+            FPE_INEXACT | FPE_OVERFLOW
+        } else if v.abs() < F::min_positive_value() {
+            FPE_INEXACT | FPE_UNDERFLOW
+        } else {
+            FPE_INEXACT
+        };
+        self.raise(exc);
+
+        match self.fcsr & 3 {
+            1 if v != F::zero() && up == (v < F::zero()) => v.next_ulp(up),
+            2 if up => v.next_ulp(true),
+            3 if !up => v.next_ulp(false),
+            _ => v,
+        }
+    }
+
+    // Check whether an exception in the Cause field is enabled, in which case
+    // the current instruction traps.
+    fn trapped(&self) -> bool {
+        let cause = (self.fcsr >> FCSR_CAUSE_SHIFT) & 0x3F;
+        let enable = ((self.fcsr >> FCSR_ENABLE_SHIFT) & 0x1F) | FPE_UNIMPLEMENTED;
+        cause & enable != 0
     }
 }
 
+/// Callback installed with `Fpu::set_nan_trap`. It receives the opcode, and
+/// the raw values of its fs and ft registers.
+pub type NanTrap = Box<dyn FnMut(u32, u64, u64)>;
+
 pub struct Fpu {
     ctx: Field<FpuContext>,
     logger: slog::Logger,
     cpu_name: &'static str,
+    // Error caused by the last failing opcode (see Cop::take_error).
+    error: Option<CopError>,
+    nan_trap: Option<NanTrap>,
 }
 
 trait FloatRawConvert {
     fn from_u64bits(v: u64) -> Self;
     fn to_u64bits(self) -> u64;
     fn bankers_round(self) -> Self;
+    fn next_ulp(self, up: bool) -> Self;
     fn to_f32(self) -> f32;
     fn to_f64(self) -> f64;
-    fn to_u64(self) -> u64;
 }
 
 impl FloatRawConvert for f32 {
@@ -88,15 +174,25 @@ impl FloatRawConvert for f32 {
             y
         }
     }
+    // Move to the adjacent representable value towards +inf (up) or -inf.
+    fn next_ulp(self, up: bool) -> Self {
+        if self == 0.0 {
+            let tiny = f32::from_bits(1);
+            return if up { tiny } else { -tiny };
+        }
+        let bits = self.to_bits();
+        if (self > 0.0) == up {
+            f32::from_bits(bits + 1)
+        } else {
+            f32::from_bits(bits - 1)
+        }
+    }
     fn to_f32(self) -> f32 {
         self as f32
     }
     fn to_f64(self) -> f64 {
         self as f64
     }
-    fn to_u64(self) -> u64 {
-        self as u64
-    }
 }
 
 impl FloatRawConvert for f64 {
@@ -114,15 +210,25 @@ impl FloatRawConvert for f64 {
             y
         }
     }
+    // Move to the adjacent representable value towards +inf (up) or -inf.
+    fn next_ulp(self, up: bool) -> Self {
+        if self == 0.0 {
+            let tiny = f64::from_bits(1);
+            return if up { tiny } else { -tiny };
+        }
+        let bits = self.to_bits();
+        if (self > 0.0) == up {
+            f64::from_bits(bits + 1)
+        } else {
+            f64::from_bits(bits - 1)
+        }
+    }
     fn to_f32(self) -> f32 {
         self as f32
     }
     fn to_f64(self) -> f64 {
         self as f64
     }
-    fn to_u64(self) -> u64 {
-        self as u64
-    }
 }
 
 struct Fop<'a, F: Float + FloatRawConvert> {
@@ -161,19 +267,175 @@ impl<'a, F: Float + FloatRawConvert> Fop<'a, F> {
     fn fgt(&self) -> u64 {
         self.ctx.get_fgr(self.rt())
     }
+    // A trapping instruction does not write its destination.
     fn set_fd(&mut self, v: F) {
-        self.ctx.set_fpr(self.rd(), v);
+        if !self.ctx.trapped() {
+            self.ctx.set_fpr(self.rd(), v);
+        }
     }
     fn set_fgd(&mut self, v: u64) {
-        self.ctx.set_fgr(self.rd(), v);
+        if !self.ctx.trapped() {
+            self.ctx.set_fgr(self.rd(), v);
+        }
+    }
+    fn set_fgd_w(&mut self, v: u32) {
+        if !self.ctx.trapped() {
+            self.ctx.regs[self.rd()] = v as u64;
+        }
+    }
+
+    fn raise(&mut self, exc: u64) {
+        self.ctx.raise(exc);
+    }
+
+    // Residual of a MUL, DIV or SQRT whose plain residual may underflow: this
+    // happens when the result v (MUL, DIV) or fs (DIV, SQRT) is close to the
+    // denormal range. The operation is repeated with fs scaled up by an even
+    // power of two: fs is small enough for this not to overflow. Only the
+    // sign of the result matters. (A tiny ADD/SUB result is always exact.)
+    fn scaled_residual(&self, v: F) -> F {
+        let (fs, ft) = (self.fs(), self.ft());
+        if v == F::zero() && fs != F::zero() && ft != F::zero() && ft.is_finite() {
+            return fs.signum() * ft.signum();
+        }
+
+        let scale = F::one() / (F::epsilon() * F::epsilon()) * num::cast::<_, F>(16).unwrap();
+        let fs = fs * scale;
+        let (vs, err, vscale) = match self.func() {
+            0x02 => {
+                let vs = fs * ft;
+                (vs, fs.mul_add(ft, -vs), scale)
+            }
+            0x03 => {
+                let vs = fs / ft;
+                (vs, (-vs).mul_add(ft, fs) * ft.signum(), scale)
+            }
+            _ => {
+                let vs = fs.sqrt();
+                (vs, (-vs).mul_add(vs, fs), scale.sqrt())
+            }
+        };
+        if vs != v * vscale {
+            vs - v * vscale
+        } else {
+            err
+        }
+    }
+
+    // Set fd to the result of an arithmetic opcode (ADD, SUB, MUL, DIV, SQRT),
+    // honoring the rounding mode in FCSR and signaling exceptions. The host
+    // computes v rounding to nearest; the exact residual of the operation
+    // tells whether v is inexact and on which side of v the exact result lies,
+    // and for the other modes v is moved by one ulp if it is on the wrong side.
+    fn set_fd_round(&mut self, v: F) {
+        let (fs, ft) = (self.fs(), self.ft());
+        let unary = self.func() == 0x04;
+        if v.is_nan() {
+            if !fs.is_nan() && (unary || !ft.is_nan()) {
+                self.raise(FPE_INVALID);
+                let (opcode, fgs, fgt) = (self.opcode, self.fgs(), self.fgt());
+                if let Some(ref mut trap) = self.fpu.nan_trap {
+                    trap(opcode, fgs, fgt);
+                }
+            }
+            self.set_fd(v);
+            return;
+        }
+
+        let small = F::min_positive_value() / (F::epsilon() * F::epsilon());
+        let scaled = match self.func() {
+            0x02 => v.abs() < small,
+            0x03 => v.abs() < F::min_positive_value() || fs.abs() < small,
+            0x04 => fs.abs() < small,
+            _ => false,
+        };
+        let err = if v.is_infinite() {
+            if !fs.is_finite() || (!unary && !ft.is_finite()) {
+                F::zero() // infinite operand: the result is exact
+            } else if self.func() == 0x03 && ft == F::zero() {
+                self.raise(FPE_DIVZERO);
+                F::zero()
+            } else {
+                -v // overflow: the exact result is finite
+            }
+        } else if scaled {
+            self.scaled_residual(v)
+        } else {
+            match self.func() {
+                0x00 | 0x01 => {
+                    // Fast2Sum: exact with the larger operand first, and
+                    // unlike TwoSum it can't overflow near the max value.
+                    let ft = if self.func() == 0x01 { -ft } else { ft };
+                    let (a, b) = if fs.abs() >= ft.abs() { (fs, ft) } else { (ft, fs) };
+                    b - (v - a)
+                }
+                0x02 => fs.mul_add(ft, -v),
+                0x03 => (-v).mul_add(ft, fs) * ft.signum(),
+                0x04 => (-v).mul_add(v, fs),
+                _ => unreachable!(),
+            }
+        };
+
+        if err.is_nan() || err == F::zero() {
+            // An exact zero sum of operands of opposite signs is -0 when
+            // rounding toward minus infinity, while the host returned +0.
+            let ft = if self.func() == 0x01 { -ft } else { ft };
+            let neg_zero = v == F::zero()
+                && self.func() <= 0x01
+                && self.ctx.fcsr & 3 == 3
+                && fs.is_sign_negative() != ft.is_sign_negative();
+            self.set_fd(if neg_zero { -F::zero() } else { v });
+            return;
+        }
+
+        let v = self.ctx.round_inexact(v, err > F::zero());
+        self.set_fd(v);
+    }
+}
+
+// Convert a float (already rounded to an integral value) into the integer type I.
+// Values that cannot be represented saturate to the max-magnitude integer with
+// the same sign (NaN saturates to the positive max); the second element of the
+// returned tuple signals that an invalid operation occurred.
+fn float_to_int<F: Float, I: PrimInt>(v: F) -> (I, bool) {
+    match num::cast::<F, I>(v) {
+        Some(i) => (i, false),
+        None if v.is_sign_negative() && !v.is_nan() => (I::min_value(), true),
+        None => (I::max_value(), true),
+    }
+}
+
+// Round to an integral value following the rounding mode in FCSR (bits 0-1),
+// as done by CVT.W and CVT.L.
+fn round_fcsr<F: Float + FloatRawConvert>(v: F, fcsr: u64) -> F {
+    match fcsr & 3 {
+        0 => v.bankers_round(),
+        1 => v.trunc(),
+        2 => v.ceil(),
+        _ => v.floor(),
     }
 }
 
 macro_rules! approx {
-    ($op:ident, $t:expr, $round:ident, $size:ident, $fallback:expr) => {{
-        match $op.fs().$round().$size() {
-            Some(v) => $op.set_fgd(v as u64),
-            None => $op.set_fgd($fallback as u64),
+    ($op:ident, $round:ident, $int:ty) => {{
+        let v = $op.fs().$round();
+        approx!($op, (v), $int)
+    }};
+    ($op:ident, $v:expr, $int:ty) => {{
+        let r = $v;
+        let (v, invalid) = float_to_int::<_, $int>(r);
+        if invalid {
+            $op.raise(FPE_INVALID);
+        } else if r != $op.fs() {
+            $op.raise(FPE_INEXACT);
+        }
+        // .W results only fill the low word of the register, .L results the
+        // whole register. The high word of a .W result is unpredictable on
+        // the VR4300: we clear it, so that MFHC1/DMFC1 read it as zero.
+        if mem::size_of::<$int>() == 4 {
+            $op.set_fgd_w(v as u32);
+        } else {
+            $op.set_fgd(v as u64);
         }
     }};
 }
@@ -186,7 +448,7 @@ macro_rules! cond {
         let less = if !nan { fs < ft } else { false };
         let equal = if !nan { fs == ft } else { false };
         if nan && $func & 8 != 0 {
-            panic!("signal FPU NaN in comparison");
+            $op.raise(FPE_INVALID);
         }
 
         let cond =
@@ -211,14 +473,47 @@ macro_rules! fp_suffix {
 impl Fpu {
     pub fn new(cpu_name: &'static str, logger: slog::Logger) -> Fpu {
         Fpu {
-            ctx: Field::new(&("mips64".to_owned() + cpu_name + "::fpu"), FpuContext::default()),
+            ctx: Field::new(
+                &("mips64".to_owned() + cpu_name + "::fpu"),
+                FpuContext {
+                    fir: FIR_VALUE,
+                    ..FpuContext::default()
+                },
+            ),
             logger,
             cpu_name,
+            error: None,
+            nan_trap: None,
         }
     }
 
+    /// Return the functions (values of the func field) of S/D-format
+    /// arithmetic opcodes implemented by the FPU.
+    pub fn supported_funcs() -> &'static [u8] {
+        &FOP_FUNCS
+    }
+
+    /// Install a callback, invoked whenever an arithmetic opcode (ADD, SUB,
+    /// MUL, DIV, SQRT) produces a NaN out of operands that are not NaNs: this
+    /// is where bad values start propagating. For SQRT, ft is meaningless.
+    /// Pass None to remove it; there is no overhead when unset.
+    pub fn set_nan_trap(&mut self, trap: Option<NanTrap>) {
+        self.nan_trap = trap;
+    }
+
+    /// Reset the FPU to its power-on state: all registers and flags are
+    /// cleared (including the condition bits and any pending error), and FIR
+    /// is set to its constant value.
+    pub fn reset(&mut self) {
+        *self.ctx = FpuContext {
+            fir: FIR_VALUE,
+            ..FpuContext::default()
+        };
+        self.error = None;
+    }
+
     fn set_cc(&mut self, cc: usize, val: bool) {
-        if cc > 8 {
+        if cc >= 8 {
             panic!("invalid cc code");
         }
         self.ctx.fccr = (self.ctx.fccr & !(1 << cc)) | ((val as u64) << cc);
@@ -230,12 +525,63 @@ impl Fpu {
     }
 
     fn get_cc(&mut self, cc: usize) -> bool {
-        if cc > 8 {
+        if cc >= 8 {
             panic!("invalid cc code");
         }
         (self.ctx.fccr & (1 << cc)) != 0
     }
 
+    // CVT.S.fmt and CVT.D.fmt for the integer formats (W and L). Like fop(),
+    // these set the Cause field to the exceptions raised by the conversion.
+    fn cvt_int(&mut self, opcode: u32, t: &Tracer) -> Result<()> {
+        let func = opcode & 0x3f;
+        let fmt = (opcode >> 21) & 0x1F;
+        let rs = ((opcode >> 11) & 0x1F) as usize;
+        let rd = ((opcode >> 6) & 0x1F) as usize;
+        let val = if fmt == 0x14 {
+            self.ctx.get_fgr(rs) as i32 as i64
+        } else {
+            self.ctx.get_fgr(rs) as i64
+        };
+
+        // The host rounds to nearest; the other rounding modes are applied by
+        // comparing the result with the exact integer.
+        self.ctx.fcsr &= !FCSR_CAUSE_MASK;
+        match func {
+            0x20 => {
+                // CVT.S.W, CVT.S.L
+                let mut v = val as f32;
+                if v as i128 != val as i128 {
+                    v = self.ctx.round_inexact(v, val as i128 > v as i128);
+                }
+                if !self.ctx.trapped() {
+                    self.ctx.set_fpr(rd, v);
+                }
+            }
+            0x21 => {
+                // CVT.D.W, CVT.D.L
+                let mut v = val as f64;
+                if v as i128 != val as i128 {
+                    v = self.ctx.round_inexact(v, val as i128 > v as i128);
+                }
+                if !self.ctx.trapped() {
+                    self.ctx.set_fpr(rd, v);
+                }
+            }
+            _ => {
+                let name = if fmt == 0x14 { "W" } else { "L" };
+                error!(self.logger, "unimplemented COP1 {}: func={:x?}", name, func);
+                self.error = Some(CopError::UnimplementedOp(opcode));
+                return t.break_here("unimplemented COP1 integer format opcode");
+            }
+        }
+        if self.ctx.trapped() {
+            error!(self.logger, "FPU exception trap: fcsr={:x?}", self.ctx.fcsr);
+            return t.break_here("FPU exception trap");
+        }
+        Ok(())
+    }
+
     fn fop<M: Float + FloatRawConvert>(
         &mut self,
         cpu: &mut CpuContext,
@@ -249,60 +595,76 @@ impl Fpu {
             cpu: cpu,
             phantom: PhantomData,
         };
+        if op.func() == 0x06 {
+            // MOV.fmt is a bit-exact copy that bypasses FCSR entirely: it
+            // doesn't quiet signaling NaNs, and doesn't even clear Cause.
+            let (fs, fd) = (op.rs(), op.rd());
+            if mem::size_of::<M>() == 4 {
+                op.ctx.regs[fd] = op.ctx.regs[fs] & 0xFFFF_FFFF;
+            } else {
+                let v = op.ctx.get_fgr(fs);
+                op.ctx.set_fgr(fd, v);
+            }
+            return Ok(());
+        }
+        op.ctx.fcsr &= !FCSR_CAUSE_MASK;
         match op.func() {
             0x00 => {
                 // ADD.fmt
                 let v = op.fs() + op.ft();
-                op.set_fd(v)
+                op.set_fd_round(v)
             }
             0x01 => {
                 // SUB.fmt
                 let v = op.fs() - op.ft();
-                op.set_fd(v)
+                op.set_fd_round(v)
             }
             0x02 => {
                 // MUL.with_fmtt
                 let v = op.fs() * op.ft();
-                op.set_fd(v)
+                op.set_fd_round(v)
             }
             0x03 => {
                 // DIV.fmt
                 let v = op.fs() / op.ft();
-                op.set_fd(v)
+                op.set_fd_round(v)
             }
             0x04 => {
                 // SQRT.fmt
                 let v = op.fs().sqrt();
-                op.set_fd(v)
+                op.set_fd_round(v)
             }
             0x05 => {
                 // ABS.fmt
                 let v = op.fs().abs();
                 op.set_fd(v)
             }
-            0x06 => {
-                // MOV.fmt
-                let v = op.fs();
-                op.set_fd(v);
-            }
             0x07 => {
                 // NEG.fmt
                 let v = op.fs().neg();
                 op.set_fd(v)
             }
-            0x08 => approx!(op, t, bankers_round, to_i64, i64::max_value()), // ROUND.L.fmt
-            0x09 => approx!(op, t, trunc, to_i64, i64::max_value()),         // TRUNC.L.fmt
-            0x0A => approx!(op, t, ceil, to_i64, i64::max_value()),          // CEIL.L.fmt
-            0x0B => approx!(op, t, floor, to_i64, i64::max_value()),         // FLOOR.L.fmt
-            0x0C => approx!(op, t, bankers_round, to_i32, i32::max_value()), // ROUND.W.fmt
-            0x0D => approx!(op, t, trunc, to_i32, i32::max_value()),         // TRUNC.W.fmt
-            0x0E => approx!(op, t, ceil, to_i32, i32::max_value()),          // CEIL.W.fmt
-            0x0F => approx!(op, t, floor, to_i32, i32::max_value()),         // FLOOR.W.fmt
+            0x08 => approx!(op, bankers_round, i64), // ROUND.L.fmt
+            0x09 => approx!(op, trunc, i64),         // TRUNC.L.fmt
+            0x0A => approx!(op, ceil, i64),          // CEIL.L.fmt
+            0x0B => approx!(op, floor, i64),         // FLOOR.L.fmt
+            0x0C => approx!(op, bankers_round, i32), // ROUND.W.fmt
+            0x0D => approx!(op, trunc, i32),         // TRUNC.W.fmt
+            0x0E => approx!(op, ceil, i32),          // CEIL.W.fmt
+            0x0F => approx!(op, floor, i32),         // FLOOR.W.fmt
 
-            0x20 => op.set_fgd(op.fs().to_f32().to_u64bits()), // CVT.S.fmt
+            0x20 => {
+                // CVT.S.fmt
+                let v = op.fs().to_f64();
+                let mut s = v as f32;
+                if !s.is_nan() && s as f64 != v {
+                    s = op.ctx.round_inexact(s, v > s as f64);
+                }
+                op.set_fgd(s.to_u64bits())
+            }
             0x21 => op.set_fgd(op.fs().to_f64().to_u64bits()), // CVT.D.fmt
-            0x24 => op.set_fgd(op.fs().to_u64() as u32 as u64), // CVT.W.fmt
-            0x25 => op.set_fgd(op.fs().to_u64()),              // CVT.L.fmt
+            0x24 => approx!(op, round_fcsr(op.fs(), op.ctx.fcsr), i32), // CVT.W.fmt
+            0x25 => approx!(op, round_fcsr(op.fs(), op.ctx.fcsr), i64), // CVT.L.fmt
 
             0x30 => cond!(op, 0x30), // C.T.fmt
             0x31 => cond!(op, 0x31), // C.UN.fmt
@@ -311,7 +673,7 @@ impl Fpu {
             0x34 => cond!(op, 0x34), // C.OLT.fmt
             0x35 => cond!(op, 0x35), // C.ULT.fmt
             0x36 => cond!(op, 0x36), // C.OLE.fmt
-            0x37 => cond!(op, 0x37), // C.ULT.fmt
+            0x37 => cond!(op, 0x37), // C.ULE.fmt
             0x38 => cond!(op, 0x38), // C.SF.fmt
             0x39 => cond!(op, 0x39), // C.NGLE.fmt
             0x3A => cond!(op, 0x3A), // C.SEQ.fmt
@@ -327,9 +689,15 @@ impl Fpu {
                     "unimplemented COP1 opcode: func={:x?}",
                     op.func()
                 );
+                op.fpu.error = Some(CopError::UnimplementedOp(opcode));
                 return t.break_here("unimplemented COP1 opcode");
             }
         }
+        if op.ctx.trapped() {
+            // The CPU has no way to take a floating-point exception yet.
+            error!(op.fpu.logger, "FPU exception trap: fcsr={:x?}", op.ctx.fcsr);
+            return t.break_here("FPU exception trap");
+        }
         Ok(())
     }
 }
@@ -341,6 +709,29 @@ impl Cop for Fpu {
     fn set_reg(&mut self, _cpu: &mut CpuContext, idx: usize, val: u128) {
         self.ctx.regs[idx] = val as u64;
     }
+    fn try_reg(&self, cpu: &CpuContext, idx: usize) -> std::result::Result<u128, CopError> {
+        match idx {
+            0..=31 => Ok(self.reg(cpu, idx)),
+            _ => Err(CopError::BadRegister(idx)),
+        }
+    }
+    fn try_set_reg(
+        &mut self,
+        cpu: &mut CpuContext,
+        idx: usize,
+        val: u128,
+    ) -> std::result::Result<(), CopError> {
+        match idx {
+            0..=31 => {
+                self.set_reg(cpu, idx, val);
+                Ok(())
+            }
+            _ => Err(CopError::BadRegister(idx)),
+        }
+    }
+    fn take_error(&mut self) -> Option<CopError> {
+        self.error.take()
+    }
 
     fn op(&mut self, cpu: &mut CpuContext, opcode: u32, t: &Tracer) -> Result<()> {
         self.ctx.fpu64 = cpu.fpu64; // copy current fpu64 mode bit (from COP0)
@@ -351,27 +742,47 @@ impl Cop for Fpu {
         let rd = ((opcode >> 6) & 0x1F) as usize;
         match fmt {
             0x0 => cpu.regs[rt] = (self.ctx.regs[rs] as u32).sx64(), // MFC1
+            0x1 => cpu.regs[rt] = self.ctx.get_fgr(rs),              // DMFC1
             0x2 => match rs {
                 // CFC1
+                0 => cpu.regs[rt] = self.ctx.fir,
                 31 => cpu.regs[rt] = self.ctx.fcsr,
                 _ => {
                     error!(self.logger, "CFC1 from unknown register: {:x}", rs);
+                    self.error = Some(CopError::BadRegister(rs));
                     return t.break_here("CFC1 from unknown register");
                 }
             },
-            0x4 => self.ctx.regs[rs] = (cpu.regs[rt] as u32) as u64, // MTC1
+            0x3 => cpu.regs[rt] = ((self.ctx.regs[rs] >> 32) as u32).sx64(), // MFHC1
+            0x4 => {
+                // MTC1: only the low word is written, so that it can be paired
+                // with MTHC1 to build a 64-bit value.
+                let hi = self.ctx.regs[rs] & 0xFFFF_FFFF_0000_0000;
+                self.ctx.regs[rs] = hi | (cpu.regs[rt] as u32) as u64;
+            }
             0x5 => self.ctx.set_fgr(rs, cpu.regs[rt]), // DMTC1
             0x6 => match rs {
                 // CTC1
-                31 => self.ctx.fcsr = cpu.regs[rt],
+                31 => {
+                    self.ctx.fcsr = cpu.regs[rt];
+                    // Keep FCC[0..7] in sync: FCC0 is bit 23, FCC1-7 are bits 25..31.
+                    let fcsr = self.ctx.fcsr;
+                    self.ctx.fccr = ((fcsr >> 23) & 1) | ((fcsr >> 24) & 0xFE);
+                }
                 _ => {
                     error!(self.logger, "CTC1 to unknown register: {:x}", rs);
+                    self.error = Some(CopError::BadRegister(rs));
                     return t.break_here("CTC1 to unknown register");
                 }
             },
+            0x7 => {
+                // MTHC1
+                let lo = self.ctx.regs[rs] & 0xFFFF_FFFF;
+                self.ctx.regs[rs] = lo | ((cpu.regs[rt] as u32 as u64) << 32);
+            }
             0x8 => {
                 let tgt = cpu.pc + (opcode as u16).sx64() * 4;
-                let cc = ((opcode >> 18) & 3) as usize;
+                let cc = ((opcode >> 18) & 7) as usize;
                 let nd = opcode & (1 << 17) != 0;
                 let tf = opcode & (1 << 16) != 0;
                 let cond = self.get_cc(cc) == tf;
@@ -380,41 +791,11 @@ impl Cop for Fpu {
             0x10 => return self.fop::<f32>(cpu, opcode, t),
             0x11 => return self.fop::<f64>(cpu, opcode, t),
 
-            0x14 => match func {
-                0x20 => {
-                    // CVT.S.W
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i32 as f32);
-                }
-                0x21 => {
-                    // CVT.D.W
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i32 as f64);
-                }
-                _ => {
-                    error!(self.logger, "unimplemented COP1 W: func={:x?}", func);
-                    return t.break_here("unimplemented COP1 W opcode");
-                }
-            },
-            0x15 => match func {
-                0x20 => {
-                    // CVT.S.L
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i64 as f32);
-                }
-                0x21 => {
-                    // CVT.D.L
-                    let fgs = self.ctx.get_fgr(rs);
-                    self.ctx.set_fpr(rd, fgs as i64 as f64);
-                }
-                _ => {
-                    error!(self.logger, "unimplemented COP1 L: func={:x?}", func);
-                    return t.break_here("unimplemented COP1 L opcode");
-                }
-            },
+            0x14 | 0x15 => return self.cvt_int(opcode, t),
 
             _ => {
                 error!(self.logger, "unimplemented COP1 fmt: fmt={:x?}", fmt);
+                self.error = Some(CopError::UnimplementedOp(opcode));
                 return t.break_here("unimplemented COP1 opcode");
             }
         }
@@ -435,13 +816,16 @@ impl Cop for Fpu {
                 let cfs = FPU_CREG_NAMES[((opcode >> 11) & 0x1f) as usize].into();
                 match fmt {
                     0x0 => DecodedInsn::new2("mfc1", OReg(rt), IReg(fs)),
+                    0x1 => DecodedInsn::new2("dmfc1", OReg(rt), IReg(fs)),
                     0x2 => DecodedInsn::new2("cfc1", OReg(rt), IReg(cfs)),
+                    0x3 => DecodedInsn::new2("mfhc1", OReg(rt), IReg(fs)),
                     0x4 => DecodedInsn::new2("mtc1", IReg(rt), OReg(fs)),
                     0x5 => DecodedInsn::new2("dmtc1", IReg(rt), OReg(fs)),
                     0x6 => DecodedInsn::new2("ctc1", IReg(rt), OReg(cfs)),
+                    0x7 => DecodedInsn::new2("mthc1", IReg(rt), OReg(fs)),
                     0x8 => {
                         let tgt = pc + 4 + (opcode as u16).sx64() * 4;
-                        let cc = ((opcode >> 18) & 3) as usize;
+                        let cc = ((opcode >> 18) & 7) as usize;
                         let nd = opcode & (1 << 17) != 0;
                         let tf = opcode & (1 << 16) != 0;
                         let name = if tf {
@@ -531,7 +915,7 @@ impl Cop for Fpu {
                             0x34 => DecodedInsn::new2(fp_suffix!("c.olt", fmt), IReg(fs), IReg(ft)),
                             0x35 => DecodedInsn::new2(fp_suffix!("c.ult", fmt), IReg(fs), IReg(ft)),
                             0x36 => DecodedInsn::new2(fp_suffix!("c.ole", fmt), IReg(fs), IReg(ft)),
-                            0x37 => DecodedInsn::new2(fp_suffix!("c.ult", fmt), IReg(fs), IReg(ft)),
+                            0x37 => DecodedInsn::new2(fp_suffix!("c.ule", fmt), IReg(fs), IReg(ft)),
                             0x38 => DecodedInsn::new2(fp_suffix!("c.sf", fmt), IReg(fs), IReg(ft)),
                             0x39 => {
                                 DecodedInsn::new2(fp_suffix!("c.ngle", fmt), IReg(fs), IReg(ft))
@@ -640,3 +1024,1104 @@ impl RegisterView for Fpu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emu::dbg::Debugger;
+    use std::cell::RefCell;
+    use std::cmp::Ordering;
+    use std::rc::Rc;
+
+    const FMT_S: u32 = 0x10;
+    const FMT_D: u32 = 0x11;
+
+    const FCSR_FLAG_INVALID: u64 = FPE_INVALID << FCSR_FLAG_SHIFT;
+    const FCSR_CAUSE_INVALID: u64 = FPE_INVALID << FCSR_CAUSE_SHIFT;
+
+    fn new_fpu() -> (Fpu, CpuContext) {
+        let logger = slog::Logger::root(slog::Discard, o!());
+        let mut cpu = CpuContext::default();
+        cpu.fpu64 = true;
+        (Fpu::new("test", logger), cpu)
+    }
+
+    fn fop(fmt: u32, func: u32, fd: u32, fs: u32, ft: u32) -> u32 {
+        (0x11 << 26) | (fmt << 21) | (ft << 16) | (fs << 11) | (fd << 6) | func
+    }
+
+    #[test]
+    fn test_trunc_w_overflow() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = (3e9f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+        assert!(fpu.ctx.fcsr & FCSR_CAUSE_INVALID != 0);
+
+        fpu.ctx.fcsr = 0;
+        fpu.ctx.regs[2] = (-3e9f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x8000_0000);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+
+        fpu.ctx.fcsr = 0;
+        fpu.ctx.regs[2] = (3e9f32).to_bits() as u64;
+        fpu.op(&mut cpu, fop(FMT_S, 0x0C, 4, 2, 0), &t).unwrap(); // ROUND.W.S f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+    }
+
+    #[test]
+    fn test_cvt_w_boundary() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        // Truncation stays within range: valid.
+        fpu.ctx.regs[2] = (2147483647.9f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID == 0);
+
+        // Round to nearest goes past i32::MAX: invalid, clamped.
+        fpu.op(&mut cpu, fop(FMT_D, 0x24, 4, 2, 0), &t).unwrap(); // CVT.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+        assert!(fpu.ctx.fcsr & FCSR_CAUSE_INVALID != 0);
+
+        // Same value, but rounding toward zero (RM=1): valid.
+        fpu.ctx.fcsr = 1;
+        fpu.op(&mut cpu, fop(FMT_D, 0x24, 4, 2, 0), &t).unwrap(); // CVT.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID == 0);
+
+        // 2^31 is never representable.
+        fpu.ctx.fcsr = 0;
+        fpu.ctx.regs[2] = (2147483648.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, 0x7FFF_FFFF);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+
+        // Negative values are converted correctly.
+        fpu.ctx.fcsr = 0;
+        fpu.ctx.regs[2] = (-2.5f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x24, 4, 2, 0), &t).unwrap(); // CVT.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4] as u32, -2i32 as u32);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID == 0);
+    }
+
+    #[test]
+    fn test_trunc_l_overflow() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = (3e9f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x09, 4, 2, 0), &t).unwrap(); // TRUNC.L.D f4,f2
+        assert_eq!(fpu.ctx.regs[4], 3_000_000_000);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID == 0);
+
+        fpu.ctx.regs[2] = (-1e30f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x09, 4, 2, 0), &t).unwrap(); // TRUNC.L.D f4,f2
+        assert_eq!(fpu.ctx.regs[4], 0x8000_0000_0000_0000);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+    }
+
+    #[test]
+    fn test_cond_cc_select() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        cpu.pc = 0x1000;
+
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = (1.0f64).to_bits();
+        fpu.ctx.regs[6] = (2.0f64).to_bits();
+
+        // C.EQ.D cc3,f2,f4: only FCC3 is set.
+        fpu.op(&mut cpu, fop(FMT_D, 0x32, 3 << 2, 2, 4), &t).unwrap();
+        assert_eq!(fpu.ctx.fccr, 1 << 3);
+        assert_eq!(fpu.ctx.fcsr, 1 << 27);
+
+        // C.EQ.D cc0,f2,f6: false, FCC3 untouched.
+        fpu.op(&mut cpu, fop(FMT_D, 0x32, 0, 2, 6), &t).unwrap();
+        assert_eq!(fpu.ctx.fccr, 1 << 3);
+
+        // C.EQ.D cc0,f2,f4: true, both set.
+        fpu.op(&mut cpu, fop(FMT_D, 0x32, 0, 2, 4), &t).unwrap();
+        assert_eq!(fpu.ctx.fccr, (1 << 3) | 1);
+        assert_eq!(fpu.ctx.fcsr, (1 << 27) | (1 << 23));
+
+        // C.EQ.D cc3,f2,f6: clears FCC3 only.
+        fpu.op(&mut cpu, fop(FMT_D, 0x32, 3 << 2, 2, 6), &t).unwrap();
+        assert_eq!(fpu.ctx.fccr, 1);
+        assert_eq!(fpu.ctx.fcsr, 1 << 23);
+    }
+
+    #[test]
+    fn test_hi_lo_untouched() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let cop1 = |fmt: u32, rt: u32, fs: u32| {
+            (0x11 << 26) | (fmt << 21) | (rt << 16) | (fs << 11)
+        };
+
+        // HI/LO belong to the integer unit: as left by a DIV, they survive
+        // any COP1 opcode, including the floating-point DIV.
+        cpu.hi = 0x1111_2222_3333_4444;
+        cpu.lo = 0x5555_6666_7777_8888;
+        cpu.regs[1] = 3.0f64.to_bits();
+        fpu.ctx.regs[2] = 1.0f64.to_bits();
+        for &op in &[
+            cop1(0x5, 1, 4),             // DMTC1 r1,f4
+            fop(FMT_D, 0x03, 6, 2, 4),   // DIV.D f6,f2,f4
+            fop(FMT_S, 0x03, 8, 2, 4),   // DIV.S f8,f2,f4
+            fop(FMT_D, 0x0D, 10, 6, 0),  // TRUNC.W.D f10,f6
+            cop1(0x1, 2, 6),             // DMFC1 r2,f6
+            cop1(0x2, 3, 31),            // CFC1 r3,fcsr
+            cop1(0x6, 0, 31),            // CTC1 r0,fcsr
+        ] {
+            fpu.op(&mut cpu, op, &t).unwrap();
+            assert_eq!(cpu.hi, 0x1111_2222_3333_4444, "op={:08x}", op);
+            assert_eq!(cpu.lo, 0x5555_6666_7777_8888, "op={:08x}", op);
+        }
+        assert_eq!(cpu.regs[2], (1.0f64 / 3.0).to_bits());
+    }
+
+    #[test]
+    fn test_cop_errors() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        assert_eq!(fpu.take_error(), None);
+
+        // Without a debugger attached, the errors are only reported here.
+        let op = fop(0x12, 0x00, 0, 0, 0); // unknown fmt
+        fpu.op(&mut cpu, op, &t).unwrap();
+        assert_eq!(fpu.take_error(), Some(CopError::UnimplementedOp(op)));
+        assert_eq!(fpu.take_error(), None);
+
+        let cfc1 = (0x11 << 26) | (0x2 << 21) | (1 << 16) | (5 << 11); // CFC1 r1,$5
+        fpu.op(&mut cpu, cfc1, &t).unwrap();
+        assert_eq!(fpu.take_error(), Some(CopError::BadRegister(5)));
+
+        fpu.ctx.regs[3] = 0x1234;
+        assert_eq!(fpu.try_reg(&cpu, 3), Ok(0x1234));
+        assert_eq!(fpu.try_reg(&cpu, 32), Err(CopError::BadRegister(32)));
+        assert_eq!(fpu.try_set_reg(&mut cpu, 32, 0), Err(CopError::BadRegister(32)));
+        assert_eq!(fpu.try_set_reg(&mut cpu, 31, 0x5678), Ok(()));
+        assert_eq!(fpu.ctx.regs[31], 0x5678);
+    }
+
+    #[test]
+    fn test_cond_signaling_nan() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = std::f64::NAN.to_bits();
+        for func in 0x30..0x40 {
+            // Quiet predicates (C.F..C.ULE) ignore NaN operands, signaling
+            // ones (C.SF..C.NGT, bit 3 set) raise invalid operation.
+            let signaling = func & 8 != 0;
+            fpu.ctx.fcsr = 0;
+            fpu.op(&mut cpu, fop(FMT_D, func, 0, 2, 4), &t).unwrap();
+            assert_eq!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0, signaling, "func={:x}", func);
+            assert_eq!(fpu.ctx.fcsr & FCSR_CAUSE_INVALID != 0, signaling, "func={:x}", func);
+            // The unordered bit selects the result, in both cases.
+            assert_eq!(fpu.get_cc(0), func & 1 != 0, "func={:x}", func);
+        }
+    }
+
+    #[test]
+    fn test_mov_signaling_nan() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        // Leftover Cause and Flag bits, and round toward zero.
+        let fcsr = (FPE_INEXACT << FCSR_CAUSE_SHIFT) | FCSR_FLAG_INVALID | 1;
+
+        // Signaling NaNs (quiet bit clear) with a payload survive bit-exact,
+        // and FCSR is not touched, not even the Cause field.
+        fpu.ctx.regs[1] = 0x7F80_0001;
+        fpu.ctx.fcsr = fcsr;
+        fpu.op(&mut cpu, fop(FMT_S, 0x06, 2, 1, 0), &t).unwrap(); // MOV.S f2,f1
+        assert_eq!(fpu.ctx.regs[2], 0x7F80_0001);
+        assert_eq!(fpu.ctx.fcsr, fcsr);
+
+        fpu.ctx.regs[4] = 0xFFF4_0000_DEAD_BEEF;
+        fpu.op(&mut cpu, fop(FMT_D, 0x06, 6, 4, 0), &t).unwrap(); // MOV.D f6,f4
+        assert_eq!(fpu.ctx.regs[6], 0xFFF4_0000_DEAD_BEEF);
+        assert_eq!(fpu.ctx.fcsr, fcsr);
+    }
+
+    #[test]
+    fn test_bc1_cc_select() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let bc1 = |cc: u32, tf: u32| (0x11 << 26) | (0x8 << 21) | (cc << 18) | (tf << 16) | 0x10;
+
+        // FCC3 set, FCC0 clear.
+        cpu.regs[1] = 1 << 27;
+        fpu.op(&mut cpu, (0x11 << 26) | (0x6 << 21) | (1 << 16) | (31 << 11), &t).unwrap(); // CTC1 r1,fcsr
+        assert_eq!(fpu.ctx.fccr, 1 << 3);
+
+        for &(cc, tf, taken) in &[(3, 1, true), (3, 0, false), (0, 1, false), (0, 0, true)] {
+            cpu.pc = 0x1000;
+            cpu.next_pc = 0x1004;
+            fpu.op(&mut cpu, bc1(cc, tf), &t).unwrap();
+            let tgt = if taken { 0x1040 } else { 0x1004 };
+            assert_eq!(cpu.next_pc, tgt, "cc={} tf={}", cc, tf);
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x32, 3 << 2, 2, 2), &t).unwrap(); // C.EQ.D cc3,f2,f2
+        fpu.ctx.fcsr |= 3;
+        assert_ne!(fpu.ctx.fccr, 0);
+
+        fpu.reset();
+        assert!(fpu.ctx.regs.iter().all(|&r| r == 0));
+        assert_eq!(fpu.ctx.fccr, 0);
+        assert_eq!(fpu.ctx.fcsr, 0);
+
+        fpu.op(&mut cpu, (0x11 << 26) | (0x2 << 21) | (1 << 16), &t).unwrap(); // CFC1 r1,fir
+        assert_eq!(cpu.regs[1], FIR_VALUE);
+    }
+
+    #[test]
+    fn test_reset_fcsr() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let cfc1 = |rt: u32, fs: u32| (0x11 << 26) | (0x2 << 21) | (rt << 16) | (fs << 11);
+        let ctc1 = |rt: u32, fs: u32| (0x11 << 26) | (0x6 << 21) | (rt << 16) | (fs << 11);
+
+        // All condition bits, FS, and every cause/enable/flag bit, with
+        // rounding towards minus infinity.
+        cpu.regs[1] = 0xFFFF_FFFF;
+        fpu.op(&mut cpu, ctc1(1, 31), &t).unwrap();
+        for cc in 0..8 {
+            assert!(fpu.get_cc(cc), "cc={}", cc);
+        }
+        fpu.op(&mut cpu, cfc1(1, 5), &t).unwrap(); // CFC1 to a bad register
+        assert!(fpu.error.is_some());
+
+        fpu.reset();
+        assert_eq!(fpu.take_error(), None);
+        for cc in 0..8 {
+            assert!(!fpu.get_cc(cc), "cc={}", cc);
+        }
+        fpu.op(&mut cpu, cfc1(2, 31), &t).unwrap();
+        assert_eq!(cpu.regs[2], 0);
+
+        // FIR: implementation 0x0A (VR4300) in bits 8-15, revision 0.
+        fpu.op(&mut cpu, cfc1(3, 0), &t).unwrap();
+        assert_eq!((cpu.regs[3] >> 8) & 0xFF, 0x0A);
+        assert_eq!(cpu.regs[3] & 0xFF, 0);
+    }
+
+    #[test]
+    fn test_nan_trap() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let trapped = Rc::new(RefCell::new(Vec::new()));
+        let log = trapped.clone();
+        fpu.set_nan_trap(Some(Box::new(move |op, fs, ft| {
+            log.borrow_mut().push((op, fs, ft))
+        })));
+
+        let div = fop(FMT_D, 0x03, 6, 2, 4); // DIV.D f6,f2,f4
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = (2.0f64).to_bits();
+        fpu.op(&mut cpu, div, &t).unwrap();
+        assert!(trapped.borrow().is_empty());
+
+        fpu.ctx.regs[2] = 0;
+        fpu.ctx.regs[4] = 0;
+        fpu.op(&mut cpu, div, &t).unwrap();
+        assert_eq!(*trapped.borrow(), vec![(div, 0, 0)]);
+
+        // A NaN operand only propagates: it is not a new NaN.
+        fpu.ctx.regs[2] = f64::NAN.to_bits();
+        fpu.ctx.regs[4] = (2.0f64).to_bits();
+        fpu.op(&mut cpu, div, &t).unwrap();
+        assert_eq!(trapped.borrow().len(), 1);
+
+        fpu.set_nan_trap(None);
+        fpu.ctx.regs[2] = 0;
+        fpu.ctx.regs[4] = 0;
+        fpu.op(&mut cpu, div, &t).unwrap();
+        assert_eq!(trapped.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_mthc1_mtc1() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let mov = |fmt: u32, rt: u32, fs: u32| {
+            (0x11 << 26) | (fmt << 21) | (rt << 16) | (fs << 11)
+        };
+
+        let val = (-1234.5678f64).to_bits();
+        cpu.regs[1] = (val as u32).sx64();
+        cpu.regs[2] = ((val >> 32) as u32).sx64();
+        fpu.op(&mut cpu, mov(0x7, 2, 4), &t).unwrap(); // MTHC1 r2,f4
+        fpu.op(&mut cpu, mov(0x4, 1, 4), &t).unwrap(); // MTC1 r1,f4
+        fpu.op(&mut cpu, mov(0x1, 3, 4), &t).unwrap(); // DMFC1 r3,f4
+        assert_eq!(cpu.regs[3], val);
+
+        // The opposite order assembles the same value.
+        fpu.ctx.regs[6] = 0;
+        fpu.op(&mut cpu, mov(0x4, 1, 6), &t).unwrap(); // MTC1 r1,f6
+        fpu.op(&mut cpu, mov(0x7, 2, 6), &t).unwrap(); // MTHC1 r2,f6
+        assert_eq!(fpu.ctx.regs[6], val);
+
+        fpu.op(&mut cpu, mov(0x3, 5, 6), &t).unwrap(); // MFHC1 r5,f6
+        assert_eq!(cpu.regs[5], ((val >> 32) as u32).sx64());
+        fpu.op(&mut cpu, mov(0x0, 5, 6), &t).unwrap(); // MFC1 r5,f6
+        assert_eq!(cpu.regs[5], (val as u32).sx64());
+    }
+
+    #[test]
+    fn test_cvt_w_after_l() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = (-5.5f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x09, 4, 2, 0), &t).unwrap(); // TRUNC.L.D f4,f2
+        assert_eq!(fpu.ctx.regs[4], -5i64 as u64);
+
+        fpu.ctx.regs[2] = (7.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        fpu.op(&mut cpu, (0x11 << 26) | (1 << 16) | (4 << 11), &t).unwrap(); // MFC1 r1,f4
+        assert_eq!(cpu.regs[1], 7);
+        let dmfc1 = (0x11 << 26) | (0x1 << 21) | (1 << 16) | (4 << 11);
+        fpu.op(&mut cpu, dmfc1, &t).unwrap(); // DMFC1 r1,f4
+        assert_eq!(cpu.regs[1], 7);
+
+        fpu.ctx.regs[2] = (-7.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x0D, 4, 2, 0), &t).unwrap(); // TRUNC.W.D f4,f2
+        assert_eq!(fpu.ctx.regs[4], 0xFFFF_FFF9);
+    }
+
+    #[test]
+    fn test_round_w_high_word() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let mov = |fmt: u32, rt: u32, fs: u32| {
+            (0x11 << 26) | (fmt << 21) | (rt << 16) | (fs << 11)
+        };
+        let round_w_s = fop(FMT_S, 0x0C, 4, 2, 0); // ROUND.W.S f4,f2
+
+        for &(v, res) in [(2.5f32, 2u32), (-3.5, -4i32 as u32)].iter() {
+            fpu.ctx.regs[4] = !0;
+            fpu.ctx.regs[2] = v.to_bits() as u64;
+            fpu.op(&mut cpu, round_w_s, &t).unwrap();
+            fpu.op(&mut cpu, mov(0x3, 1, 4), &t).unwrap(); // MFHC1 r1,f4
+            assert_eq!(cpu.regs[1], 0);
+            fpu.op(&mut cpu, mov(0x1, 1, 4), &t).unwrap(); // DMFC1 r1,f4
+            assert_eq!(cpu.regs[1], res as u64);
+            fpu.op(&mut cpu, mov(0x0, 1, 4), &t).unwrap(); // MFC1 r1,f4
+            assert_eq!(cpu.regs[1], res.sx64());
+        }
+
+        // In FR=0, the high word is f5, which is not touched.
+        cpu.fpu64 = false;
+        fpu.ctx.regs[5] = 0x1234_5678;
+        fpu.op(&mut cpu, round_w_s, &t).unwrap();
+        assert_eq!(fpu.ctx.regs[4], -4i32 as u32 as u64);
+        assert_eq!(fpu.ctx.regs[5], 0x1234_5678);
+    }
+
+    #[test]
+    fn test_single_fr0() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        cpu.fpu64 = false;
+        let dmtc1 = |rt: u32, fs: u32| (0x11 << 26) | (0x5 << 21) | (rt << 16) | (fs << 11);
+
+        // In FR=0, f1 and f3 are the high halves of the doubles in f0 and f2.
+        cpu.regs[1] = (1.5f32.to_bits() as u64) << 32;
+        cpu.regs[2] = ((2.25f32.to_bits() as u64) << 32) | 0x1234_5678;
+        fpu.op(&mut cpu, dmtc1(1, 0), &t).unwrap(); // DMTC1 r1,f0
+        fpu.op(&mut cpu, dmtc1(2, 2), &t).unwrap(); // DMTC1 r2,f2
+
+        // ADD.S f4,f1,f3
+        fpu.op(&mut cpu, fop(FMT_S, 0x00, 4, 1, 3), &t).unwrap();
+        assert_eq!(fpu.ctx.regs[4], 3.75f32.to_bits() as u64);
+        // ADD.S f5,f0,f2: the low halves are also independent registers.
+        fpu.op(&mut cpu, fop(FMT_S, 0x00, 5, 0, 2), &t).unwrap();
+        assert_eq!(fpu.ctx.regs[5], f32::from_bits(0x1234_5678).to_bits() as u64);
+
+        // Writing a single doesn't clobber the other half of the pair.
+        fpu.op(&mut cpu, fop(FMT_S, 0x06, 2, 1, 0), &t).unwrap(); // MOV.S f2,f1
+        assert_eq!(fpu.ctx.regs[2], 1.5f32.to_bits() as u64);
+        assert_eq!(fpu.ctx.regs[3], 2.25f32.to_bits() as u64);
+
+        // f31 is a valid single register too.
+        fpu.op(&mut cpu, fop(FMT_S, 0x06, 31, 1, 0), &t).unwrap(); // MOV.S f31,f1
+        assert_eq!(fpu.ctx.regs[31], 1.5f32.to_bits() as u64);
+    }
+
+    #[test]
+    fn test_div_rounding_modes() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        // 1/3 is below the nearest double, and above the nearest single.
+        for &(rm, d, s) in &[
+            (0, 0x3FD5_5555_5555_5555, 0x3EAA_AAAB),
+            (1, 0x3FD5_5555_5555_5555, 0x3EAA_AAAA),
+            (2, 0x3FD5_5555_5555_5556, 0x3EAA_AAAB),
+            (3, 0x3FD5_5555_5555_5555, 0x3EAA_AAAA),
+        ] {
+            fpu.ctx.fcsr = rm;
+            fpu.ctx.regs[2] = (1.0f64).to_bits();
+            fpu.ctx.regs[4] = (3.0f64).to_bits();
+            fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 2, 4), &t).unwrap(); // DIV.D f6,f2,f4
+            assert_eq!(fpu.ctx.regs[6], d, "DIV.D rm={}", rm);
+
+            fpu.ctx.regs[2] = (-1.0f64).to_bits();
+            fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 2, 4), &t).unwrap(); // DIV.D f6,f2,f4
+            let nd = if rm == 3 { 0x3FD5_5555_5555_5556 } else { 0x3FD5_5555_5555_5555 };
+            assert_eq!(fpu.ctx.regs[6], nd | (1 << 63), "DIV.D rm={} (neg)", rm);
+
+            fpu.ctx.regs[2] = (1.0f32).to_bits() as u64;
+            fpu.ctx.regs[4] = (3.0f32).to_bits() as u64;
+            fpu.op(&mut cpu, fop(FMT_S, 0x03, 6, 2, 4), &t).unwrap(); // DIV.S f6,f2,f4
+            assert_eq!(fpu.ctx.regs[6], s, "DIV.S rm={}", rm);
+        }
+    }
+
+    #[test]
+    fn test_add_overflow_rounding() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+
+        fpu.ctx.regs[2] = std::f64::MAX.to_bits();
+        for &(rm, res) in &[
+            (0, std::f64::INFINITY),
+            (1, std::f64::MAX),
+            (2, std::f64::INFINITY),
+            (3, std::f64::MAX),
+        ] {
+            fpu.ctx.fcsr = rm;
+            fpu.op(&mut cpu, fop(FMT_D, 0x00, 4, 2, 2), &t).unwrap(); // ADD.D f4,f2,f2
+            assert_eq!(fpu.ctx.regs[4], res.to_bits(), "rm={}", rm);
+        }
+
+        // Infinite operands are exact in every mode.
+        fpu.ctx.fcsr = 1;
+        fpu.ctx.regs[2] = std::f64::INFINITY.to_bits();
+        fpu.ctx.regs[3] = (1.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x00, 4, 2, 3), &t).unwrap(); // ADD.D f4,f2,f3
+        assert_eq!(fpu.ctx.regs[4], std::f64::INFINITY.to_bits());
+    }
+
+    #[test]
+    fn test_fcsr_flags_and_cause() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let flags = |fpu: &Fpu| (fpu.ctx.fcsr >> FCSR_FLAG_SHIFT) & 0x1F;
+        let cause = |fpu: &Fpu| (fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT) & 0x3F;
+
+        // MUL.D f6,f2,f2: underflow, inexact.
+        fpu.ctx.regs[2] = (1e-160f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x02, 6, 2, 2), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_UNDERFLOW | FPE_INEXACT);
+        assert_eq!(flags(&fpu), FPE_UNDERFLOW | FPE_INEXACT);
+
+        // DIV.D f6,f2,f4: inexact only. Cause reflects the last instruction,
+        // while Flag keeps the underflow.
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = (3.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 2, 4), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INEXACT);
+        assert_eq!(flags(&fpu), FPE_UNDERFLOW | FPE_INEXACT);
+
+        // ADD.D f6,f2,f2: exact, clears Cause.
+        fpu.op(&mut cpu, fop(FMT_D, 0x00, 6, 2, 2), &t).unwrap();
+        assert_eq!(cause(&fpu), 0);
+        assert_eq!(flags(&fpu), FPE_UNDERFLOW | FPE_INEXACT);
+
+        // DIV.D f6,f4,f0: division by zero.
+        fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 4, 0), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_DIVZERO);
+        assert_eq!(fpu.ctx.regs[6], std::f64::INFINITY.to_bits());
+
+        // C.SEQ.D f0,f8 with a NaN: invalid.
+        fpu.ctx.regs[8] = std::f64::NAN.to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x3A, 0, 0, 8), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INVALID);
+        assert_eq!(
+            flags(&fpu),
+            FPE_UNDERFLOW | FPE_INEXACT | FPE_DIVZERO | FPE_INVALID
+        );
+    }
+
+    #[test]
+    fn test_fcsr_cause_per_op() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let cause = |fpu: &Fpu| (fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT) & 0x3F;
+
+        // SQRT.D f6,f2 of a negative number: invalid.
+        fpu.ctx.regs[2] = (-1.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x04, 6, 2, 0), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INVALID);
+
+        // DIV.D f6,f2,f4: inexact. Cause doesn't keep the previous invalid.
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = (3.0f64).to_bits();
+        fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 2, 4), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INEXACT);
+        assert!(fpu.ctx.fcsr & FCSR_FLAG_INVALID != 0);
+
+        // CVT.S.W f6,f8: 2^24+1 is not representable in single precision.
+        fpu.ctx.regs[8] = 0x0100_0001;
+        fpu.op(&mut cpu, fop(0x14, 0x20, 6, 8, 0), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INEXACT);
+        assert_eq!(fpu.ctx.regs[6], (16777216.0f32).to_bits() as u64);
+
+        // CVT.D.W f6,f8: always exact, clears Cause.
+        fpu.op(&mut cpu, fop(0x14, 0x21, 6, 8, 0), &t).unwrap();
+        assert_eq!(cause(&fpu), 0);
+        assert_eq!(fpu.ctx.regs[6], (16777217.0f64).to_bits());
+
+        // CVT.D.L f6,f8: i64::MAX rounds up to 2^63.
+        fpu.ctx.regs[8] = std::i64::MAX as u64;
+        fpu.op(&mut cpu, fop(0x15, 0x21, 6, 8, 0), &t).unwrap();
+        assert_eq!(cause(&fpu), FPE_INEXACT);
+    }
+
+    #[test]
+    fn test_fcsr_enable_traps() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let dbg = Debugger::new(&vec![]);
+        let t = dbg.new_tracer();
+
+        // Inexact enabled: DIV.D f6,f2,f4 traps without writing f6 or Flag.
+        fpu.ctx.fcsr = FPE_INEXACT << FCSR_ENABLE_SHIFT;
+        fpu.ctx.regs[2] = (1.0f64).to_bits();
+        fpu.ctx.regs[4] = (3.0f64).to_bits();
+        fpu.ctx.regs[6] = 0x1234;
+        assert!(fpu.op(&mut cpu, fop(FMT_D, 0x03, 6, 2, 4), &t).is_err());
+        assert_eq!(fpu.ctx.regs[6], 0x1234);
+        assert_eq!(fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT, FPE_INEXACT);
+        assert_eq!((fpu.ctx.fcsr >> FCSR_FLAG_SHIFT) & 0x1F, 0);
+
+        // An exact operation doesn't trap.
+        assert!(fpu.op(&mut cpu, fop(FMT_D, 0x00, 6, 2, 2), &t).is_ok());
+        assert_eq!(fpu.ctx.regs[6], (2.0f64).to_bits());
+    }
+
+    // Deterministic xorshift generator for the reference tests.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    // Round the exact value hi+lo (|lo| is much smaller than an ulp of hi) to
+    // single precision with rounding mode rm. Returns the result and the
+    // exceptions it raises; like the FPU, overflow and tininess are detected
+    // on the result rounded to nearest.
+    fn ref_round_s(hi: f64, lo: f64, rm: u64) -> (f32, u64) {
+        let n = hi as f32;
+        let diff = hi - n as f64;
+        if diff == 0.0 && lo == 0.0 {
+            return (n, 0);
+        }
+
+        // Bracket the exact value between two adjacent singles. The exponent
+        // is unbounded for rounding purposes, so infinity stands for 2^128.
+        let next = |x: f32, up: bool| match x {
+            x if x == 0.0 && up => f32::from_bits(1),
+            x if x == 0.0 => -f32::from_bits(1),
+            x if (x > 0.0) == up => f32::from_bits(x.to_bits() + 1),
+            x => f32::from_bits(x.to_bits() - 1),
+        };
+        let unbounded = |x: f32| {
+            if x.is_infinite() {
+                2f64.powi(128).copysign(x as f64)
+            } else {
+                x as f64
+            }
+        };
+        let above = if diff != 0.0 { diff > 0.0 } else { lo > 0.0 };
+        let (lower, upper) = if above { (n, next(n, true)) } else { (next(n, false), n) };
+        let dlower = (hi - unbounded(lower)) + lo;
+        let dupper = (unbounded(upper) - hi) - lo;
+        let nearest = if dlower < dupper || (dlower == dupper && lower.to_bits() & 1 == 0) {
+            lower
+        } else {
+            upper
+        };
+
+        let exc = if nearest.is_infinite() {
+            FPE_INEXACT | FPE_OVERFLOW
+        } else if nearest.abs() < std::f32::MIN_POSITIVE {
+            FPE_INEXACT | FPE_UNDERFLOW
+        } else {
+            FPE_INEXACT
+        };
+        let res = match rm {
+            0 => nearest,
+            1 if lower.abs() < upper.abs() => lower,
+            1 => upper,
+            2 => upper,
+            _ => lower,
+        };
+        (res, exc)
+    }
+
+    // Random singles: mostly normal numbers around 1.0 (so that operations
+    // mix operands of similar magnitude), plus special and random bit
+    // patterns that cover overflow, underflow and denormals.
+    fn ref_inputs_s() -> Vec<f32> {
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        let mut vals = vec![
+            0.0,
+            -0.0,
+            1.0,
+            -3.0,
+            0.1,
+            16777217.0,
+            std::f32::MAX,
+            -std::f32::MAX,
+            std::f32::MIN_POSITIVE,
+            std::f32::EPSILON,
+            f32::from_bits(1),
+            f32::from_bits(0x8000_0003),
+        ];
+        while vals.len() < 64 {
+            let bits = rng.next_u64() as u32;
+            let v = if vals.len() % 2 == 0 {
+                f32::from_bits((bits & 0x807F_FFFF) | ((0x70 + (bits >> 23) % 32) << 23))
+            } else {
+                f32::from_bits(bits)
+            };
+            if v.is_finite() {
+                vals.push(v);
+            }
+        }
+        vals
+    }
+
+    // Cross-check single-precision arithmetic against the same operation
+    // carried out in double precision. A double product of singles is exact;
+    // a double quotient or square root is never close enough to a single (or
+    // to a midpoint) to change its rounding unless it is exact; a sum is
+    // exact once the TwoSum residual is added.
+    #[test]
+    fn test_arith_reference_s() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let vals = ref_inputs_s();
+
+        let two_sum = |a: f64, b: f64| {
+            let s = a + b;
+            let bb = s - a;
+            (s, (a - (s - bb)) + (b - bb))
+        };
+        let ops: [(u32, &dyn Fn(f64, f64) -> (f64, f64)); 5] = [
+            (0x00, &two_sum),
+            (0x01, &|a, b| two_sum(a, -b)),
+            (0x02, &|a, b| (a * b, 0.0)),
+            (0x03, &|a, b| (a / b, 0.0)),
+            (0x04, &|a, _| (a.sqrt(), 0.0)),
+        ];
+
+        for &(func, exact) in ops.iter() {
+            for &a in &vals {
+                for &b in &vals {
+                    let (hi, lo) = exact(a as f64, b as f64);
+                    if hi.is_nan() || (func == 0x03 && b == 0.0) {
+                        continue;
+                    }
+                    for rm in 0..4 {
+                        let (res, exc) = ref_round_s(hi, lo, rm);
+                        // An exact zero sum of operands of opposite signs is
+                        // -0 when rounding toward minus infinity.
+                        let neg_b = b.is_sign_negative() != (func == 0x01);
+                        let res = if res == 0.0 && func <= 0x01 && rm == 3 {
+                            if a.is_sign_negative() != neg_b {
+                                -0.0
+                            } else {
+                                res
+                            }
+                        } else {
+                            res
+                        };
+                        fpu.ctx.fcsr = rm;
+                        fpu.ctx.regs[2] = a.to_bits() as u64;
+                        fpu.ctx.regs[4] = b.to_bits() as u64;
+                        fpu.op(&mut cpu, fop(FMT_S, func, 6, 2, 4), &t).unwrap();
+                        assert_eq!(
+                            (fpu.ctx.regs[6] as u32, fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                            (res.to_bits(), exc),
+                            "func={:x} rm={} a={:e} b={:e}",
+                            func,
+                            rm,
+                            a,
+                            b
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Split a finite double into its sign, integer significand and exponent:
+    // its value is (-1)^neg * m * 2^e.
+    fn split_d(x: f64) -> (bool, u64, i32) {
+        let bits = x.to_bits();
+        let exp = ((bits >> 52) & 0x7FF) as i32;
+        let frac = bits & ((1 << 52) - 1);
+        let (m, e) = if exp == 0 {
+            (frac, -1074)
+        } else {
+            (frac | (1 << 52), exp - 1075)
+        };
+        (x.is_sign_negative(), m, e)
+    }
+
+    // Round the exact value (-1)^neg * (m + f) * 2^e to double precision with
+    // rounding mode rm, where f is a fraction strictly between 0 and 1 if
+    // sticky is set, and 0 otherwise; m must not be zero. Returns the result
+    // and the exceptions it raises, with the conventions of ref_round_s.
+    fn ref_round_d(neg: bool, m: u128, e: i32, sticky: bool, rm: u64) -> (f64, u64) {
+        let pow2 = |k: i32| match k {
+            k if k > 1023 => std::f64::INFINITY,
+            k if k >= -1022 => f64::from_bits(((k + 1023) as u64) << 52),
+            k => f64::from_bits(1 << (k + 1074)),
+        };
+        let sign = |x: f64| if neg { -x } else { x };
+
+        // Exponent of the leading bit, and of the last bit kept by rounding
+        // (the unit): denormals have fewer significant bits.
+        let top = 127 - m.leading_zeros() as i32 + e;
+        let unit = (top - 52).max(-1074);
+        let shift = unit - e;
+        assert!(!sticky || shift > 0, "not enough bits to round");
+
+        // Split the value into q units, and compare the rest with half a
+        // unit (None if there is no rest).
+        let (q, rest) = match shift {
+            s if s <= 0 => (m << -s, None),
+            s if s > 128 => (0, Some(Ordering::Less)),
+            s => {
+                let half = 1u128 << (s - 1);
+                let r = m & (half.wrapping_shl(1).wrapping_sub(1));
+                let q = m.checked_shr(s as u32).unwrap_or(0);
+                let tie = if sticky { Ordering::Greater } else { Ordering::Equal };
+                if r == 0 && !sticky {
+                    (q, None)
+                } else {
+                    (q, Some(r.cmp(&half).then(tie)))
+                }
+            }
+        };
+
+        let (lower, upper, nearest) = if top > 1023 {
+            (std::f64::MAX, std::f64::INFINITY, std::f64::INFINITY)
+        } else {
+            let (lower, upper) = (q as f64 * pow2(unit), (q + 1) as f64 * pow2(unit));
+            let nearest = match rest {
+                None => return (sign(lower), 0),
+                Some(Ordering::Less) => lower,
+                Some(Ordering::Greater) => upper,
+                Some(Ordering::Equal) if q & 1 == 0 => lower,
+                Some(Ordering::Equal) => upper,
+            };
+            (lower, upper, nearest)
+        };
+
+        let exc = if nearest.is_infinite() {
+            FPE_INEXACT | FPE_OVERFLOW
+        } else if nearest < std::f64::MIN_POSITIVE {
+            FPE_INEXACT | FPE_UNDERFLOW
+        } else {
+            FPE_INEXACT
+        };
+        let res = match rm {
+            0 => nearest,
+            1 => lower,
+            2 if neg => lower,
+            2 => upper,
+            _ if neg => upper,
+            _ => lower,
+        };
+        (sign(res), exc)
+    }
+
+    // Compute a D-format ADD, SUB, MUL, DIV or SQRT of finite operands
+    // exactly with integers, and round it with ref_round_d. Returns None if
+    // the result is not a number, or for a division by zero.
+    fn ref_arith_d(func: u32, a: f64, b: f64, rm: u64) -> Option<(f64, u64)> {
+        let (sa, ma, ea) = split_d(a);
+        let (sb, mb, eb) = split_d(b);
+        let zero = |neg: bool| Some((if neg { -0.0 } else { 0.0 }, 0));
+        // Normalize a nonzero significand to 53 bits.
+        let norm = |m: u64, e: i32| {
+            let s = m.leading_zeros() as i32 - 11;
+            (m << s, e - s)
+        };
+
+        let (neg, m, e, sticky) = match func {
+            0x00 | 0x01 => {
+                let sb = sb != (func == 0x01);
+                if ma == 0 && mb == 0 {
+                    // An exact zero sum is negative only rounding down.
+                    return zero(if sa == sb { sa } else { rm == 3 });
+                } else if ma == 0 || mb == 0 {
+                    let x = if ma == 0 { b } else { a };
+                    let neg = if ma == 0 { sb } else { sa };
+                    return Some((x.abs() * if neg { -1.0 } else { 1.0 }, 0));
+                }
+                if (ea - eb).abs() <= 64 {
+                    let emin = ea.min(eb);
+                    let va = (ma as i128) << (ea - emin);
+                    let vb = (mb as i128) << (eb - emin);
+                    let sum = if sa { -va } else { va } + if sb { -vb } else { vb };
+                    if sum == 0 {
+                        return zero(rm == 3);
+                    }
+                    (sum < 0, sum.unsigned_abs(), emin, false)
+                } else {
+                    // The smaller operand is less than 2^-3 units of the last
+                    // bit of the larger one: it only moves the sum by a
+                    // fraction below (or above) the larger one.
+                    let ((s1, m1, e1), s2) = if ea > eb {
+                        ((sa, ma, ea), sb)
+                    } else {
+                        ((sb, mb, eb), sa)
+                    };
+                    let m = (m1 as u128) << 3;
+                    (s1, if s1 == s2 { m } else { m - 1 }, e1 - 3, true)
+                }
+            }
+            0x02 => {
+                if ma == 0 || mb == 0 {
+                    return zero(sa != sb);
+                }
+                (sa != sb, ma as u128 * mb as u128, ea + eb, false)
+            }
+            0x03 => {
+                if mb == 0 {
+                    return None;
+                } else if ma == 0 {
+                    return zero(sa != sb);
+                }
+                let ((na, ea), (nb, eb)) = (norm(ma, ea), norm(mb, eb));
+                let num = (na as u128) << 74;
+                let q = num / nb as u128;
+                (sa != sb, q, ea - 74 - eb, num % nb as u128 != 0)
+            }
+            _ => {
+                if ma == 0 {
+                    return Some((a, 0));
+                } else if sa {
+                    return None;
+                }
+                let (na, ea) = norm(ma, ea);
+                let (na, ea) = if ea & 1 != 0 { (na << 1, ea - 1) } else { (na, ea) };
+                let n = (na as u128) << 66;
+                let mut r = (n as f64).sqrt() as u128;
+                while r * r > n {
+                    r -= 1;
+                }
+                while (r + 1) * (r + 1) <= n {
+                    r += 1;
+                }
+                (false, r, (ea - 66) / 2, r * r != n)
+            }
+        };
+        Some(ref_round_d(neg, m, e, sticky, rm))
+    }
+
+    // Random doubles, chosen like ref_inputs_s.
+    fn ref_inputs_d() -> Vec<f64> {
+        let mut rng = XorShift(0x5851_F42D_4C95_7F2D);
+        let mut vals = vec![
+            0.0,
+            -0.0,
+            1.0,
+            -3.0,
+            0.1,
+            9007199254740994.0,
+            std::f64::MAX,
+            -std::f64::MAX,
+            std::f64::MIN_POSITIVE,
+            std::f64::EPSILON,
+            f64::from_bits(1),
+            f64::from_bits(0x8000_0000_0000_0003),
+        ];
+        while vals.len() < 64 {
+            let bits = rng.next_u64();
+            let v = if vals.len() % 2 == 0 {
+                let exp = 0x3F0 + (bits >> 52) % 32;
+                f64::from_bits((bits & 0x800F_FFFF_FFFF_FFFF) | (exp << 52))
+            } else {
+                f64::from_bits(bits)
+            };
+            if v.is_finite() {
+                vals.push(v);
+            }
+        }
+        vals
+    }
+
+    // Cross-check double-precision arithmetic against ref_arith_d, which
+    // carries out each operation exactly on the integer significands.
+    #[test]
+    fn test_arith_reference_d() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let vals = ref_inputs_d();
+
+        for func in 0x00..=0x04 {
+            for &a in &vals {
+                for &b in &vals {
+                    for rm in 0..4 {
+                        let (res, exc) = match ref_arith_d(func, a, b, rm) {
+                            Some(r) => r,
+                            None => continue,
+                        };
+                        fpu.ctx.fcsr = rm;
+                        fpu.ctx.regs[2] = a.to_bits();
+                        fpu.ctx.regs[4] = b.to_bits();
+                        fpu.op(&mut cpu, fop(FMT_D, func, 6, 2, 4), &t).unwrap();
+                        assert_eq!(
+                            (fpu.ctx.regs[6], fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                            (res.to_bits(), exc),
+                            "func={:x} rm={} a={:e} b={:e}",
+                            func,
+                            rm,
+                            a,
+                            b
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cvt_reference() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let t = Tracer::null();
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+
+        for i in 0..4096 {
+            let rm = i & 3;
+            fpu.ctx.fcsr = rm;
+
+            // CVT.S.D f6,f2 of a double around the single-precision range.
+            let bits = rng.next_u64();
+            let exp = 0x360 + (bits >> 52) % 0x140;
+            let d = f64::from_bits((bits & 0x800F_FFFF_FFFF_FFFF) | (exp << 52));
+            fpu.ctx.regs[2] = d.to_bits();
+            fpu.op(&mut cpu, fop(FMT_D, 0x20, 6, 2, 0), &t).unwrap();
+            let (res, exc) = ref_round_s(d, 0.0, rm);
+            assert_eq!(
+                (fpu.ctx.regs[6] as u32, fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                (res.to_bits(), exc),
+                "CVT.S.D rm={} d={:e}",
+                rm,
+                d
+            );
+
+            // CVT.S.L f6,f8 and CVT.D.L f6,f8 of a random integer, with a
+            // random number of significant bits.
+            let l = (rng.next_u64() as i64) >> (rng.next_u64() % 64);
+            fpu.ctx.regs[8] = l as u64;
+            fpu.op(&mut cpu, fop(0x15, 0x20, 6, 8, 0), &t).unwrap();
+            let hi = l as f64;
+            let lo = (l as i128 - hi as i128) as f64;
+            let (res, exc) = ref_round_s(hi, lo, rm);
+            assert_eq!(
+                (fpu.ctx.regs[6] as u32, fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                (res.to_bits(), exc),
+                "CVT.S.L rm={} l={}",
+                rm,
+                l
+            );
+            fpu.op(&mut cpu, fop(0x15, 0x21, 6, 8, 0), &t).unwrap();
+            let step = |x: f64, up: bool| {
+                if (x > 0.0) == up {
+                    f64::from_bits(x.to_bits() + 1)
+                } else {
+                    f64::from_bits(x.to_bits() - 1)
+                }
+            };
+            let res = match rm {
+                _ if lo == 0.0 => hi,
+                1 if (lo > 0.0) != (hi > 0.0) => step(hi, lo > 0.0),
+                2 if lo > 0.0 => step(hi, true),
+                3 if lo < 0.0 => step(hi, false),
+                _ => hi,
+            };
+            assert_eq!(
+                (fpu.ctx.regs[6], fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                (res.to_bits(), if lo == 0.0 { 0 } else { FPE_INEXACT }),
+                "CVT.D.L rm={} l={}",
+                rm,
+                l
+            );
+
+            // CVT.W.D f6,f2 of a double with 8 fractional bits, around the
+            // 32-bit integer range.
+            let w = ((rng.next_u64() as i64) >> (rng.next_u64() % 64)) as f64 / 256.0;
+            fpu.ctx.regs[2] = w.to_bits();
+            fpu.op(&mut cpu, fop(FMT_D, 0x24, 6, 2, 0), &t).unwrap();
+            let f = w.floor();
+            let r = match rm {
+                0 if w - f > 0.5 || (w - f == 0.5 && f % 2.0 != 0.0) => f + 1.0,
+                0 => f,
+                1 => w.trunc(),
+                2 => w.ceil(),
+                _ => f,
+            };
+            let (res, exc) = if r < -2147483648.0 {
+                (0x8000_0000, FPE_INVALID)
+            } else if r > 2147483647.0 {
+                (0x7FFF_FFFF, FPE_INVALID)
+            } else if r != w {
+                (r as i32 as u32, FPE_INEXACT)
+            } else {
+                (r as i32 as u32, 0)
+            };
+            assert_eq!(
+                (fpu.ctx.regs[6], fpu.ctx.fcsr >> FCSR_CAUSE_SHIFT),
+                (res as u64, exc),
+                "CVT.W.D rm={} w={:e}",
+                rm,
+                w
+            );
+        }
+    }
+
+    #[test]
+    fn test_supported_funcs() {
+        let (mut fpu, mut cpu) = new_fpu();
+        let dbg = Debugger::new(&vec![]);
+        let t = dbg.new_tracer();
+
+        for fmt in &[FMT_S, FMT_D] {
+            for func in 0..0x40 {
+                let res = fpu.op(&mut cpu, fop(*fmt, func, 4, 2, 0), &t);
+                let supported = Fpu::supported_funcs().contains(&(func as u8));
+                assert_eq!(res.is_ok(), supported, "fmt={:x} func={:x}", fmt, func);
+            }
+        }
+    }
+}
@@ -13,6 +13,7 @@ extern crate slog;
 mod arch;
 mod cp0;
 mod cpu;
+#[cfg(feature = "cop1")]
 mod fpu;
 mod traits;
 
@@ -23,5 +24,6 @@ pub use self::arch::{ArchI, ArchII, ArchIII};
 pub use self::cp0::Cp0;
 pub use self::cpu::{Cpu, CpuContext, Exception};
 pub use self::decode::REG_NAMES;
-pub use self::fpu::Fpu;
-pub use self::traits::{Arch, Config, Cop, Cop0, CopNull};
+#[cfg(feature = "cop1")]
+pub use self::fpu::{Fpu, NanTrap};
+pub use self::traits::{Arch, Config, Cop, Cop0, CopError, CopNull};
@@ -2,6 +2,7 @@ use super::{CpuContext, Exception};
 use emu::bus::be::Bus;
 use emu::dbg::{DebuggerRenderer, DecodedInsn, Result, Tracer};
 use emu::memint::MemInt;
+use std::fmt;
 
 /// Arch is a trait that allows to customise the MIPS core at the opcode level.
 /// It is used to implement different MIPS variants (architecture levels).
@@ -41,11 +42,62 @@ pub trait Config {
     }
 }
 
+/// CopError is a structured error reported by a coprocessor. Failing opcodes
+/// still break into the debugger through the tracer; the error is also kept
+/// so that hosts running without a debugger can retrieve it with
+/// `Cop::take_error`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CopError {
+    /// The opcode is not implemented by the coprocessor.
+    UnimplementedOp(u32),
+    /// Access to a register index that does not exist.
+    BadRegister(usize),
+    /// A load or store accessed a faulting address.
+    AddressFault(u32),
+    /// The opcode is valid, but not a computational one as the entry point
+    /// requires.
+    NotComputational(u32),
+}
+
+impl fmt::Display for CopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CopError::UnimplementedOp(op) => write!(f, "unimplemented opcode: {:08x}", op),
+            CopError::BadRegister(idx) => write!(f, "invalid register: {}", idx),
+            CopError::AddressFault(addr) => write!(f, "address fault: {:08x}", addr),
+            CopError::NotComputational(op) => {
+                write!(f, "not a computational opcode: {:08x}", op)
+            }
+        }
+    }
+}
+
 /// Cop is a MIPS64 coprocessor that can be installed within the core.
 pub trait Cop {
     fn reg(&self, cpu: &CpuContext, idx: usize) -> u128;
     fn set_reg(&mut self, cpu: &mut CpuContext, idx: usize, val: u128);
 
+    // Variants of reg/set_reg that report invalid register indices instead
+    // of panicking or ignoring them. Coprocessors with a fixed register file
+    // should reimplement them.
+    fn try_reg(&self, cpu: &CpuContext, idx: usize) -> std::result::Result<u128, CopError> {
+        Ok(self.reg(cpu, idx))
+    }
+    fn try_set_reg(
+        &mut self,
+        cpu: &mut CpuContext,
+        idx: usize,
+        val: u128,
+    ) -> std::result::Result<(), CopError> {
+        self.set_reg(cpu, idx, val);
+        Ok(())
+    }
+
+    // Return (and clear) the error caused by the last failing opcode, if any.
+    fn take_error(&mut self) -> Option<CopError> {
+        None
+    }
+
     fn op(&mut self, cpu: &mut CpuContext, opcode: u32, t: &Tracer) -> Result<()>;
     fn decode(&self, _opcode: u32, _pc: u64) -> DecodedInsn {
         DecodedInsn::new0("unkcop")
@@ -67,7 +119,7 @@ pub trait Cop {
         Ok(())
     }
 
-    fn swc(&mut self, op: u32, ctx: &CpuContext, bus: &mut Bus, _t: &Tracer) -> Result<()> {
+    fn swc(&mut self, op: u32, ctx: &mut CpuContext, bus: &mut Bus, _t: &Tracer) -> Result<()> {
         let rt = ((op >> 16) & 0x1f) as usize;
         let ea = ctx.regs[((op >> 21) & 0x1f) as usize] as u32 + (op & 0xffff) as i16 as i32 as u32;
         let val = self.reg(ctx, rt) as u32;
@@ -75,7 +127,7 @@ pub trait Cop {
         Ok(())
     }
 
-    fn sdc(&mut self, op: u32, ctx: &CpuContext, bus: &mut Bus, _t: &Tracer) -> Result<()> {
+    fn sdc(&mut self, op: u32, ctx: &mut CpuContext, bus: &mut Bus, _t: &Tracer) -> Result<()> {
         let rt = ((op >> 16) & 0x1f) as usize;
         let ea = ctx.regs[((op >> 21) & 0x1f) as usize] as u32 + (op & 0xffff) as i16 as i32 as u32;
         let val = self.reg(ctx, rt) as u64;
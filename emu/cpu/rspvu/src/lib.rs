@@ -0,0 +1,28 @@
+//! Computational core of the RSP vector unit (COP2): multiplications with the
+//! 48-bit accumulator, clipping, compares and reciprocals, on 8-lane vectors
+//! held in SSE registers. The `scalar` module has per-lane versions of the
+//! multiply and clip functions, for hosts without SSE4.1.
+//!
+//! This crate is `no_std` and has no dependencies, so that it can be embedded
+//! in constrained hosts; building it is enough to check that it stays so.
+//! Register files, memory accesses and logging are layered on top of it by
+//! the emulator.
+//!
+//! # Safety
+//!
+//! Vector functions are `unsafe` as they are compiled with the SSE4.1 target
+//! feature enabled: callers must check that the host CPU supports it. They
+//! are only available on x86-64; `scalar` and `vrcp` build on any target.
+#![no_std]
+#![allow(clippy::missing_safety_doc)]
+
+#[cfg(target_arch = "x86_64")]
+mod accumulator;
+pub mod scalar;
+#[cfg(target_arch = "x86_64")]
+pub mod vclip;
+#[cfg(target_arch = "x86_64")]
+pub mod vcmp;
+#[cfg(target_arch = "x86_64")]
+pub mod vmul;
+pub mod vrcp;
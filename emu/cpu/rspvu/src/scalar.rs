@@ -0,0 +1,183 @@
+//! Per-lane versions of the multiply and clip functions, for hosts without
+//! SSE4.1. Each function computes a single lane, with the same results as
+//! its SIMD counterpart. The accumulator of the lane is a signed 48-bit
+//! value, sign-extended to i64 (the three 16-bit slices are just its bits).
+
+// Wrap an accumulator value to 48 bits, as the 16-bit slices do on carry.
+#[inline(always)]
+fn acc_wrap(acc: i64) -> i64 {
+    (acc << 16) >> 16
+}
+
+// Signed saturation of the MD/HI slices of the accumulator.
+#[inline(always)]
+fn acc_clamp_signed(acc: i64) -> u16 {
+    (acc >> 16).clamp(-0x8000, 0x7FFF) as u16
+}
+
+// Unsigned saturation of X given the MD/HI slices (see acc_clamp_unsigned3
+// in the SIMD version).
+#[inline(always)]
+fn acc_clamp_unsigned3(x: u16, acc: i64) -> u16 {
+    match acc >> 16 {
+        v if v < -0x8000 => 0,
+        v if v > 0x7FFF => 0xFFFF,
+        _ => x,
+    }
+}
+
+// Unsigned saturation of the MD slice: negative values are clamped to 0,
+// values that don't fit in 15 bits to 0xFFFF.
+#[inline(always)]
+fn acc_clamp_unsigned2(acc: i64) -> u16 {
+    match acc >> 16 {
+        v if v < 0 => 0,
+        v if v > 0x7FFF => 0xFFFF,
+        v => v as u16,
+    }
+}
+
+#[inline(always)]
+fn internal_vmulfu(vs: u16, vt: u16, acc: i64, signed: bool, mac: bool) -> (u16, i64) {
+    let prod = i64::from(vs as i16) * i64::from(vt as i16) * 2;
+    let acc = if mac { acc_wrap(acc + prod) } else { prod + 0x8000 };
+    let res = if signed {
+        acc_clamp_signed(acc)
+    } else {
+        acc_clamp_unsigned2(acc)
+    };
+    (res, acc)
+}
+
+#[inline(always)]
+fn internal_vmudnm(vs: u16, vt: u16, acc: i64, mac: bool, mid: bool) -> (u16, i64) {
+    // The unsigned operand is VS for VMUDN/VMADN, and VT for VMUDM/VMADM.
+    let (vs, vt) = if mid { (vt, vs) } else { (vs, vt) };
+    let prod = i64::from(vs) * i64::from(vt as i16);
+    let acc = if mac { acc_wrap(acc + prod) } else { prod };
+    let res = match (mac, mid) {
+        (false, false) => acc as u16,
+        (false, true) => (acc >> 16) as u16,
+        (true, false) => acc_clamp_unsigned3(acc as u16, acc),
+        (true, true) => acc_clamp_signed(acc),
+    };
+    (res, acc)
+}
+
+#[inline(always)]
+fn internal_vmudh(vs: u16, vt: u16, acc: i64, mac: bool) -> (u16, i64) {
+    let prod = (i64::from(vs as i16) * i64::from(vt as i16)) << 16;
+    let acc = if mac { acc_wrap(acc + prod) } else { prod };
+    (acc_clamp_signed(acc), acc)
+}
+
+#[inline(always)]
+fn internal_vmudl(vs: u16, vt: u16, acc: i64, mac: bool) -> (u16, i64) {
+    let prod = (i64::from(vs) * i64::from(vt)) >> 16;
+    if mac {
+        let acc = acc_wrap(acc + prod);
+        (acc_clamp_unsigned3(acc as u16, acc), acc)
+    } else {
+        (prod as u16, prod)
+    }
+}
+
+macro_rules! gen_mul_variant {
+    ($name:ident, $base:ident, $($arg:expr),*) => {
+        /// Compute a lane: returns the result and the new accumulator.
+        #[inline]
+        pub fn $name(vs: u16, vt: u16, acc: i64) -> (u16, i64) {
+            $base(vs, vt, acc, $($arg),*)
+        }
+    };
+}
+
+gen_mul_variant!(vmudn, internal_vmudnm, false, false);
+gen_mul_variant!(vmadn, internal_vmudnm, true, false);
+gen_mul_variant!(vmudm, internal_vmudnm, false, true);
+gen_mul_variant!(vmadm, internal_vmudnm, true, true);
+
+gen_mul_variant!(vmudh, internal_vmudh, false);
+gen_mul_variant!(vmadh, internal_vmudh, true);
+
+gen_mul_variant!(vmudl, internal_vmudl, false);
+gen_mul_variant!(vmadl, internal_vmudl, true);
+
+gen_mul_variant!(vmulf, internal_vmulfu, true, false);
+gen_mul_variant!(vmulu, internal_vmulfu, false, false);
+gen_mul_variant!(vmacf, internal_vmulfu, true, true);
+gen_mul_variant!(vmacu, internal_vmulfu, false, true);
+
+/// Flags produced by the clip functions for a lane, in the order of the
+/// SIMD versions: carry (sign), ne, le (VCC normal), ge (VCC clip) and vce.
+pub type ClipFlags = (bool, bool, bool, bool, bool);
+
+/// VCH on a lane: returns the result and the new flags.
+pub fn vch(vs: u16, vt: u16) -> (u16, ClipFlags) {
+    let (vs, vt) = (vs as i16, vt as i16);
+    let sign = (vs ^ vt) < 0;
+
+    // Operands of opposite sign can't overflow the sum, and operands of
+    // the same sign can't overflow the difference.
+    let (ge, le, vce, ne) = if sign {
+        let sum = vs.wrapping_add(vt);
+        (vt < 0, sum <= 0, sum == -1, sum != 0 && sum != -1)
+    } else {
+        (vs.wrapping_sub(vt) >= 0, vt < 0, false, vs != vt)
+    };
+
+    let res = match (sign, le, ge) {
+        (true, true, _) => vt.wrapping_neg(),
+        (false, _, true) => vt,
+        _ => vs,
+    };
+    (res as u16, (sign, ne, le, ge, vce))
+}
+
+/// VCR on a lane: returns the result and the new flags.
+pub fn vcr(vs: u16, vt: u16) -> (u16, ClipFlags) {
+    let (vs, vt) = (vs as i16, vt as i16);
+    let sign = (vs ^ vt) < 0;
+
+    let (ge, le) = if sign {
+        (vt < 0, vs.wrapping_add(vt) < 0)
+    } else {
+        (vs.wrapping_sub(vt) >= 0, vt < 0)
+    };
+
+    let res = match (sign, le, ge) {
+        (true, true, _) => vt.wrapping_neg(),
+        (false, _, true) => vt,
+        _ => vs,
+    };
+    (res as u16, (false, false, le, ge, false))
+}
+
+/// VCL on a lane, given the current flags: returns the result and the new
+/// flags.
+pub fn vcl(vs: u16, vt: u16, old: ClipFlags) -> (u16, ClipFlags) {
+    let (old_sign, old_ne, old_le, old_ge, old_vce) = old;
+
+    // VTSIGN = SIGN ? -VT : VT
+    let vtsign = if old_sign { vt.wrapping_neg() } else { vt };
+    let di = vs.wrapping_sub(vtsign);
+
+    // IF SIGN
+    let ncarry = di == vt.saturating_add(vs);
+    let le = match (old_ne, old_vce) {
+        (true, _) => old_le,
+        (false, true) => di == 0 || ncarry,
+        (false, false) => di == 0 && ncarry,
+    };
+
+    // IF NOT SIGN
+    let ge = if old_ne { old_ge } else { vt <= vs };
+
+    let mask = if old_sign { le } else { ge };
+    let res = if mask { vtsign } else { vs };
+    if old_sign {
+        (res, (false, false, le, old_ge, false))
+    } else {
+        (res, (false, false, old_le, ge, false))
+    }
+}
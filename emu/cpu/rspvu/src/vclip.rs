@@ -1,14 +1,9 @@
-use std::arch::x86_64::*;
-
-#[inline]
-#[target_feature(enable = "sse2")]
-unsafe fn vselect(mask: __m128i, a: __m128i, b: __m128i) -> __m128i {
-    _mm_or_si128(_mm_and_si128(mask, a), _mm_andnot_si128(mask, b))
-}
+use super::vcmp::vselect;
+use core::arch::x86_64::*;
 
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
-pub(crate) unsafe fn vch(
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vch(
     vs: __m128i,
     vt: __m128i,
 ) -> (__m128i, __m128i, __m128i, __m128i, __m128i, __m128i) {
@@ -74,8 +69,8 @@ pub(crate) unsafe fn vch(
 }
 
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
-pub(crate) unsafe fn vcr(
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vcr(
     vs: __m128i,
     vt: __m128i,
 ) -> (__m128i, __m128i, __m128i, __m128i, __m128i, __m128i) {
@@ -119,8 +114,8 @@ pub(crate) unsafe fn vcr(
 }
 
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
-pub(crate) unsafe fn vcl(
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vcl(
     vs: __m128i,
     vt: __m128i,
     old_sign: __m128i,
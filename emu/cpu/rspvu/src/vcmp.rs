@@ -0,0 +1,76 @@
+use core::arch::x86_64::*;
+
+// Select lanes from A where MASK is set, and from B otherwise.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vselect(mask: __m128i, a: __m128i, b: __m128i) -> __m128i {
+    _mm_or_si128(_mm_and_si128(mask, a), _mm_andnot_si128(mask, b))
+}
+
+// Signed lane-wise minimum of VS and VT. On equal lanes, VS is selected only
+// where TIE is set (which is irrelevant for the result, but not for the
+// returned mask).
+// Returns the result and the mask of lanes where VS was selected, which is
+// what compare opcodes store into VCC.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vmin(vs: __m128i, vt: __m128i, tie: __m128i) -> (__m128i, __m128i) {
+    let mask = _mm_or_si128(
+        _mm_cmpgt_epi16(vt, vs),
+        _mm_and_si128(tie, _mm_cmpeq_epi16(vs, vt)),
+    );
+    (vselect(mask, vs, vt), mask)
+}
+
+// Signed lane-wise maximum of VS and VT. See vmin() for the meaning of
+// TIE and the returned values.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn vmax(vs: __m128i, vt: __m128i, tie: __m128i) -> (__m128i, __m128i) {
+    let mask = _mm_or_si128(
+        _mm_cmpgt_epi16(vs, vt),
+        _mm_and_si128(tie, _mm_cmpeq_epi16(vs, vt)),
+    );
+    (vselect(mask, vs, vt), mask)
+}
+
+// Plain lane-wise minimum and maximum, without a selection mask. The VU
+// has both signed and unsigned comparisons, so the signedness is explicit
+// in the name: SSE2 min/max instructions are signed-only, and using them
+// for an unsigned comparison silently gives wrong results on lanes with
+// the top bit set.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn smin16(a: __m128i, b: __m128i) -> __m128i {
+    _mm_min_epi16(a, b)
+}
+
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn smax16(a: __m128i, b: __m128i) -> __m128i {
+    _mm_max_epi16(a, b)
+}
+
+// Unsigned versions: flipping the sign bit maps the unsigned range onto the
+// signed one, preserving the order.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn umin16(a: __m128i, b: __m128i) -> __m128i {
+    #[allow(overflowing_literals)]
+    let sign = _mm_set1_epi16(0x8000);
+    _mm_xor_si128(
+        _mm_min_epi16(_mm_xor_si128(a, sign), _mm_xor_si128(b, sign)),
+        sign,
+    )
+}
+
+#[inline]
+#[target_feature(enable = "sse4.1")]
+pub unsafe fn umax16(a: __m128i, b: __m128i) -> __m128i {
+    #[allow(overflowing_literals)]
+    let sign = _mm_set1_epi16(0x8000);
+    _mm_xor_si128(
+        _mm_max_epi16(_mm_xor_si128(a, sign), _mm_xor_si128(b, sign)),
+        sign,
+    )
+}
@@ -1,9 +1,9 @@
 use super::accumulator::{acc_add, acc_clamp_signed, acc_clamp_unsigned2, acc_clamp_unsigned3};
-use std::arch::x86_64::*;
+use core::arch::x86_64::*;
 
 // SSE 4.1 version
 #[inline]
-#[target_feature(enable = "sse2")]
+#[target_feature(enable = "sse4.1")]
 unsafe fn internal_vmulfu(
     vs: __m128i,
     vt: __m128i,
@@ -65,7 +65,7 @@ unsafe fn internal_vmulfu(
 
 // SSE 4.1 version
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
+#[target_feature(enable = "sse4.1")]
 pub(crate) unsafe fn internal_vmudnm(
     vs: __m128i,
     vt: __m128i,
@@ -110,7 +110,7 @@ pub(crate) unsafe fn internal_vmudnm(
 
 // SSE 4.1 version
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
+#[target_feature(enable = "sse4.1")]
 pub(crate) unsafe fn internal_vmudh(
     vs: __m128i,
     vt: __m128i,
@@ -137,7 +137,7 @@ pub(crate) unsafe fn internal_vmudh(
 
 // SSE 4.1 version
 #[inline] // FIXME: for some reason, Rust doesn't allow inline(always) here
-#[target_feature(enable = "sse2")]
+#[target_feature(enable = "sse4.1")]
 pub(crate) unsafe fn internal_vmudl(
     vs: __m128i,
     vt: __m128i,
@@ -196,18 +196,18 @@ macro_rules! gen_mul_variant {
     };
 }
 
-gen_mul_variant!(vmudn, internal_vmudnm, "sse2", false, false);
-gen_mul_variant!(vmadn, internal_vmudnm, "sse2", true, false);
-gen_mul_variant!(vmudm, internal_vmudnm, "sse2", false, true);
-gen_mul_variant!(vmadm, internal_vmudnm, "sse2", true, true);
+gen_mul_variant!(vmudn, internal_vmudnm, "sse4.1", false, false);
+gen_mul_variant!(vmadn, internal_vmudnm, "sse4.1", true, false);
+gen_mul_variant!(vmudm, internal_vmudnm, "sse4.1", false, true);
+gen_mul_variant!(vmadm, internal_vmudnm, "sse4.1", true, true);
 
-gen_mul_variant!(vmudh, internal_vmudh, "sse2", false);
-gen_mul_variant!(vmadh, internal_vmudh, "sse2", true);
+gen_mul_variant!(vmudh, internal_vmudh, "sse4.1", false);
+gen_mul_variant!(vmadh, internal_vmudh, "sse4.1", true);
 
-gen_mul_variant!(vmudl, internal_vmudl, "sse2", false);
-gen_mul_variant!(vmadl, internal_vmudl, "sse2", true);
+gen_mul_variant!(vmudl, internal_vmudl, "sse4.1", false);
+gen_mul_variant!(vmadl, internal_vmudl, "sse4.1", true);
 
-gen_mul_variant!(vmulf, internal_vmulfu, "sse2", true, false);
-gen_mul_variant!(vmulu, internal_vmulfu, "sse2", false, false);
-gen_mul_variant!(vmacf, internal_vmulfu, "sse2", true, true);
-gen_mul_variant!(vmacu, internal_vmulfu, "sse2", false, true);
+gen_mul_variant!(vmulf, internal_vmulfu, "sse4.1", true, false);
+gen_mul_variant!(vmulu, internal_vmulfu, "sse4.1", false, false);
+gen_mul_variant!(vmacf, internal_vmulfu, "sse4.1", true, true);
+gen_mul_variant!(vmacu, internal_vmulfu, "sse4.1", false, true);
@@ -0,0 +1,15 @@
+// Build configuration test: the vector unit core must be usable from a no_std
+// crate. Only the test harness links std.
+#![no_std]
+
+use rspvu::scalar::vmudh;
+use rspvu::vrcp::{vrcp, RCP_ROM};
+
+#[test]
+fn core_ops_without_std() {
+    assert_eq!(vrcp(0, &RCP_ROM[..]), 0x7FFF_FFFF);
+    assert_eq!(vrcp(0x8000, &RCP_ROM[..]), 0xFFFF);
+
+    // The scalar versions need no target feature, so they run on any host.
+    assert_eq!(vmudh(0x7FFF, 0x7FFF, 0), (0x7FFF, 0x3FFF_0001_0000));
+}
@@ -157,13 +157,13 @@ impl PartialEq for Breakpoint {
 }
 
 #[derive(Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
-pub(crate) enum WatchpointType {
+pub enum WatchpointType {
     Read,
     Write,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum WatchpointCondition {
+pub enum WatchpointCondition {
     Always,
     Eq(u64), // equal to
     Ne(u64), // not equal to
@@ -368,6 +368,20 @@ impl Debugger {
             .unwrap()
             .add_breakpoint(pc, description);
     }
+
+    pub fn add_watchpoint(
+        &mut self,
+        cpu_name: &str,
+        addr: u64,
+        description: &str,
+        wtype: WatchpointType,
+        condition: WatchpointCondition,
+    ) {
+        self.cpus
+            .get_mut(cpu_name)
+            .unwrap()
+            .add_watchpoint(addr, description, wtype, condition);
+    }
 }
 
 impl Debugger {
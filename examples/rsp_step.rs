@@ -0,0 +1,119 @@
+//! Single-step a RSP program, printing each instruction as it is executed,
+//! followed by the registers that it changed.
+//!
+//!     cargo run --example rsp_step -- <imem.bin> [dmem.bin] [max steps]
+//!
+//! The program starts at IMEM address 0, and runs until it halts (BREAK)
+//! or the maximum number of steps (default: 1000) is reached.
+
+use emu::bus::be::Device;
+use emu::dbg::{DisasmView, Tracer};
+use mips64::{Cop, REG_NAMES};
+use r64emu::dp::Dp;
+use r64emu::r4300::R4300;
+use r64emu::sp::{Cop2Reg, Sp, SpCop2, RSPCPU};
+use std::env;
+use std::fs;
+
+const SP_STATUS: u32 = 0x0404_0010;
+const SP_PC: u32 = 0x0408_0000;
+
+/// Create the RSP (and the minimum of the rest of the N64 needed to drive
+/// it), and load the IMEM and DMEM images.
+pub fn setup(logger: slog::Logger, imem: &[u8], dmem: &[u8]) {
+    R4300::new(logger.new(slog::o!())).register();
+    Dp::new(logger.new(slog::o!())).register();
+    Sp::new(logger.new(slog::o!())).unwrap().register();
+    {
+        let bus = &mut R4300::get_mut().bus;
+        bus.map_device(0x0400_0000, Sp::get(), 0).unwrap();
+        bus.map_device(0x0404_0000, Sp::get(), 1).unwrap();
+        bus.map_device(0x0408_0000, Sp::get(), 2).unwrap();
+    }
+    RSPCPU::get_mut().map_bus().unwrap();
+
+    let sp = Sp::get_mut();
+    sp.imem[..imem.len()].copy_from_slice(imem);
+    sp.dmem[..dmem.len()].copy_from_slice(dmem);
+}
+
+// All the registers that can change during a step: the GPRs, followed by
+// the COP2 registers (with the indices used by Cop::reg).
+fn registers() -> Vec<u128> {
+    let cpu = RSPCPU::get();
+    let ctx = cpu.ctx();
+    let mut regs: Vec<u128> = ctx.regs.iter().map(|&r| r.into()).collect();
+    regs.extend((0..=SpCop2::REG_DIV_OUT).map(|idx| cpu.cop2.reg(ctx, idx)));
+    regs
+}
+
+fn reg_name(idx: usize) -> String {
+    if idx < 32 {
+        REG_NAMES[idx].to_owned()
+    } else {
+        Cop2Reg::from_index(idx - 32).unwrap().to_string()
+    }
+}
+
+/// Run the program loaded by `setup` from IMEM address 0, one instruction
+/// at a time, and return the trace: one line per instruction with its
+/// address, its disassembly, and the new value of the registers it changed.
+pub fn trace(max_steps: usize) -> Vec<String> {
+    let main_bus = &mut R4300::get_mut().bus;
+    main_bus.write::<u32>(SP_PC, 0);
+    main_bus.write::<u32>(SP_STATUS, 1 << 0); // clear HALT
+
+    let cpu = RSPCPU::get_mut();
+    let t = Tracer::null();
+    let mut lines = Vec::new();
+    for _ in 0..max_steps {
+        if main_bus.read::<u32>(SP_STATUS) & 1 != 0 {
+            break;
+        }
+
+        let pc = cpu.ctx().get_pc() & 0xFFF;
+        let mut line = String::new();
+        cpu.disasm_block((pc, pc + 4), |_, _, insn| {
+            line = format!(
+                "{:03x}: {}",
+                pc,
+                insn.disasm().replace('\t', " ").trim_end()
+            );
+        });
+
+        let before = registers();
+        let clock = cpu.ctx().clock;
+        cpu.run(clock + 1, &t).unwrap();
+        for (idx, (old, new)) in before.iter().zip(registers()).enumerate() {
+            if *old != new {
+                line += &format!(" {}={:#x}", reg_name(idx), new);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// The tests include this file as a module, to run the functions above.
+#[allow(dead_code)]
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <imem.bin> [dmem.bin] [max steps]", args[0]);
+        std::process::exit(1);
+    }
+    let imem = fs::read(&args[1]).expect("cannot read IMEM image");
+    let dmem = args
+        .get(2)
+        .map(|f| fs::read(f).expect("cannot read DMEM image"))
+        .unwrap_or_default();
+    let max_steps = args
+        .get(3)
+        .map_or(1000, |n| n.parse().expect("invalid max steps"));
+
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    setup(logger, &imem, &dmem);
+    for line in trace(max_steps) {
+        println!("{}", line);
+    }
+}
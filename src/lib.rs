@@ -10,6 +10,8 @@ extern crate emu_derive;
 extern crate byteorder;
 extern crate emu;
 extern crate mips64;
+#[cfg(feature = "cop2")]
+extern crate rspvu;
 
 extern crate packed_simd;
 
@@ -23,6 +25,7 @@ pub mod errors {
     error_chain! {
         foreign_links {
             Io(::std::io::Error);
+            Json(::serde_json::Error);
         }
     }
 }
@@ -14,12 +14,26 @@ use super::si::Si;
 use super::sp::{Sp, RSPCPU};
 use super::vi::Vi;
 
+#[cfg(feature = "cop1")]
+type Fpu = mips64::Fpu;
+#[cfg(not(feature = "cop1"))]
+type Fpu = mips64::CopNull;
+
+#[cfg(feature = "cop1")]
+fn new_fpu(logger: slog::Logger) -> Fpu {
+    mips64::Fpu::new("R4300-FPU", logger)
+}
+#[cfg(not(feature = "cop1"))]
+fn new_fpu(_logger: slog::Logger) -> Fpu {
+    mips64::CopNull {}
+}
+
 pub struct R4300Config;
 
 impl mips64::Config for R4300Config {
     type Arch = mips64::ArchIII; // 64-bit MIPS III architecture
     type Cop0 = mips64::Cp0;
-    type Cop1 = mips64::Fpu;
+    type Cop1 = Fpu;
     type Cop2 = mips64::CopNull;
     type Cop3 = mips64::CopNull;
 }
@@ -51,7 +65,7 @@ impl R4300 {
                 Bus::new(logger.new(o!())),
                 (
                     mips64::Cp0::new("R4300-COP0", logger.new(o!())),
-                    mips64::Fpu::new("R4300-FPU", logger.new(o!())),
+                    new_fpu(logger.new(o!())),
                     mips64::CopNull {},
                     mips64::CopNull {},
                 ),
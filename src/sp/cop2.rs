@@ -1,41 +1,51 @@
 extern crate emu;
 
 use super::decode::{decode, ACC_NAMES, VREG_NAMES};
-use super::sp::Sp;
-use super::vclip;
-use super::vmul;
-use super::vrcp;
+use super::instr::Cop2Instr;
+use super::oplog::{OpLog, OpLogEntry};
+use super::sp::{Sp, StatusFlags, DMEM_ALLOC_SIZE, DMEM_SIZE};
+use super::stats::OpStats;
+use rspvu::vcmp::{smax16, smin16, umax16, umin16, vmax, vmin, vselect};
+use rspvu::{scalar, vclip, vmul, vrcp};
 
 use crate::errors::*;
+use crate::n64::RSPCPU_NAME;
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use emu::bus::be::{Bus, Device};
 use emu::dbg;
 use emu::int::Numerics;
 use emu::memint::MemInt;
 use emu::state::Field;
-use mips64::{Cop, CpuContext};
+use mips64::{Cop, CopError, CpuContext};
 use serde_derive::{Deserialize, Serialize};
 use slog;
 use std::arch::x86_64::*;
+use std::fmt;
 
 // Vector registers as array of u8.
-// Kept as little endian so that it's easier to directly load into SSE registers
-#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+// Kept as little endian so that it's easier to directly load into SSE registers.
+// All SSE accesses to vector registers (and accumulator slices) go through
+// m128/setm128, which are the only places relying on the alignment.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
 #[repr(align(16))]
 struct VectorReg([u8; 16]);
 
 impl VectorReg {
     fn byte(&self, idx: usize) -> u8 {
+        debug_assert!(idx < 16, "invalid vector register byte: {}", idx);
         self.0[15 - idx]
     }
     fn setbyte(&mut self, idx: usize, val: u8) {
+        debug_assert!(idx < 16, "invalid vector register byte: {}", idx);
         self.0[15 - idx] = val;
     }
 
     fn lane(&self, idx: usize) -> u16 {
+        debug_assert!(idx < 8, "invalid vector register lane: {}", idx);
         LittleEndian::read_u16(&self.0[(7 - idx) * 2..])
     }
     fn setlane(&mut self, idx: usize, val: u16) {
+        debug_assert!(idx < 8, "invalid vector register lane: {}", idx);
         LittleEndian::write_u16(&mut self.0[(7 - idx) * 2..], val);
     }
 
@@ -46,16 +56,33 @@ impl VectorReg {
         LittleEndian::write_u128(&mut self.0, val);
     }
 
+    fn lanes(&self) -> [u16; 8] {
+        let mut lanes = [0u16; 8];
+        for (idx, lane) in lanes.iter_mut().enumerate() {
+            *lane = self.lane(idx);
+        }
+        lanes
+    }
+    fn setlanes(&mut self, lanes: [u16; 8]) {
+        for (idx, &lane) in lanes.iter().enumerate() {
+            self.setlane(idx, lane);
+        }
+    }
+
     fn m128(&self) -> __m128i {
         unsafe { _mm_loadu_si128(self.0.as_ptr() as *const _) }
     }
     fn setm128(&mut self, val: __m128i) {
-        unsafe { _mm_store_si128(self.0.as_ptr() as *mut _, val) };
+        unsafe { _mm_store_si128(self.0.as_mut_ptr() as *mut _, val) };
     }
 }
 
-#[derive(Copy, Clone, Default, Serialize, Deserialize)]
-struct SpCop2Context {
+/// Architectural state of the vector unit: vector registers, accumulator and
+/// flag registers, plus the pending state of the divide unit. Obtained with
+/// `SpCop2::snapshot`; two states can be compared with `==`, or with `diff`
+/// to find out where they differ.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpCop2Context {
     vregs: [VectorReg; 32],
     accum: [VectorReg; 3],
     vco_carry: VectorReg,
@@ -67,10 +94,62 @@ struct SpCop2Context {
     div_out: u32,
 }
 
+/// First difference between two `SpCop2Context`, as returned by
+/// `SpCop2Context::diff`. `field` is the name of the register ("v0".."v31",
+/// "acc_lo", "acc_md", "acc_hi", "vco_carry", "vco_ne", "vce", "vcc_normal",
+/// "vcc_clip", "div_in", "div_out"), and `lane` the differing lane for vector
+/// registers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StateDiff {
+    pub field: &'static str,
+    pub lane: Option<usize>,
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.lane {
+            Some(lane) => write!(f, "{} lane {}", self.field, lane),
+            None => write!(f, "{}", self.field),
+        }
+    }
+}
+
 pub struct SpCop2 {
     ctx: Field<SpCop2Context>,
     name: String,
     logger: slog::Logger,
+    rcp_rom: &'static [u16],
+    rsq_rom: &'static [u16],
+
+    // True if the host supports the SIMD instructions used to implement the
+    // vector unit (checked once at construction). If false, the slower
+    // scalar implementation is used instead.
+    simd: bool,
+
+    // If true, loads and stores access DMEM through the RSP bus rather than
+    // directly (see set_dmem_via_bus).
+    dmem_via_bus: bool,
+
+    // Opcode log being recorded, if any (see start_recording).
+    oplog: Option<OpLog>,
+
+    // Per-opcode execution counters, if enabled (see enable_stats).
+    stats: Option<Box<OpStats>>,
+
+    // DMEM address that loads and stores treat as faulting, if any (see
+    // inject_fault).
+    fault_addr: Option<u32>,
+
+    // Error caused by the last failing opcode (see Cop::take_error).
+    error: Option<CopError>,
+
+    // Private DMEM used in place of the SP one by instances that are not
+    // attached to a Sp (see standalone).
+    dmem: Option<Box<[u8]>>,
+
+    // If false, hardware quirks are replaced by the documented behavior
+    // (see set_strict_hw).
+    strict_hw: bool,
 }
 
 impl SpCop2 {
@@ -80,131 +159,759 @@ impl SpCop2 {
     pub const REG_ACCUM_LO: usize = 35;
     pub const REG_ACCUM_MD: usize = 36;
     pub const REG_ACCUM_HI: usize = 37;
+    /// Last result of the divider (VRCP/VRSQ and their variants), whose high
+    /// half is returned by VRCPH/VRSQH.
+    pub const REG_DIV_OUT: usize = 38;
 
     pub fn new(name: &str, logger: slog::Logger) -> Result<SpCop2> {
+        SpCop2::with_reciprocal_rom(name, logger, &vrcp::RCP_ROM[..], &vrcp::RSQ_ROM[..])
+    }
+
+    /// Create a SpCop2 that is not attached to a Sp, for tests of the vector
+    /// unit that don't need a whole RSP: loads and stores access a private
+    /// 4 KiB DMEM (see dmem_mut), and failing opcodes don't halt the RSP
+    /// (errors are still reported through the tracer and Cop::take_error).
+    pub fn standalone(name: &str, logger: slog::Logger) -> Result<SpCop2> {
+        let mut cop2 = SpCop2::new(name, logger)?;
+        cop2.dmem = Some(vec![0u8; DMEM_ALLOC_SIZE].into_boxed_slice());
+        Ok(cop2)
+    }
+
+    /// Return the private DMEM of a standalone instance, or None if the
+    /// vector unit accesses the DMEM of the Sp.
+    pub fn dmem_mut(&mut self) -> Option<&mut [u8]> {
+        self.dmem.as_mut().map(|dmem| &mut dmem[..DMEM_SIZE])
+    }
+
+    // DMEM buffer that loads read directly, if it is the private one of a
+    // standalone instance; None if they go through the bus or the Sp.
+    pub(crate) fn load_dmem(&mut self) -> Option<&mut [u8]> {
+        if self.dmem_via_bus {
+            return None;
+        }
+        self.dmem_mut()
+    }
+
+    /// Create a SpCop2 that uses the specified tables in place of the
+    /// reciprocal (VRCP*) and inverse square root (VRSQ*) ROMs. The table
+    /// sizes must be powers of two; this is meant for testing with small
+    /// synthetic tables.
+    pub fn with_reciprocal_rom(
+        name: &str,
+        logger: slog::Logger,
+        rcp: &'static [u16],
+        rsq: &'static [u16],
+    ) -> Result<SpCop2> {
+        assert!(rcp.len().is_power_of_two() && rsq.len().is_power_of_two());
         Ok(SpCop2 {
             name: name.to_owned(),
             ctx: Field::new("sp::cop2", SpCop2Context::default()),
             logger: logger,
+            rcp_rom: rcp,
+            rsq_rom: rsq,
+            simd: is_x86_feature_detected!("sse4.1"),
+            dmem_via_bus: false,
+            oplog: None,
+            stats: None,
+            fault_addr: None,
+            error: None,
+            dmem: None,
+            strict_hw: true,
         })
     }
 
-    fn oploadstore(op: u32, ctx: &CpuContext) -> (u32, usize, u32, u32, u32) {
-        let base = ctx.regs[((op >> 21) & 0x1F) as usize] as u32;
-        let vt = ((op >> 16) & 0x1F) as usize;
-        let opcode = (op >> 11) & 0x1F;
-        let element = (op >> 7) & 0xF;
-        let offset = (((op as i32) & 0x7F) << 25) >> 25;
-        (base, vt, opcode, element, offset as u32)
+    /// Execute a single COP2 opcode. This is the safe entry point to the vector
+    /// unit: computational opcodes are implemented with SSE4.1, and on hosts
+    /// without it they go through a slower scalar implementation, with the
+    /// same results.
+    pub fn execute(&mut self, cpu: &mut CpuContext, op: u32, t: &dbg::Tracer) -> dbg::Result<()> {
+        if let Some(ref mut oplog) = self.oplog {
+            let gpr = match Cop2Instr::decode(op) {
+                Cop2Instr::Mtc2 { rt, .. } | Cop2Instr::Ctc2 { rt, .. } => cpu.regs[rt],
+                _ => 0,
+            };
+            oplog.push(OpLogEntry::Op { opcode: op, gpr });
+        }
+        if !self.simd {
+            return self.sop(cpu, op, t);
+        }
+        unsafe { self.uop(cpu, op, t) }
     }
-}
 
-impl SpCop2Context {
-    fn vce(&self) -> u8 {
-        let mut res = 0u8;
-        for i in 0..8 {
-            res |= ((self.vce.lane(i) & 1) << i) as u8;
+    /// Execute a computational opcode (VADD, VMULF, ...). These don't access
+    /// the scalar CPU, so no CpuContext is needed: this is a shortcut for
+    /// tests of the vector unit. Errors are returned instead of breaking into
+    /// the debugger; any other opcode (e.g. MFC2 or CFC2) is rejected with
+    /// `CopError::NotComputational` without being executed.
+    pub fn op_vector_only(&mut self, op: u32) -> Result<(), CopError> {
+        match Cop2Instr::decode(op) {
+            Cop2Instr::Compute { .. } => {}
+            _ => return Err(CopError::NotComputational(op)),
+        }
+        // The null tracer never breaks: failures are only recorded.
+        let _ = self.execute(&mut CpuContext::default(), op, &dbg::Tracer::null());
+        self.error.take().map_or(Ok(()), Err)
+    }
+
+    /// Behave as if the host did not support SSE4.1, using the scalar
+    /// implementation of the computational opcodes. This is only meant to
+    /// test it against the SIMD one.
+    #[doc(hidden)]
+    pub fn disable_simd(&mut self) {
+        self.simd = false;
+    }
+
+    /// Return the computational opcodes (values of the func field) implemented
+    /// by the vector unit. Any other computational opcode halts the RSP.
+    pub fn supported_ops() -> &'static [u8] {
+        &VU_OPS
+    }
+
+    /// Route LWC2/SWC2 accesses to DMEM through the RSP bus instead of
+    /// accessing the DMEM buffer directly. This is slower, but each byte
+    /// access goes through the bus and the tracer, so mapped accesses and
+    /// watchpoints work as they do for scalar loads and stores.
+    pub fn set_dmem_via_bus(&mut self, enable: bool) {
+        self.dmem_via_bus = enable;
+    }
+
+    /// Make LWC2/SWC2 opcodes touching the DMEM address `addr` fail as if the
+    /// access faulted: the opcode is skipped and the tracer breaks. This is
+    /// only meant to exercise error paths in tests; None removes the fault.
+    #[doc(hidden)]
+    pub fn inject_fault(&mut self, addr: Option<u32>) {
+        self.fault_addr = addr;
+    }
+
+    // Check whether a LWC2/SWC2 opcode hits the injected fault address, and
+    // return it (as a DMEM address) if so.
+    fn faulting(&self, op: u32, ctx: &CpuContext) -> Option<u32> {
+        let mask = DMEM_SIZE as u32 - 1;
+        let fault = self.fault_addr? & mask;
+        let (ea, len) = SpCop2::effective_address(op, ctx);
+        if (0..len as u32).any(|i| (ea + i) & mask == fault) {
+            Some(fault)
+        } else {
+            None
         }
-        res
     }
-    fn set_vce(&mut self, vce: u8) {
-        for i in 0..8 {
-            let vce = (vce >> i) & 1;
-            self.vce.setlane(i, if vce != 0 { 0xFFFF } else { 0 });
+
+    /// Log a hexdump of a region of DMEM at debug level, one record per line
+    /// of 16 bytes, to help diagnosing corrupted loads and stores. The region
+    /// is clamped to the end of DMEM.
+    pub fn log_dmem_region(&self, start: usize, len: usize) {
+        let dmem = match self.dmem {
+            Some(ref dmem) => &dmem[..DMEM_SIZE],
+            None => &Sp::get().dmem[..DMEM_SIZE],
+        };
+        let start = start.min(DMEM_SIZE);
+        let end = start.saturating_add(len).min(DMEM_SIZE);
+        for (i, line) in dmem[start..end].chunks(16).enumerate() {
+            let data: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            debug!(self.logger, "DMEM dump";
+                "addr" => ((start + i * 16) as u32).hex(),
+                "data" => data.join(" "));
         }
     }
 
-    fn vcc(&self) -> u16 {
-        let mut res = 0u16;
-        for i in 0..8 {
-            res |= (self.vcc_normal.lane(i) & 1) << i;
-            res |= (self.vcc_clip.lane(i) & 1) << (i + 8);
+    /// Select between bug-for-bug hardware behavior (the default) and the
+    /// behavior described by the documentation, for the opcodes where they
+    /// differ:
+    ///
+    ///   * VSAR only reads the accumulator on hardware; when not strict, it
+    ///     also writes VS into the selected accumulator slice.
+    ///   * VMOV reads the VT lane selected by applying the element to the
+    ///     destination lane on hardware; when not strict, it reads lane e.
+    ///   * VRSQ and VRSQL of -32768 (0xFFFF8000 in double precision) return
+    ///     a hardcoded result on hardware; when not strict, it is computed
+    ///     through the ROM like any other input.
+    ///
+    /// The other reciprocal opcodes behave the same in both modes: VRCP and
+    /// VRCPL of zero return 0x7FFFFFFF as documented, and VRCPH and VRSQH
+    /// only move the high halves of the input and result.
+    pub fn set_strict_hw(&mut self, enable: bool) {
+        self.strict_hw = enable;
+    }
+
+    /// Reset the vector unit to its power-on state: vector registers,
+    /// accumulators, control registers and divider state are all cleared.
+    pub fn reset(&mut self) {
+        *self.ctx = SpCop2Context::default();
+    }
+
+    /// Start recording the opcodes executed by the vector unit into an
+    /// opcode log, discarding any recording in progress.
+    pub fn start_recording(&mut self) {
+        self.oplog = Some(OpLog::new(*self.ctx));
+    }
+
+    /// Stop recording and return the opcode log, which also holds the
+    /// current state as the expected result of a replay. Returns None if no
+    /// recording was in progress.
+    pub fn stop_recording(&mut self) -> Option<OpLog> {
+        let mut oplog = self.oplog.take()?;
+        oplog.finish(*self.ctx);
+        Some(oplog)
+    }
+
+    /// Enable or disable counting the opcodes executed by the vector unit.
+    /// Counters are reset each time they are enabled. This is disabled by
+    /// default, to keep it out of the hot path.
+    pub fn enable_stats(&mut self, enable: bool) {
+        self.stats = if enable { Some(Box::default()) } else { None };
+    }
+
+    /// Return the opcode counters, or None if they are not enabled.
+    pub fn stats(&self) -> Option<&OpStats> {
+        self.stats.as_deref()
+    }
+
+    /// Return the 48-bit accumulator of a lane (0-7), sign-extended.
+    pub fn accumulator(&self, lane: usize) -> i64 {
+        self.ctx.accumulator(lane)
+    }
+
+    /// Set the 48-bit accumulator of a lane (0-7), to seed it before a chain
+    /// of multiply-accumulate opcodes. Bits of value above 47 are ignored.
+    pub fn set_accumulator(&mut self, lane: usize, value: i64) {
+        self.ctx.set_accumulator(lane, value);
+    }
+
+    /// Set the accumulators of all the lanes (see set_accumulator).
+    pub fn set_accumulators(&mut self, values: &[i64; 8]) {
+        for (lane, &value) in values.iter().enumerate() {
+            self.set_accumulator(lane, value);
         }
-        res
     }
-    fn set_vcc(&mut self, vcc: u16) {
-        for i in 0..8 {
-            let normal = (vcc >> i) & 1;
-            let clip = (vcc >> (i + 8)) & 1;
 
-            self.vcc_normal
-                .setlane(i, if normal != 0 { 0xFFFF } else { 0 });
-            self.vcc_clip.setlane(i, if clip != 0 { 0xFFFF } else { 0 });
+    /// Return the 32 vector registers as a flat buffer: v0 at offset 0, v1 at
+    /// offset 16 and so on. Each register is big-endian (lane 0 first), which
+    /// is the same layout that SQV writes to DMEM.
+    pub fn export_vregs(&self) -> [u8; 512] {
+        let mut buf = [0u8; 512];
+        for (dst, vreg) in buf.chunks_exact_mut(16).zip(self.ctx.vregs.iter()) {
+            BigEndian::write_u128(dst, vreg.u128());
         }
+        buf
     }
 
-    fn vco(&self) -> u16 {
-        let mut res = 0u16;
-        for i in 0..8 {
-            res |= (self.vco_carry.lane(i) & 1) << i;
-            res |= (self.vco_ne.lane(i) & 1) << (i + 8);
+    /// Load the 32 vector registers from a buffer in the layout returned by
+    /// export_vregs.
+    pub fn import_vregs(&mut self, buf: &[u8; 512]) {
+        for (src, vreg) in buf.chunks_exact(16).zip(self.ctx.vregs.iter_mut()) {
+            vreg.setu128(BigEndian::read_u128(src));
+        }
+    }
+
+    /// Return a copy of the current state of the vector unit.
+    pub fn snapshot(&self) -> SpCop2Context {
+        *self.ctx
+    }
+
+    pub(crate) fn restore(&mut self, ctx: SpCop2Context) {
+        *self.ctx = ctx;
+    }
+
+    // Halt the RSP as if a BREAK opcode was executed: both HALT and BROKE are
+    // set in the SP status register, so that the host can observe that the
+    // RSP stopped because of an error in the microcode. The error is kept
+    // for Cop::take_error.
+    fn halt(&mut self, cpu: &mut CpuContext, err: CopError) {
+        self.error = Some(err);
+        if self.dmem.is_some() {
+            // Standalone instance: there is no RSP to halt.
+            return;
+        }
+        let sp = Sp::get_mut();
+        let mut status = sp.get_status();
+        status.insert(StatusFlags::HALT | StatusFlags::BROKE);
+        match sp.set_status(status) {
+            Some(halt) => cpu.set_halt_line(halt),
+            None => {}
+        }
+    }
+
+    fn oploadstore(op: u32, ctx: &CpuContext) -> (u32, usize, u32, u32, u32) {
+        match Cop2Instr::decode(op) {
+            Cop2Instr::Load {
+                op,
+                vt,
+                base,
+                e,
+                offset,
+            }
+            | Cop2Instr::Store {
+                op,
+                vt,
+                base,
+                e,
+                offset,
+            } => (ctx.regs[base] as u32, vt, op, e as u32, offset as u32),
+            insn => panic!("invalid COP2 load/store: {:?}", insn),
+        }
+    }
+
+    /// Compute the range of DMEM touched by a LWC2/SWC2 opcode, without
+    /// performing the access: the start address and the length in bytes.
+    /// The range can wrap around the end of DMEM. Subword opcodes access
+    /// just the subword; LQV/SQV the bytes from the address to the end of its
+    /// quadword, and LRV/SRV the ones from the start of the quadword up to the
+    /// address. Opcodes working on 8-byte boundaries (LPV..LTV and their
+    /// stores) report the 16 bytes starting at the double-word. Unknown
+    /// opcodes have a zero length.
+    ///
+    /// Panics if `op` is not a LWC2/SWC2 opcode.
+    pub fn effective_address(op: u32, ctx: &CpuContext) -> (u32, usize) {
+        let (base, _, op, _, offset) = SpCop2::oploadstore(op, ctx);
+        let shift = VLOAD_OPS.get(op as usize).map_or(0, |o| o.1);
+        let ea = dmem_ea(base, offset, shift);
+        let (start, len) = match op {
+            0x00..=0x03 => (ea, 1 << shift),
+            0x04 => (ea, 0x10 - (ea & 0xF)),
+            0x05 => (ea & !0xF, ea & 0xF),
+            0x06..=0x0B => (ea & !0x7, 0x10),
+            _ => (ea, 0),
+        };
+        (start as u32, len)
+    }
+
+    // Trace a VU load/store opcode. The record is only built when the trace
+    // level is compiled in (see the "trace-vu" feature), so this is almost
+    // free otherwise.
+    fn trace_loadstore(
+        &self,
+        store: bool,
+        opcode: u32,
+        base: u32,
+        vt: usize,
+        element: u32,
+        offset: u32,
+    ) {
+        let (msg, names) = if store {
+            ("VU store", &VSTORE_OPS)
+        } else {
+            ("VU load", &VLOAD_OPS)
+        };
+        let (name, shift) = names.get(opcode as usize).cloned().unwrap_or(("???", 4));
+        trace!(self.logger, "{}", msg;
+            "op" => name,
+            "ea" => (dmem_ea(base, offset, shift) as u32).hex(),
+            "element" => element,
+            "vt" => VREG_NAMES[vt]);
+    }
+
+    // Execute a SWC2 opcode on the specified DMEM buffer. Returns false if
+    // the opcode is not implemented.
+    fn store(
+        ctx: &SpCop2Context,
+        dmem: &mut [u8],
+        op: u32,
+        base: u32,
+        vtidx: usize,
+        element: u32,
+        offset: u32,
+    ) -> bool {
+        let vt = &ctx.vregs[vtidx];
+        match op {
+            0x00 => sxv::<u8>(dmem, base, offset, vt, element as usize), // SBV
+            0x01 => sxv::<u16>(dmem, base, offset, vt, element as usize), // SSV
+            0x02 => sxv::<u32>(dmem, base, offset, vt, element as usize), // SLV
+            0x03 => sxv::<u64>(dmem, base, offset, vt, element as usize), // SDV
+            0x04 => {
+                // SQV
+                let ea = dmem_ea(base, offset, 4);
+                let qw_start = ea & !0xF;
+                let ea_idx = ea & 0xF;
+
+                let mut reg = vt.u128();
+                reg = reg.rotate_left(element_to_bit(element as usize) as u32);
+
+                let memptr = &mut dmem[qw_start..qw_start + 0x10];
+                write_partial_right::<BigEndian>(memptr, reg, ea_idx * 8, 128);
+            }
+            0x05 => {
+                // SRV
+                let ea = dmem_ea(base, offset, 4);
+                let qw_start = ea & !0xF;
+                let ea_idx = ea & 0xF;
+
+                let mut reg = vt.u128();
+                reg = reg.rotate_left(element_to_bit(element as usize) as u32);
+
+                let memptr = &mut dmem[qw_start..qw_start + 0x10];
+                write_partial_left::<BigEndian>(memptr, reg, (16 - ea_idx) * 8);
+            }
+            0x06 => {
+                // SPV
+                let ea = dmem_ea(base, offset, 3);
+
+                let memptr = &mut dmem[ea..ea + 0x10];
+                for e in 0 as usize..8 as usize {
+                    let eidx = (e + element as usize) & 0xF;
+                    memptr[e] = ((vt.lane(eidx & 0x7) << (eidx >> 3)) >> 8) as u8;
+                }
+            }
+            0x07 => {
+                // SUV
+                let ea = dmem_ea(base, offset, 3);
+
+                let memptr = &mut dmem[ea..ea + 0x10];
+                for e in 0 as usize..8 as usize {
+                    let eidx = (e + element as usize) & 0xF;
+                    memptr[e] = ((vt.lane(eidx & 0x7) >> (eidx >> 3)) >> 7) as u8;
+                }
+            }
+            0x08 => {
+                // SHV
+                let ea = dmem_ea(base, offset, 4);
+                let qw_start = ea as usize & !0x7;
+                let ea_idx = ea & 0x7;
+
+                let memptr = &mut dmem[qw_start..qw_start + 0x10];
+                for e in 0 as usize..8 as usize {
+                    let eidx = (e * 2 + element as usize) & 0xF;
+                    let midx = (e * 2 + ea_idx) & 0xF;
+                    let v = ((vt.byte(eidx) as u16) << 8) | vt.byte((eidx + 1) & 0xF) as u16;
+                    memptr[midx] = (v >> 7) as u8;
+                }
+            }
+            0x09 => {
+                // SFV
+                // FIXME: this is dumped through experimentation. Surely there's no
+                // table in the silicon... figure it out the pattern and the logic.
+                const LANES: [[isize; 4]; 16] = [
+                    [0, 1, 2, 3],     // e0
+                    [6, 7, 4, 5],     // e1
+                    [-1, -1, -1, -1], // e2
+                    [-1, -1, -1, -1], // e3
+                    [1, 2, 3, 0],     // e4
+                    [7, 4, 5, 6],     // e5
+                    [-1, -1, -1, -1], // e6
+                    [-1, -1, -1, -1], // e7
+                    [4, 5, 6, 7],     // e8
+                    [-1, -1, -1, -1], // e9
+                    [-1, -1, -1, -1], // e10
+                    [3, 0, 1, 2],     // e11
+                    [5, 6, 7, 4],     // e12
+                    [-1, -1, -1, -1], // e13
+                    [-1, -1, -1, -1], // e14
+                    [0, 1, 2, 3],     // e15
+                ];
+
+                let ea = dmem_ea(base, offset, 4);
+                let qw_start = ea as usize & !0x7;
+                let ea_idx = ea & 0x7;
+
+                let memptr = &mut dmem[qw_start..qw_start + 0x10];
+                for e in 0 as usize..4 as usize {
+                    let eidx = LANES[element as usize][e];
+                    let v = if eidx < 0 {
+                        0 as u16
+                    } else {
+                        vt.lane(eidx as usize) as u16
+                    };
+                    let midx = (e * 4 + ea_idx) & 0xF;
+                    memptr[midx] = (v >> 7) as u8;
+                }
+            }
+            0x0A => {
+                // SWV
+                let ea = dmem_ea(base, offset, 4) as u32;
+                let qw_start = ea as usize & !0x7;
+
+                let mut reg = vt.u128();
+                reg = reg.rotate_right((ea & 7) * 8);
+                reg = reg.rotate_left(element_to_bit(element as usize) as u32);
+                BigEndian::write_u128(&mut dmem[qw_start..qw_start + 0x10], reg);
+            }
+            0x0B => {
+                // STV
+                let ea = dmem_ea(base, offset, 4) as u32;
+                let qw_start = ea as usize & !0x7;
+                let mut mem: u128 = 0;
+
+                let vtbase = vtidx & !7;
+                let mut vtoff = element as usize >> 1;
+
+                for e in 0..8 {
+                    let r = ctx.vregs[vtbase + vtoff].lane(e);
+                    mem <<= 16;
+                    mem |= r as u128;
+                    vtoff += 1;
+                    vtoff &= 7;
+                }
+
+                mem = mem.rotate_right((ea & 7) * 8);
+                for i in 0..0x10 {
+                    dmem[(qw_start + i) & 0xFFF] = (mem >> (120 - i * 8)) as u8;
+                }
+            }
+            _ => return false,
         }
-        res
+        true
     }
+}
 
-    fn set_vco(&mut self, vco: u16) {
-        for i in 0..8 {
-            let carry = (vco >> i) & 1;
-            let ne = (vco >> (i + 8)) & 1;
+// Computational opcodes (func field) implemented by uop_compute. Keep in sync
+// with its match arms.
+const VU_OPS: [u8; 43] = [
+    0x00, 0x01, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0C, 0x0D, 0x0E, 0x0F,
+    0x10, 0x11, 0x13, 0x14, 0x15, 0x17, 0x19, 0x1D, 0x20, 0x21, 0x22, 0x23,
+    0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x30, 0x31,
+    0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x3F,
+];
+
+// Mnemonics of LWC2/SWC2 opcodes (indexed by opcode), together with the
+// shift applied to the offset to compute the effective address.
+const VLOAD_OPS: [(&str, usize); 12] = [
+    ("lbv", 0),
+    ("lsv", 1),
+    ("llv", 2),
+    ("ldv", 3),
+    ("lqv", 4),
+    ("lrv", 4),
+    ("lpv", 3),
+    ("luv", 3),
+    ("lhv", 4),
+    ("lfv", 4),
+    ("lwv", 4),
+    ("ltv", 4),
+];
+
+const VSTORE_OPS: [(&str, usize); 12] = [
+    ("sbv", 0),
+    ("ssv", 1),
+    ("slv", 2),
+    ("sdv", 3),
+    ("sqv", 4),
+    ("srv", 4),
+    ("spv", 3),
+    ("suv", 3),
+    ("shv", 4),
+    ("sfv", 4),
+    ("swv", 4),
+    ("stv", 4),
+];
 
-            self.vco_carry
-                .setlane(i, if carry != 0 { 0xFFFF } else { 0 });
-            self.vco_ne.setlane(i, if ne != 0 { 0xFFFF } else { 0 });
+impl SpCop2Context {
+    /// Compare with another state, returning the first differing register
+    /// (and lane), or None if the two states are equal.
+    pub fn diff(&self, other: &SpCop2Context) -> Option<StateDiff> {
+        let vregs = VREG_NAMES.iter().zip(self.vregs.iter().zip(&other.vregs));
+        let accum = ACC_NAMES.iter().zip(self.accum.iter().zip(&other.accum));
+        let flags = [
+            ("vco_carry", (&self.vco_carry, &other.vco_carry)),
+            ("vco_ne", (&self.vco_ne, &other.vco_ne)),
+            ("vce", (&self.vce, &other.vce)),
+            ("vcc_normal", (&self.vcc_normal, &other.vcc_normal)),
+            ("vcc_clip", (&self.vcc_clip, &other.vcc_clip)),
+        ];
+        let flags = flags.iter().map(|(name, regs)| (name, *regs));
+
+        for (&field, (a, b)) in vregs.chain(accum).chain(flags) {
+            if let Some(lane) = (0..8).find(|&l| a.lane(l) != b.lane(l)) {
+                return Some(StateDiff {
+                    field,
+                    lane: Some(lane),
+                });
+            }
         }
+        let field = if self.div_in != other.div_in {
+            "div_in"
+        } else if self.div_out != other.div_out {
+            "div_out"
+        } else {
+            return None;
+        };
+        Some(StateDiff { field, lane: None })
+    }
+
+    fn accumulator(&self, lane: usize) -> i64 {
+        let lo = self.accum[0].lane(lane) as u64;
+        let md = self.accum[1].lane(lane) as u64;
+        let hi = self.accum[2].lane(lane) as u64;
+        (((hi << 32) | (md << 16) | lo) << 16) as i64 >> 16
+    }
+    fn set_accumulator(&mut self, lane: usize, value: i64) {
+        self.accum[0].setlane(lane, value as u16);
+        self.accum[1].setlane(lane, (value >> 16) as u16);
+        self.accum[2].setlane(lane, (value >> 32) as u16);
+    }
+
+    fn vce(&self) -> u8 {
+        pack_lanes(&self.vce)
+    }
+    // The setters take the full value written by CTC2 (or the debugger), and
+    // ignore the bits past the width of the register: 16 bits for VCO and
+    // VCC, 8 bits for VCE.
+    fn set_vce(&mut self, vce: u32) {
+        unpack_lanes((vce & 0xFF) as u8, &mut self.vce);
+    }
+
+    fn vcc(&self) -> u16 {
+        pack_flags(&self.vcc_normal, &self.vcc_clip)
+    }
+    fn set_vcc(&mut self, vcc: u32) {
+        unpack_flags((vcc & 0xFFFF) as u16, &mut self.vcc_normal, &mut self.vcc_clip);
+    }
+
+    fn vco(&self) -> u16 {
+        pack_flags(&self.vco_carry, &self.vco_ne)
+    }
+    fn set_vco(&mut self, vco: u32) {
+        unpack_flags((vco & 0xFFFF) as u16, &mut self.vco_carry, &mut self.vco_ne);
+    }
+}
+
+// Flag registers are stored as one 16-bit mask (0x0000 or 0xFFFF) per lane;
+// CFC2/CTC2 see them as one bit per lane, with lane N in bit N.
+fn pack_lanes(reg: &VectorReg) -> u8 {
+    let mut res = 0u8;
+    for i in 0..8 {
+        res |= ((reg.lane(i) & 1) << i) as u8;
+    }
+    res
+}
+
+fn unpack_lanes(bits: u8, reg: &mut VectorReg) {
+    for i in 0..8 {
+        reg.setlane(i, if (bits >> i) & 1 != 0 { 0xFFFF } else { 0 });
+    }
+}
+
+// Pack a pair of flag registers (VCO, VCC) in the CFC2 format: lo in bits
+// 0..7, hi in bits 8..15.
+fn pack_flags(lo: &VectorReg, hi: &VectorReg) -> u16 {
+    pack_lanes(lo) as u16 | (pack_lanes(hi) as u16) << 8
+}
+
+fn unpack_flags(flags: u16, lo: &mut VectorReg, hi: &mut VectorReg) {
+    unpack_lanes(flags as u8, lo);
+    unpack_lanes((flags >> 8) as u8, hi);
+}
+
+// Apply the element specifier of a computational opcode to a register:
+//   * 0-1: the whole vector
+//   * 2-3: lanes of each pair (0q, 1q)
+//   * 4-7: lanes of each quad (0h-3h)
+//   * 8-15: a single lane broadcast to all lanes (0-7)
+// The element is a 4-bit field, so the last arm covers all remaining values.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn velem(vt: &VectorReg, e: usize) -> __m128i {
+    match e {
+        0..=1 => vt.m128(),
+        2 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b11_11_01_01), 0b11_11_01_01),
+        3 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b10_10_00_00), 0b10_10_00_00),
+        4 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b11_11_11_11), 0b11_11_11_11),
+        5 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b10_10_10_10), 0b10_10_10_10),
+        6 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b01_01_01_01), 0b01_01_01_01),
+        7 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b00_00_00_00), 0b00_00_00_00),
+        _ => _mm_set1_epi16(vt.lane(e & 7) as i16),
     }
 }
 
+// Scalar version of velem: return the lane of the register that the element
+// specifier moves into lane idx.
+#[inline]
+fn velem_lane(e: usize, idx: usize) -> usize {
+    match e {
+        0..=1 => idx,
+        2..=3 => (idx & !1) | (e & 1),
+        4..=7 => (idx & !3) | (e & 3),
+        _ => e & 7,
+    }
+}
+
+// Operands of a computational opcode (see Cop2Instr::Compute).
 struct Vectorop<'a> {
-    op: u32,
+    opcode: u32,
+    func: u32,
+    e: usize,
+    rs: usize,
+    rt: usize,
+    rd: usize,
     ctx: &'a mut SpCop2Context,
     spv: &'a mut SpCop2,
 }
 
 impl<'a> Vectorop<'a> {
     fn func(&self) -> u32 {
-        self.op & 0x3F
+        self.func
     }
     fn e(&self) -> usize {
-        ((self.op >> 21) & 0xF) as usize
+        self.e
     }
     fn rs(&self) -> usize {
-        ((self.op >> 11) & 0x1F) as usize
+        self.rs
     }
     fn rt(&self) -> usize {
-        ((self.op >> 16) & 0x1F) as usize
+        self.rt
     }
     fn rd(&self) -> usize {
-        ((self.op >> 6) & 0x1F) as usize
+        self.rd
+    }
+    // Destination element of the single-lane opcodes (VMOV and the reciprocal
+    // ones): they encode it in the low bits of the vs field, and write only
+    // that lane of vd.
+    fn de(&self) -> usize {
+        self.rs & 7
     }
+    // VS operand of a computational opcode. The element never applies to VS,
+    // not even in the compare and clip ops that select between VS and VT
+    // lanes: each VS lane is compared with the (broadcast) VT lane.
     fn vs(&self) -> __m128i {
         self.ctx.vregs[self.rs()].m128()
     }
-    fn vt(&self) -> __m128i {
-        self.ctx.vregs[self.rt()].m128()
-    }
+    // VT operand of a computational opcode, with the element broadcast
+    // applied. All computational opcodes must access VT through this.
     unsafe fn vte(&self) -> __m128i {
-        let vt = self.ctx.vregs[self.rt()];
-        let e = self.e();
-        match e {
-            0..=1 => vt.m128(),
-            2 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b11_11_01_01), 0b11_11_01_01),
-            3 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b10_10_00_00), 0b10_10_00_00),
-            4 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b11_11_11_11), 0b11_11_11_11),
-            5 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b10_10_10_10), 0b10_10_10_10),
-            6 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b01_01_01_01), 0b01_01_01_01),
-            7 => _mm_shufflehi_epi16(_mm_shufflelo_epi16(vt.m128(), 0b00_00_00_00), 0b00_00_00_00),
-            8..=15 => _mm_set1_epi16(vt.lane(e - 8) as i16),
-            _ => unreachable!(),
-        }
+        velem(&self.ctx.vregs[self.rt()], self.e())
     }
     fn setvd(&mut self, val: __m128i) {
         self.ctx.vregs[self.rd()].setm128(val);
     }
     fn accum(&self, idx: usize) -> __m128i {
-        unsafe { _mm_loadu_si128(self.ctx.accum[idx].0.as_ptr() as *const _) }
+        self.ctx.accum[idx].m128()
     }
     fn setaccum(&mut self, idx: usize, val: __m128i) {
-        unsafe { _mm_store_si128(self.ctx.accum[idx].0.as_ptr() as *mut _, val) }
+        self.ctx.accum[idx].setm128(val);
+    }
+
+    // Compute a multiply opcode with its scalar version, which works on the
+    // accumulator as a plain i64. Returns the lanes of VD and the lanes of
+    // the accumulator, without writing them.
+    fn scalar_mul(&self, mul: fn(u16, u16, i64) -> (u16, i64)) -> ([u16; 8], [i64; 8]) {
+        let (vs, vt) = (self.vs_lanes(), self.vte_lanes());
+        let mut res = [0u16; 8];
+        let mut acc = [0i64; 8];
+        for (i, (r, a)) in res.iter_mut().zip(acc.iter_mut()).enumerate() {
+            let (val, newacc) = mul(vs[i], vt[i], self.ctx.accumulator(i));
+            *r = val;
+            *a = newacc;
+        }
+        (res, acc)
+    }
+
+    // Debug check run after each SIMD multiply: VD and the accumulator must
+    // match the result of the scalar version, computed before the opcode.
+    fn check_mul(&self, expected: ([u16; 8], [i64; 8])) {
+        let (res, acc) = expected;
+        for (i, (&res, &acc)) in res.iter().zip(acc.iter()).enumerate() {
+            let vd = self.ctx.vregs[self.rd()].lane(i);
+            let got = self.ctx.accumulator(i);
+            debug_assert!(
+                vd == res && got == acc,
+                "multiply mismatch: op={:08x} lane={} vd={:04x} (expected {:04x}) \
+                 acc={:#x} (expected {:#x})",
+                self.opcode,
+                i,
+                vd,
+                res,
+                got,
+                acc
+            );
+        }
     }
     fn carry(&self) -> __m128i {
         self.ctx.vco_carry.m128()
@@ -247,20 +954,34 @@ impl<'a> Vectorop<'a> {
     fn setvd_lane(&mut self, idx: usize, val: u16) {
         self.ctx.vregs[self.rd()].setlane(idx, val);
     }
-    fn setvs_lane(&mut self, idx: usize, val: u16) {
-        self.ctx.vregs[self.rs()].setlane(idx, val);
-    }
 
-    fn vs_byte(&mut self, idx: usize) -> u8 {
-        self.ctx.vregs[self.rs()].byte(idx)
+    // Lane-wise accessors for the scalar implementation. VD may be the same
+    // register as VS or VT, so all lanes are read before any is written.
+    fn vs_lanes(&self) -> [u16; 8] {
+        self.ctx.vregs[self.rs()].lanes()
     }
-    fn setvs_byte(&mut self, idx: usize, val: u8) {
-        self.ctx.vregs[self.rs()].setbyte(idx, val);
+    fn vte_lanes(&self) -> [u16; 8] {
+        let mut lanes = [0u16; 8];
+        for (idx, lane) in lanes.iter_mut().enumerate() {
+            *lane = self.vt_lane(velem_lane(self.e(), idx));
+        }
+        lanes
+    }
+    fn setvd_lanes(&mut self, val: [u16; 8]) {
+        self.ctx.vregs[self.rd()].setlanes(val);
+    }
+    fn setaccum_lanes(&mut self, idx: usize, val: [u16; 8]) {
+        self.ctx.accum[idx].setlanes(val);
     }
 }
 
 macro_rules! op_vmul {
     ($op:expr, $name:ident) => {{
+        let expected = if cfg!(debug_assertions) {
+            Some($op.scalar_mul(scalar::$name))
+        } else {
+            None
+        };
         let (res, acc_lo, acc_md, acc_hi) = vmul::$name(
             $op.vs(),
             $op.vte(),
@@ -272,453 +993,867 @@ macro_rules! op_vmul {
         $op.setaccum(0, acc_lo);
         $op.setaccum(1, acc_md);
         $op.setaccum(2, acc_hi);
+        if let Some(expected) = expected {
+            $op.check_mul(expected);
+        }
     }};
 }
 
-impl SpCop2 {
-    #[target_feature(enable = "sse2")]
-    unsafe fn uop(&mut self, cpu: &mut CpuContext, op: u32, t: &dbg::Tracer) -> dbg::Result<()> {
-        let mut op = Vectorop {
-            op,
-            ctx: unsafe { self.ctx.as_mut() },
-            spv: self,
-        };
-        let vzero = _mm_setzero_si128();
-        #[allow(overflowing_literals)]
-        let vones = _mm_set1_epi16(0xFFFF);
+impl SpCop2 {
+    /// Execute a straight-line block of COP2 opcodes (loads and stores are not
+    /// supported). This is equivalent to calling op() on each of them, but the
+    /// SSE code path is entered only once for the whole block.
+    pub fn run_block(
+        &mut self,
+        cpu: &mut CpuContext,
+        ops: &[u32],
+        t: &dbg::Tracer,
+    ) -> dbg::Result<()> {
+        if !self.simd {
+            for &op in ops {
+                self.sop(cpu, op, t)?;
+            }
+            return Ok(());
+        }
+        unsafe { self.ublock(cpu, ops, t) }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn ublock(
+        &mut self,
+        cpu: &mut CpuContext,
+        ops: &[u32],
+        t: &dbg::Tracer,
+    ) -> dbg::Result<()> {
+        for &op in ops {
+            self.uop(cpu, op, t)?;
+        }
+        Ok(())
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn uop(&mut self, cpu: &mut CpuContext, op: u32, t: &dbg::Tracer) -> dbg::Result<()> {
+        match Cop2Instr::decode(op) {
+            Cop2Instr::Compute {
+                func,
+                vd,
+                vs,
+                vt,
+                e,
+            } => {
+                if let Some(ref mut stats) = self.stats {
+                    stats.count_compute(func);
+                }
+                let op = Vectorop {
+                    opcode: op,
+                    func,
+                    e,
+                    rs: vs,
+                    rt: vt,
+                    rd: vd,
+                    ctx: unsafe { self.ctx.as_mut() },
+                    spv: self,
+                };
+                SpCop2::uop_compute(op, cpu, t)
+            }
+            instr => self.move_op(cpu, op, instr, t),
+        }
+    }
+
+    // Execute a non-computational opcode (moves between the vector unit and
+    // the scalar CPU). These don't need SIMD, so both implementations share
+    // them.
+    fn move_op(
+        &mut self,
+        cpu: &mut CpuContext,
+        op: u32,
+        instr: Cop2Instr,
+        t: &dbg::Tracer,
+    ) -> dbg::Result<()> {
+        match instr {
+            Cop2Instr::Mfc2 { rt, vs, e } => {
+                let vs = &self.ctx.vregs[vs];
+                let val = ((vs.byte(e) as u16) << 8) | vs.byte((e + 1) & 15) as u16;
+                cpu.set_gpr(rt, val.sx64());
+                Ok(())
+            }
+            Cop2Instr::Mtc2 { rt, vs, e } => {
+                let val = cpu.gpr(rt);
+                let vs = &mut self.ctx.vregs[vs];
+                vs.setbyte(e, (val >> 8) as u8);
+                if e != 15 {
+                    vs.setbyte(e + 1, val as u8);
+                }
+                Ok(())
+            }
+            Cop2Instr::Cfc2 { rt, rd } => {
+                match rd {
+                    0 => cpu.set_gpr(rt, self.ctx.vco().sx64()),
+                    1 => cpu.set_gpr(rt, self.ctx.vcc().sx64()),
+                    2 => cpu.set_gpr(rt, self.ctx.vce() as u64),
+                    _ => unreachable!(),
+                }
+                Ok(())
+            }
+            Cop2Instr::Ctc2 { rt, rd } => {
+                match rd {
+                    0 => self.ctx.set_vco(cpu.gpr(rt) as u32),
+                    1 => self.ctx.set_vcc(cpu.gpr(rt) as u32),
+                    2 => self.ctx.set_vce(cpu.gpr(rt) as u32),
+                    _ => unreachable!(),
+                }
+                Ok(())
+            }
+            _ => {
+                error!(self.logger, "unimplemented COP2 non-VU opcode={}", op.hex());
+                self.halt(cpu, CopError::UnimplementedOp(op));
+                t.break_here("unimplemented COP2 non-VU opcode")
+            }
+        }
+    }
+
+    // Scalar implementation of uop, for hosts without SSE4.1.
+    fn sop(&mut self, cpu: &mut CpuContext, op: u32, t: &dbg::Tracer) -> dbg::Result<()> {
+        match Cop2Instr::decode(op) {
+            Cop2Instr::Compute {
+                func,
+                vd,
+                vs,
+                vt,
+                e,
+            } => {
+                if let Some(ref mut stats) = self.stats {
+                    stats.count_compute(func);
+                }
+                let op = Vectorop {
+                    opcode: op,
+                    func,
+                    e,
+                    rs: vs,
+                    rt: vt,
+                    rd: vd,
+                    ctx: unsafe { self.ctx.as_mut() },
+                    spv: self,
+                };
+                SpCop2::sop_compute(op, cpu, t)
+            }
+            instr => self.move_op(cpu, op, instr, t),
+        }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn uop_compute(
+        mut op: Vectorop,
+        cpu: &mut CpuContext,
+        t: &dbg::Tracer,
+    ) -> dbg::Result<()> {
+        let vzero = _mm_setzero_si128();
+        #[allow(overflowing_literals)]
+        let vones = _mm_set1_epi16(0xFFFF);
+
+        match op.func() {
+            0x00 => op_vmul!(op, vmulf), // VMULF
+            0x01 => op_vmul!(op, vmulu), // VMULU
+            0x04 => op_vmul!(op, vmudl), // VMUDL
+            0x05 => op_vmul!(op, vmudm), // VMUDM
+            0x06 => op_vmul!(op, vmudn), // VMUDN
+            0x07 => op_vmul!(op, vmudh), // VMUDH
+            0x08 => op_vmul!(op, vmacf), // VMACF
+            0x09 => op_vmul!(op, vmacu), // VMACU
+            0x0C => op_vmul!(op, vmadl), // VMADL
+            0x0D => op_vmul!(op, vmadm), // VMADM
+            0x0E => op_vmul!(op, vmadn), // VMADN
+            0x0F => op_vmul!(op, vmadh), // VMADH
+            0x10 => {
+                // VADD
+                let vs = op.vs();
+                let vt = op.vte();
+                let carry = op.carry();
+
+                // We need to compute Saturate(VS+VT+CARRY).
+                // Add the carry to the minimum value, as we need to
+                // saturate the final result and not only intermediate
+                // results:
+                //     0x8000 + 0x8000 + 0x1 must be 0x8000, not 0x8001
+                // NOTE: the carry register is either 0x0 or 0xFFFF (-1), so add/sub
+                // operations are reversed.
+                let min = smin16(vs, vt);
+                let max = smax16(vs, vt);
+                op.setvd(_mm_adds_epi16(_mm_subs_epi16(min, carry), max));
+                op.setaccum(0, _mm_sub_epi16(_mm_add_epi16(vs, vt), carry));
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x11 => {
+                // VSUB
+                let vs = op.vs();
+                let vt = op.vte();
+                let carry = op.carry();
+
+                // We need to compute Saturate(VS-VT-CARRY).
+                // The min/max trick of VADD doesn't apply, as subtraction is
+                // not commutative. Instead, compute VS-(VT+CARRY), with
+                // VT+CARRY saturated: it only overflows for VT=0x7FFF, where
+                // it becomes 0x7FFF instead of 0x8000, so subtract the missing
+                // 1 afterwards (mask is -1 in that case). Saturating at each
+                // step is then exact: VS-0x7FFF-1 only saturates at the low
+                // end, which the second step can't bring back in range.
+                // Eg: 0x8000-0x7FFF-1 = 0x8000, and 0x7FFF-0x7FFF-1 = -1
+                // (adding the borrow to VT first would wrap it to 0x8000).
+                // NOTE: the carry register is either 0x0 or 0xFFFF (-1), so add/sub
+                // operations are reversed.
+                let diff = _mm_sub_epi16(vt, carry);
+                let sdiff = _mm_subs_epi16(vt, carry);
+                let mask = _mm_cmpgt_epi16(sdiff, diff);
+
+                op.setvd(_mm_adds_epi16(_mm_subs_epi16(vs, sdiff), mask));
+                op.setaccum(0, _mm_sub_epi16(vs, diff));
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x13 => {
+                // VABS
+                let vs = op.vs();
+                let vt = op.vte();
+                let res = _mm_sign_epi16(vt, vs);
+                op.setaccum(0, res);
+                op.setvd(res);
+            }
+            0x14 => {
+                // VADDC
+                let vs = op.vs();
+                let vt = op.vte();
+                let res = _mm_add_epi16(vs, vt);
+                op.setvd(res);
+                op.setaccum(0, res);
+                op.setne(vzero);
+                // Unsigned overflow: the result wrapped below VS.
+                op.setcarry(_mm_xor_si128(vones, _mm_cmpeq_epi16(umin16(res, vs), vs)));
+            }
+            0x15 => {
+                // VSUBC
+                let vs = op.vs();
+                let vt = op.vte();
+                let res = _mm_sub_epi16(vs, vt);
+                op.setvd(res);
+                op.setaccum(0, res);
+
+                // Unsigned borrow: VT is greater than VS.
+                op.setcarry(_mm_xor_si128(vones, _mm_cmpeq_epi16(umax16(vs, vt), vs)));
+                op.setne(_mm_xor_si128(_mm_cmpeq_epi16(vs, vt), vones));
+            }
+            0x17 => {
+                // VSUBB -- undocumented?
+                let vs = op.vs();
+                let vt = op.vte();
+                let res = _mm_add_epi16(vs, vt);
+                op.setvd(vzero);
+                op.setaccum(0, res);
+            }
+            0x19 => {
+                // VSUCB -- undocumented?
+                let vs = op.vs();
+                let vt = op.vte();
+                let res = _mm_add_epi16(vs, vt);
+                op.setvd(vzero);
+                op.setaccum(0, res);
+            }
+            0x1D => {
+                // VSAR
+                // Only e=8..10 select an accumulator slice; any other
+                // element reads as zero on hardware.
+                let e = op.e();
+                match e {
+                    8..=10 => {
+                        // e=8 selects HI, e=9 MD and e=10 LO.
+                        // NOTE: VSAR is not able to write the accumulator,
+                        // contrary to what documentation says.
+                        let sar = op.accum(2 - (e - 8));
+                        let vs = op.vs();
+                        op.setvd(sar);
+                        if !op.spv.strict_hw {
+                            op.setaccum(2 - (e - 8), vs);
+                        }
+                    }
+                    _ => op.setvd(vzero),
+                }
+            }
+            0x20 => {
+                // VLT
+                // On equal lanes, VS is selected if both carry and NE are set.
+                let tie = _mm_and_si128(op.ne(), op.carry());
+                let (res, vcc) = vmin(op.vs(), op.vte(), tie);
+                op.setaccum(0, res);
+                op.setvd(res);
+                op.setvccnormal(vcc);
+                op.setvccclip(vzero);
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x21 => {
+                // VEQ
+                let vs = op.vs();
+                let vt = op.vte();
+                let vcc = _mm_andnot_si128(op.ne(), _mm_cmpeq_epi16(vs, vt));
+                let res = vselect(vcc, vs, vt);
+
+                op.setvccnormal(vcc);
+                op.setvccclip(vzero);
+                op.setaccum(0, res);
+                op.setvd(res);
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x22 => {
+                // VNE
+                let vs = op.vs();
+                let vt = op.vte();
+
+                let vcc = _mm_or_si128(
+                    _mm_or_si128(_mm_cmpgt_epi16(vt, vs), _mm_cmpgt_epi16(vs, vt)),
+                    _mm_and_si128(op.ne(), _mm_cmpeq_epi16(vs, vt)),
+                );
+                let res = vselect(vcc, vs, vt);
+
+                op.setvccnormal(vcc);
+                op.setvccclip(vzero);
+                op.setaccum(0, res);
+                op.setvd(res);
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x23 => {
+                // VGE
+                // On equal lanes, VS is selected unless both carry and NE are set.
+                let tie = _mm_xor_si128(_mm_and_si128(op.carry(), op.ne()), vones);
+                let (res, vcc) = vmax(op.vs(), op.vte(), tie);
+                op.setvccnormal(vcc);
+                op.setvccclip(vzero);
+                op.setaccum(0, res);
+                op.setvd(res);
+                op.setcarry(vzero);
+                op.setne(vzero);
+            }
+            0x24 => {
+                // VCL
+                let (res, carry, ne, le, ge, vce) = vclip::vcl(
+                    op.vs(),
+                    op.vte(),
+                    op.carry(),
+                    op.ne(),
+                    op.vccnormal(),
+                    op.vccclip(),
+                    op.vce(),
+                );
+                op.setvd(res);
+                op.setaccum(0, res);
+                op.setvccnormal(le);
+                op.setvccclip(ge);
+                op.setvce(vce); // always zero
+                op.setcarry(carry); // always zero
+                op.setne(ne); // always zero
+            }
+            0x25 => {
+                // VCH
+                let (res, carry, ne, le, ge, vce) = vclip::vch(op.vs(), op.vte());
+                op.setvd(res);
+                op.setaccum(0, res);
+                op.setvccnormal(le);
+                op.setvccclip(ge);
+                op.setvce(vce);
+                op.setcarry(carry);
+                op.setne(ne);
+            }
+            0x26 => {
+                // VCR
+                let (res, carry, ne, le, ge, vce) = vclip::vcr(op.vs(), op.vte());
+                op.setvd(res);
+                op.setaccum(0, res);
+                op.setvccnormal(le);
+                op.setvccclip(ge);
+                op.setvce(vce); // always zero
+                op.setcarry(carry); // always zero
+                op.setne(ne); // always zero
+            }
+            0x27 => {
+                // VMRG
+                let vs = op.vs();
+                let vt = op.vte();
+                let vcc = op.vccnormal();
+
+                let res = vselect(vcc, vs, vt);
+                op.setvd(res);
+                op.setaccum(0, res);
+                op.setne(vzero);
+                op.setcarry(vzero);
+            }
+            0x28 => {
+                // VAND
+                let res = _mm_and_si128(op.vs(), op.vte());
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x29 => {
+                // VNAND
+                let res = _mm_xor_si128(_mm_and_si128(op.vs(), op.vte()), vones);
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x2A if op.rs() == op.rt() && op.e() == 0 => {
+                // VOR vd,vs,vs[e0]: the idiom used by microcode to move a
+                // register. Copy it directly, but still write ACCUM_LO.
+                let res = op.vs();
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x2A => {
+                // VOR
+                let res = _mm_or_si128(op.vs(), op.vte());
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x2B => {
+                // VNOR
+                let res = _mm_xor_si128(_mm_or_si128(op.vs(), op.vte()), vones);
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x2C => {
+                // VXOR
+                let res = _mm_xor_si128(op.vs(), op.vte());
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x2D => {
+                // VNXOR
+                let res = _mm_xor_si128(_mm_xor_si128(op.vs(), op.vte()), vones);
+                op.setvd(res);
+                op.setaccum(0, res);
+            }
+            0x30..=0x36 => SpCop2::single_lane_op(&mut op),
+            0x37 => {} // VNOP
+            0x3f => {} // VNULL
+
+            _ => return SpCop2::unimplemented_compute(op, cpu, t),
+        }
+        Ok(())
+    }
 
-        if op.op & (1 << 25) != 0 {
-            match op.func() {
-                0x00 => op_vmul!(op, vmulf), // VMULF
-                0x01 => op_vmul!(op, vmulu), // VMULU
-                0x04 => op_vmul!(op, vmudl), // VMUDL
-                0x05 => op_vmul!(op, vmudm), // VMUDM
-                0x06 => op_vmul!(op, vmudn), // VMUDN
-                0x07 => op_vmul!(op, vmudh), // VMUDH
-                0x08 => op_vmul!(op, vmacf), // VMACF
-                0x09 => op_vmul!(op, vmacu), // VMACU
-                0x0C => op_vmul!(op, vmadl), // VMADL
-                0x0D => op_vmul!(op, vmadm), // VMADM
-                0x0E => op_vmul!(op, vmadn), // VMADN
-                0x0F => op_vmul!(op, vmadh), // VMADH
-                0x10 => {
-                    // VADD
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let carry = op.carry();
-
-                    // We need to compute Saturate(VS+VT+CARRY).
-                    // Add the carry to the minimum value, as we need to
-                    // saturate the final result and not only intermediate
-                    // results:
-                    //     0x8000 + 0x8000 + 0x1 must be 0x8000, not 0x8001
-                    // NOTE: the carry register is either 0x0 or 0xFFFF (-1), so add/sub
-                    // operations are reversed.
-                    let min = _mm_min_epi16(vs, vt);
-                    let max = _mm_max_epi16(vs, vt);
-                    op.setvd(_mm_adds_epi16(_mm_subs_epi16(min, carry), max));
-                    op.setaccum(0, _mm_sub_epi16(_mm_add_epi16(vs, vt), carry));
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x11 => {
-                    // VSUB
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let carry = op.carry();
-
-                    // We need to compute Saturate(VS-VT-CARRY).
-                    // Compute VS-(VT+CARRY), and fix the result if there
-                    // was an overflow.
-                    // NOTE: the carry register is either 0x0 or 0xFFFF (-1), so add/sub
-                    // operations are reversed.
-                    let diff = _mm_sub_epi16(vt, carry);
-                    let sdiff = _mm_subs_epi16(vt, carry);
-                    let mask = _mm_cmpgt_epi16(sdiff, diff);
-
-                    op.setvd(_mm_adds_epi16(_mm_subs_epi16(vs, sdiff), mask));
-                    op.setaccum(0, _mm_sub_epi16(vs, diff));
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x13 => {
-                    // VABS
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let res = _mm_sign_epi16(vt, vs);
-                    op.setaccum(0, res);
-                    op.setvd(res);
+    // Scalar implementation of uop_compute, for hosts without SSE4.1: each
+    // opcode computes the same results, one lane at a time.
+    fn sop_compute(mut op: Vectorop, cpu: &mut CpuContext, t: &dbg::Tracer) -> dbg::Result<()> {
+        let mask = |b: bool| if b { 0xFFFFu16 } else { 0 };
+        let vs = op.vs_lanes();
+        let vt = op.vte_lanes();
+        let carry = op.ctx.vco_carry.lanes();
+        let ne = op.ctx.vco_ne.lanes();
+        let mut res = [0u16; 8];
+
+        match op.func() {
+            0x00..=0x0F => {
+                let mul: fn(u16, u16, i64) -> (u16, i64) = match op.func() {
+                    0x00 => scalar::vmulf,
+                    0x01 => scalar::vmulu,
+                    0x04 => scalar::vmudl,
+                    0x05 => scalar::vmudm,
+                    0x06 => scalar::vmudn,
+                    0x07 => scalar::vmudh,
+                    0x08 => scalar::vmacf,
+                    0x09 => scalar::vmacu,
+                    0x0C => scalar::vmadl,
+                    0x0D => scalar::vmadm,
+                    0x0E => scalar::vmadn,
+                    0x0F => scalar::vmadh,
+                    _ => return SpCop2::unimplemented_compute(op, cpu, t),
+                };
+                let (res, acc) = op.scalar_mul(mul);
+                for (i, &val) in acc.iter().enumerate() {
+                    op.ctx.set_accumulator(i, val);
                 }
-                0x14 => {
-                    // VADDC
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let res = _mm_add_epi16(vs, vt);
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                    op.setne(vzero);
-                    op.setcarry(_mm_xor_si128(
-                        vones,
-                        _mm_cmpeq_epi16(res, _mm_adds_epu16(vs, vt)),
-                    ));
+                op.setvd_lanes(res);
+            }
+            0x10 | 0x11 => {
+                // VADD, VSUB: see uop_compute for the saturation with carry
+                // (the carry lanes are 0 or -1).
+                let mut acc = [0u16; 8];
+                for (i, (r, w)) in res.iter_mut().zip(acc.iter_mut()).enumerate() {
+                    let (a, b, c) = (vs[i] as i16, vt[i] as i16, carry[i] as i16);
+                    let (val, wrapped) = if op.func() == 0x10 {
+                        let sum = a.min(b).saturating_sub(c).saturating_add(a.max(b));
+                        (sum, a.wrapping_add(b).wrapping_sub(c))
+                    } else {
+                        let diff = b.wrapping_sub(c);
+                        let sdiff = b.saturating_sub(c);
+                        let borrow = if sdiff > diff { -1 } else { 0 };
+                        (
+                            a.saturating_sub(sdiff).saturating_add(borrow),
+                            a.wrapping_sub(diff),
+                        )
+                    };
+                    *r = val as u16;
+                    *w = wrapped as u16;
                 }
-                0x15 => {
-                    // VSUBC
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let res = _mm_sub_epi16(vs, vt);
-                    op.setvd(res);
-                    op.setaccum(0, res);
-
-                    #[allow(overflowing_literals)]
-                    let mask = _mm_set1_epi16(0x8000);
-                    op.setcarry(_mm_cmpgt_epi16(
-                        _mm_xor_si128(mask, vt),
-                        _mm_xor_si128(mask, vs),
-                    ));
-                    op.setne(_mm_xor_si128(_mm_cmpeq_epi16(vs, vt), vones));
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, acc);
+                op.ctx.vco_carry.setlanes([0; 8]);
+                op.ctx.vco_ne.setlanes([0; 8]);
+            }
+            0x13 => {
+                // VABS
+                for (i, r) in res.iter_mut().enumerate() {
+                    *r = match (vs[i] as i16).signum() {
+                        0 => 0,
+                        -1 => vt[i].wrapping_neg(),
+                        _ => vt[i],
+                    };
                 }
-                0x17 => {
-                    // VSUBB -- undocumented?
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let res = _mm_add_epi16(vs, vt);
-                    op.setvd(vzero);
-                    op.setaccum(0, res);
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, res);
+            }
+            0x14 | 0x15 => {
+                // VADDC, VSUBC
+                let mut newcarry = [0u16; 8];
+                let mut newne = [0u16; 8];
+                for (i, r) in res.iter_mut().enumerate() {
+                    let (val, c, n) = if op.func() == 0x14 {
+                        let (sum, c) = vs[i].overflowing_add(vt[i]);
+                        (sum, c, false)
+                    } else {
+                        let (diff, c) = vs[i].overflowing_sub(vt[i]);
+                        (diff, c, vs[i] != vt[i])
+                    };
+                    *r = val;
+                    newcarry[i] = mask(c);
+                    newne[i] = mask(n);
                 }
-                0x19 => {
-                    // VSUCB -- undocumented?
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let res = _mm_add_epi16(vs, vt);
-                    op.setvd(vzero);
-                    op.setaccum(0, res);
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, res);
+                op.ctx.vco_carry.setlanes(newcarry);
+                op.ctx.vco_ne.setlanes(newne);
+            }
+            0x17 | 0x19 => {
+                // VSUBB, VSUCB
+                for (i, r) in res.iter_mut().enumerate() {
+                    *r = vs[i].wrapping_add(vt[i]);
                 }
-                0x1D => {
-                    // VSAR
-                    let e = op.e();
-                    match e {
-                        0..=2 => {
-                            op.setvd(vzero);
-                        }
-                        8..=10 => {
-                            // NOTE: VSAR is not able to write the accumulator,
-                            // contrary to what documentation says.
-                            let sar = op.accum(2 - (e - 8));
-                            op.setvd(sar);
+                op.setvd_lanes([0; 8]);
+                op.setaccum_lanes(0, res);
+            }
+            0x1D => {
+                // VSAR
+                match op.e() {
+                    8..=10 => {
+                        let idx = 2 - (op.e() - 8);
+                        op.setvd_lanes(op.ctx.accum[idx].lanes());
+                        if !op.spv.strict_hw {
+                            op.setaccum_lanes(idx, vs);
                         }
-                        _ => unimplemented!(),
                     }
+                    _ => op.setvd_lanes([0; 8]),
                 }
-                0x20 => {
-                    // VLT
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let vcc = _mm_or_si128(
-                        _mm_cmpgt_epi16(vt, vs),
-                        _mm_and_si128(op.ne(), _mm_and_si128(op.carry(), _mm_cmpeq_epi16(vs, vt))),
-                    );
-                    let res = _mm_or_si128(_mm_and_si128(vcc, vs), _mm_andnot_si128(vcc, vt));
-                    op.setaccum(0, res);
-                    op.setvd(res);
-                    op.setvccnormal(vcc);
-                    op.setvccclip(vzero);
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x21 => {
-                    // VEQ
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let vcc = _mm_andnot_si128(op.ne(), _mm_cmpeq_epi16(vs, vt));
-                    let res = _mm_or_si128(_mm_and_si128(vcc, vs), _mm_andnot_si128(vcc, vt));
-
-                    op.setvccnormal(vcc);
-                    op.setvccclip(vzero);
-                    op.setaccum(0, res);
-                    op.setvd(res);
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x22 => {
-                    // VNE
-                    let vs = op.vs();
-                    let vt = op.vte();
-
-                    let vcc = _mm_or_si128(
-                        _mm_or_si128(_mm_cmpgt_epi16(vt, vs), _mm_cmpgt_epi16(vs, vt)),
-                        _mm_and_si128(op.ne(), _mm_cmpeq_epi16(vs, vt)),
-                    );
-                    let res =
-                        _mm_or_si128(_mm_and_si128(vcc, op.vs()), _mm_andnot_si128(vcc, op.vt()));
-
-                    op.setvccnormal(vcc);
-                    op.setvccclip(vzero);
-                    op.setaccum(0, res);
-                    op.setvd(res);
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x23 => {
-                    // VGE
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let vcc = _mm_or_si128(
-                        _mm_cmpgt_epi16(vs, vt),
-                        _mm_andnot_si128(
-                            _mm_and_si128(op.carry(), op.ne()),
-                            _mm_cmpeq_epi16(vs, vt),
-                        ),
-                    );
-                    let res = _mm_or_si128(_mm_and_si128(vcc, vs), _mm_andnot_si128(vcc, vt));
-                    op.setvccnormal(vcc);
-                    op.setvccclip(vzero);
-                    op.setaccum(0, res);
-                    op.setvd(res);
-                    op.setcarry(vzero);
-                    op.setne(vzero);
-                }
-                0x24 => {
-                    // VCL
-                    let (res, carry, ne, le, ge, vce) = vclip::vcl(
-                        op.vs(),
-                        op.vte(),
-                        op.carry(),
-                        op.ne(),
-                        op.vccnormal(),
-                        op.vccclip(),
-                        op.vce(),
-                    );
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                    op.setvccnormal(le);
-                    op.setvccclip(ge);
-                    op.setvce(vce); // always zero
-                    op.setcarry(carry); // always zero
-                    op.setne(ne); // always zero
-                }
-                0x25 => {
-                    // VCH
-                    let (res, carry, ne, le, ge, vce) = vclip::vch(op.vs(), op.vte());
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                    op.setvccnormal(le);
-                    op.setvccclip(ge);
-                    op.setvce(vce);
-                    op.setcarry(carry);
-                    op.setne(ne);
-                }
-                0x26 => {
-                    // VCR
-                    let (res, carry, ne, le, ge, vce) = vclip::vcr(op.vs(), op.vte());
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                    op.setvccnormal(le);
-                    op.setvccclip(ge);
-                    op.setvce(vce); // always zero
-                    op.setcarry(carry); // always zero
-                    op.setne(ne); // always zero
-                }
-                0x27 => {
-                    // VMRG
-                    let vs = op.vs();
-                    let vt = op.vte();
-                    let vcc = op.vccnormal();
-
-                    let res = _mm_or_si128(_mm_and_si128(vcc, vs), _mm_andnot_si128(vcc, vt));
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                    op.setne(vzero);
-                    op.setcarry(vzero);
-                }
-                0x28 => {
-                    // VAND
-                    let res = _mm_and_si128(op.vs(), op.vte());
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x29 => {
-                    // VNAND
-                    let res = _mm_xor_si128(_mm_and_si128(op.vs(), op.vte()), vones);
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x2A => {
-                    // VOR
-                    let res = _mm_or_si128(op.vs(), op.vte());
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x2B => {
-                    // VNOR
-                    let res = _mm_xor_si128(_mm_or_si128(op.vs(), op.vte()), vones);
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x2C => {
-                    // VXOR
-                    let res = _mm_xor_si128(op.vs(), op.vte());
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x2D => {
-                    // VNXOR
-                    let res = _mm_xor_si128(_mm_xor_si128(op.vs(), op.vte()), vones);
-                    op.setvd(res);
-                    op.setaccum(0, res);
-                }
-                0x30 => {
-                    // VRCP
-                    let x = op.vt_lane(op.e() & 7);
-                    let res = vrcp::vrcp(x.sx32());
-                    op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_out = res;
-                }
-                0x31 => {
-                    // VRCPL
-                    let x = op.vt_lane(op.e() & 7);
-                    let res = match op.ctx.div_in {
-                        Some(div_in) => vrcp::vrcp((x as u32) | div_in),
-                        None => vrcp::vrcp(x.sx32()),
+            }
+            0x20..=0x23 | 0x27 => {
+                // VLT, VEQ, VNE, VGE, VMRG: select between VS and VT lanes.
+                let vcc_normal = op.ctx.vcc_normal.lanes();
+                let mut vcc = [0u16; 8];
+                for (i, r) in res.iter_mut().enumerate() {
+                    let (a, b) = (vs[i] as i16, vt[i] as i16);
+                    let eq = mask(a == b);
+                    vcc[i] = match op.func() {
+                        0x20 => mask(a < b) | (ne[i] & carry[i] & eq),
+                        0x21 => !ne[i] & eq,
+                        0x22 => mask(a != b) | (ne[i] & eq),
+                        0x23 => mask(a > b) | (!(carry[i] & ne[i]) & eq),
+                        _ => vcc_normal[i],
                     };
-                    op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_out = res;
-                    op.ctx.div_in = None;
+                    *r = (vcc[i] & vs[i]) | (!vcc[i] & vt[i]);
                 }
-                0x32 => {
-                    // VRCPH
-                    let x = op.vt_lane(op.e() & 7);
-                    op.setvd_lane(op.rs() & 7, (op.ctx.div_out >> 16) as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_in = Some((x as u32) << 16);
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, res);
+                if op.func() != 0x27 {
+                    op.ctx.vcc_normal.setlanes(vcc);
+                    op.ctx.vcc_clip.setlanes([0; 8]);
                 }
-                0x33 => {
-                    // VMOV
-                    let se = match op.e() {
-                        0..=1 => (op.e() & 0b000) | (op.rs() & 0b111),
-                        2..=3 => (op.e() & 0b001) | (op.rs() & 0b110),
-                        4..=7 => (op.e() & 0b011) | (op.rs() & 0b100),
-                        8..=15 => (op.e() & 0b111) | (op.rs() & 0b000),
-                        _ => unreachable!(),
+                op.ctx.vco_carry.setlanes([0; 8]);
+                op.ctx.vco_ne.setlanes([0; 8]);
+            }
+            0x24..=0x26 => {
+                // VCL, VCH, VCR
+                let le = op.ctx.vcc_normal.lanes();
+                let ge = op.ctx.vcc_clip.lanes();
+                let vce = op.ctx.vce.lanes();
+                let mut flags = [[0u16; 8]; 5];
+                for (i, r) in res.iter_mut().enumerate() {
+                    let (val, (c, n, l, g, e)) = match op.func() {
+                        0x24 => {
+                            let old = (
+                                carry[i] != 0,
+                                ne[i] != 0,
+                                le[i] != 0,
+                                ge[i] != 0,
+                                vce[i] != 0,
+                            );
+                            scalar::vcl(vs[i], vt[i], old)
+                        }
+                        0x25 => scalar::vch(vs[i], vt[i]),
+                        _ => scalar::vcr(vs[i], vt[i]),
                     };
-
-                    let res = op.vt_lane(se);
-                    op.setvd_lane(op.rs() & 7, res);
-                    // FIXME: update ACCUM with VMOV?
-                    op.setaccum(0, op.vt());
-                }
-                0x34 => {
-                    // VRSQ
-                    let x = op.vt_lane(op.e() & 7);
-                    let res = vrcp::vrsq(x.sx32());
-                    op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_out = res;
+                    *r = val;
+                    for (flag, &set) in flags.iter_mut().zip(&[c, n, l, g, e]) {
+                        flag[i] = mask(set);
+                    }
                 }
-                0x35 => {
-                    // VRSQL
-                    let x = op.vt_lane(op.e() & 7);
-                    let res = match op.ctx.div_in {
-                        Some(div_in) => vrcp::vrsq((x as u32) | div_in),
-                        None => vrcp::vrsq(x.sx32()),
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, res);
+                op.ctx.vco_carry.setlanes(flags[0]);
+                op.ctx.vco_ne.setlanes(flags[1]);
+                op.ctx.vcc_normal.setlanes(flags[2]);
+                op.ctx.vcc_clip.setlanes(flags[3]);
+                op.ctx.vce.setlanes(flags[4]);
+            }
+            0x28..=0x2D => {
+                // VAND, VNAND, VOR, VNOR, VXOR, VNXOR
+                for (i, r) in res.iter_mut().enumerate() {
+                    *r = match op.func() {
+                        0x28 => vs[i] & vt[i],
+                        0x29 => !(vs[i] & vt[i]),
+                        0x2A => vs[i] | vt[i],
+                        0x2B => !(vs[i] | vt[i]),
+                        0x2C => vs[i] ^ vt[i],
+                        _ => !(vs[i] ^ vt[i]),
                     };
-                    op.setvd_lane(op.rs() & 7, res as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_out = res;
-                    op.ctx.div_in = None;
                 }
-                0x36 => {
-                    // VRSQH
-                    let x = op.vt_lane(op.e() & 7);
-                    op.setvd_lane(op.rs() & 7, (op.ctx.div_out >> 16) as u16);
-                    op.setaccum(0, op.vt());
-                    op.ctx.div_in = Some((x as u32) << 16);
-                }
-                0x37 => {} // VNOP
-                0x3f => {} // VNULL
+                op.setvd_lanes(res);
+                op.setaccum_lanes(0, res);
+            }
+            0x30..=0x36 => SpCop2::single_lane_op(&mut op),
+            0x37 => {} // VNOP
+            0x3f => {} // VNULL
+
+            _ => return SpCop2::unimplemented_compute(op, cpu, t),
+        }
+        Ok(())
+    }
+
+    fn unimplemented_compute(
+        mut op: Vectorop,
+        cpu: &mut CpuContext,
+        t: &dbg::Tracer,
+    ) -> dbg::Result<()> {
+        error!(op.spv.logger, "unimplemented COP2 VU opcode={}", op.func().hex());
+        op.spv.halt(cpu, CopError::UnimplementedOp(op.opcode));
+        t.break_here("unimplemented COP2 VU opcode")
+    }
 
-                _ => panic!("unimplemented COP2 VU opcode={}", op.func().hex()),
+    // Opcodes that compute a single lane (VMOV and the reciprocal ones, 0x30
+    // to 0x36). They are scalar, so both implementations share them.
+    fn single_lane_op(op: &mut Vectorop) {
+        match op.func() {
+            0x30 => {
+                // VRCP
+                let x = op.vt_lane(op.e() & 7);
+                let res = vrcp::vrcp(x.sx32(), op.spv.rcp_rom);
+                op.setvd_lane(op.de(), res as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_out = res;
             }
-        } else {
-            match op.e() {
-                0x0 => {
-                    // MFC2
-                    let e = op.rd() >> 1;
-
-                    let mut val = (op.vs_byte(e) as u16) << 8;
-                    val |= op.vs_byte((e + 1) & 15) as u16;
-                    cpu.regs[op.rt()] = val.sx64();
-                }
-                0x2 => match op.rs() {
-                    // CFC2
-                    0 => cpu.regs[op.rt()] = op.ctx.vco().sx64(),
-                    1 => cpu.regs[op.rt()] = op.ctx.vcc().sx64(),
-                    2 => cpu.regs[op.rt()] = op.ctx.vce() as u64,
-                    _ => panic!("unimplement COP2 CFC2 reg:{}", op.rs()),
-                },
-                0x4 => {
-                    // MTC2
-                    let e = op.rd() >> 1;
-                    op.setvs_byte(e, (cpu.regs[op.rt()] >> 8) as u8);
-                    if e != 15 {
-                        op.setvs_byte(e + 1, cpu.regs[op.rt()] as u8);
-                    }
-                }
-                0x6 => match op.rs() {
-                    // CTC2
-                    0 => op.ctx.set_vco(cpu.regs[op.rt()] as u16),
-                    1 => op.ctx.set_vcc(cpu.regs[op.rt()] as u16),
-                    2 => op.ctx.set_vce(cpu.regs[op.rt()] as u8),
-                    _ => panic!("unimplement COP2 CTC2 reg:{}", op.rd()),
-                },
-                _ => {
-                    error!(
-                        op.spv.logger,
-                        "unimplemented COP2 non-VU opcode={:x}",
-                        op.e()
-                    );
-                    return t.break_here("unimplemented COP2 non-VU opcode");
-                }
+            0x31 => {
+                // VRCPL
+                let x = op.vt_lane(op.e() & 7);
+                let res = match op.ctx.div_in {
+                    Some(div_in) => vrcp::vrcp((x as u32) | div_in, op.spv.rcp_rom),
+                    None => vrcp::vrcp(x.sx32(), op.spv.rcp_rom),
+                };
+                op.setvd_lane(op.de(), res as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_out = res;
+                op.ctx.div_in = None;
+            }
+            0x32 => {
+                // VRCPH
+                let x = op.vt_lane(op.e() & 7);
+                op.setvd_lane(op.de(), (op.ctx.div_out >> 16) as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_in = Some((x as u32) << 16);
+            }
+            0x33 => {
+                // VMOV
+                // On hardware, the source lane is the one that the element
+                // would broadcast into the destination lane; when not
+                // strict, it is lane e of VT, as documented.
+                let se = match op.e() {
+                    _ if !op.spv.strict_hw => op.e() & 0b111,
+                    0..=1 => (op.e() & 0b000) | (op.de() & 0b111),
+                    2..=3 => (op.e() & 0b001) | (op.de() & 0b110),
+                    4..=7 => (op.e() & 0b011) | (op.de() & 0b100),
+                    _ => op.e() & 0b111, // 8-15
+                };
+    
+                let res = op.vt_lane(se);
+                op.setvd_lane(op.de(), res);
+                // FIXME: update ACCUM with VMOV?
+                op.setaccum_lanes(0, op.vte_lanes());
+            }
+            0x34 => {
+                // VRSQ
+                let x = op.vt_lane(op.e() & 7);
+                let res = if op.spv.strict_hw {
+                    vrcp::vrsq(x.sx32(), op.spv.rsq_rom)
+                } else {
+                    vrcp::vrsq_no_quirk(x.sx32(), op.spv.rsq_rom)
+                };
+                op.setvd_lane(op.de(), res as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_out = res;
+            }
+            0x35 => {
+                // VRSQL
+                // The hardware special case of VRSQ also applies to the
+                // double precision input 0xFFFF8000.
+                let x = op.vt_lane(op.e() & 7);
+                let x = match op.ctx.div_in {
+                    Some(div_in) => (x as u32) | div_in,
+                    None => x.sx32(),
+                };
+                let res = if op.spv.strict_hw {
+                    vrcp::vrsq(x, op.spv.rsq_rom)
+                } else {
+                    vrcp::vrsq_no_quirk(x, op.spv.rsq_rom)
+                };
+                op.setvd_lane(op.de(), res as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_out = res;
+                op.ctx.div_in = None;
             }
+            0x36 => {
+                // VRSQH
+                let x = op.vt_lane(op.e() & 7);
+                op.setvd_lane(op.de(), (op.ctx.div_out >> 16) as u16);
+                op.setaccum_lanes(0, op.vte_lanes());
+                op.ctx.div_in = Some((x as u32) << 16);
+            }
+            _ => unreachable!(),
         }
-        Ok(())
     }
 }
 
+// Write the bits of src from skip_bits upward into dst, leaving the lowest
+// skip_bits bits of dst untouched (skip_bits >= 128 writes nothing).
 fn write_partial_left<B: ByteOrder>(dst: &mut [u8], src: u128, skip_bits: usize) {
-    let mask = !0u128;
-    let mask = if skip_bits < 128 {
-        mask << skip_bits
-    } else {
-        0
-    };
-    let src = if skip_bits < 128 { src << skip_bits } else { 0 };
+    let mask = (!0u128).checked_shl(skip_bits as u32).unwrap_or(0);
+    let src = src.checked_shl(skip_bits as u32).unwrap_or(0);
 
     let mut d = B::read_u128(dst);
     d = (d & !mask) | (src & mask);
     B::write_u128(dst, d);
 }
 
+// Write the top nbits bits of src into dst, moved down by skip_bits: the
+// bits that would fall below bit 0 are dropped. The shifts are checked, as
+// both nbits = 0 and skip_bits = 128 would shift by the full width.
 fn write_partial_right<B: ByteOrder>(dst: &mut [u8], src: u128, skip_bits: usize, nbits: usize) {
-    let mask = !0u128;
-    let mask = mask << (128 - nbits);
-    let mask = if skip_bits < 128 {
-        mask >> skip_bits
-    } else {
-        0
-    };
-    let src = if skip_bits < 128 { src >> skip_bits } else { 0 };
+    let mask = (!0u128).checked_shl((128 - nbits) as u32).unwrap_or(0);
+    let mask = mask.checked_shr(skip_bits as u32).unwrap_or(0);
+    let src = src.checked_shr(skip_bits as u32).unwrap_or(0);
 
     let mut d = B::read_u128(dst);
     d = (d & !mask) | (src & mask);
     B::write_u128(dst, d);
 }
 
-// Plain "load vector subword from memory"
+// Compute the DMEM address accessed by a load/store opcode. The base register
+// is a full 32-bit value, so the addition must wrap before being masked to the
+// 12-bit DMEM address space, like the hardware does.
+#[inline(always)]
+fn dmem_ea(base: u32, offset: u32, shift: usize) -> usize {
+    (base.wrapping_add(offset << shift) & 0xFFF) as usize
+}
+
+// Register byte selected by the element field of a load/store opcode. The
+// element is a byte index, and it wraps around the 16 bytes of the register.
+#[inline(always)]
+fn element_to_byte(e: usize) -> usize {
+    e & 0xF
+}
+
+// Same as element_to_byte, but as a bit offset in the 128-bit value of the
+// register (eg: for rotations and write_partial_*).
+#[inline(always)]
+fn element_to_bit(e: usize) -> usize {
+    element_to_byte(e) * 8
+}
+
+// Range of DMEM bytes (start, length) read by a LWC2 opcode; it can wrap
+// around the end of DMEM. Plain subword loads read just the subword, the
+// other opcodes read a whole 128-bit line (or, for the ones that work on
+// 8-byte boundaries, the 16 bytes starting at the double-word).
+fn load_window(op: u32, base: u32, offset: u32) -> (usize, usize) {
+    let shift = VLOAD_OPS.get(op as usize).map_or(0, |o| o.1);
+    let ea = dmem_ea(base, offset, shift);
+    match op {
+        0x00..=0x03 => (ea, 1 << shift),
+        0x04 | 0x05 => (ea & !0xF, 0x10),
+        0x06..=0x0B => (ea & !0x7, 0x10),
+        _ => (ea, 0),
+    }
+}
+
+// Fill the specified window of a scratch DMEM buffer by reading it through
+// the bus, one byte at a time.
+fn load_via_bus(
+    dmem: &mut [u8],
+    bus: &Bus,
+    window: (usize, usize),
+    t: &dbg::Tracer,
+) -> dbg::Result<()> {
+    let (start, len) = window;
+    for i in 0..len {
+        let addr = (start + i) & (DMEM_SIZE - 1);
+        let val = bus.read::<u8>(addr as u32);
+        t.trace_mem_read(RSPCPU_NAME, addr as u64, u8::ACCESS_SIZE, val.into())?;
+        dmem[addr] = val;
+    }
+    Ok(())
+}
+
+// Run a store on scratch DMEM buffers and write the bytes it modified through
+// the bus, one byte at a time. Stores never read DMEM, so the written bytes
+// are found by running the store twice, over two buffers with a different
+// fill: they are the ones that end up with the same value in both.
+// Returns the result of the store.
+fn store_via_bus<F>(bus: &mut Bus, ea: usize, t: &dbg::Tracer, store: F) -> dbg::Result<bool>
+where
+    F: Fn(&mut [u8]) -> bool,
+{
+    let mut dmem1 = [0x00u8; DMEM_ALLOC_SIZE];
+    let mut dmem2 = [0xFFu8; DMEM_ALLOC_SIZE];
+    if !store(&mut dmem1) {
+        return Ok(false);
+    }
+    store(&mut dmem2);
+
+    // All stores write within the 32 bytes from the 128-bit line containing
+    // EA, wrapping around the end of DMEM (eg: STV at 0xFF8 writes 0x000-0x007).
+    // Bytes written past the end without wrapping are not visible.
+    let start = ea & !0xF;
+    for i in 0..0x20 {
+        let addr = (start + i) & (DMEM_SIZE - 1);
+        if dmem1[addr] == dmem2[addr] {
+            bus.write::<u8>(addr as u32, dmem1[addr]);
+            t.trace_mem_write(RSPCPU_NAME, addr as u64, u8::ACCESS_SIZE, dmem1[addr].into())?;
+        }
+    }
+    Ok(true)
+}
+
+// Plain "load vector subword from memory". Unlike stores, loads don't wrap
+// around the end of the register: bytes past it are dropped (eg: LDV at
+// element 12 only writes bytes 12-15), as verified by the ldv_sdv golden test.
+// The subword is not sign-extended: only its bytes are written, and the rest
+// of the register (including the other byte of a partially written lane) is
+// left untouched.
 fn lxv<T: MemInt>(reg: &mut VectorReg, element: usize, dmem: &mut [u8], base: u32, offset: u32) {
-    let ea = ((base + (offset << T::SIZE_LOG)) & 0xFFF) as usize;
+    let ea = dmem_ea(base, offset, T::SIZE_LOG);
     if ea + T::SIZE > 0x1000 {
         for i in 0..16 {
             // Mirror the beginning of DMEM after the end (using excess memory that
@@ -730,20 +1865,26 @@ fn lxv<T: MemInt>(reg: &mut VectorReg, element: usize, dmem: &mut [u8], base: u3
     let mut mem: u128 = mem64.into();
     mem <<= 128 - T::SIZE * 8;
 
-    write_partial_right::<LittleEndian>(&mut reg.0, mem, element as usize * 8, T::SIZE * 8);
+    write_partial_right::<LittleEndian>(&mut reg.0, mem, element_to_bit(element), T::SIZE * 8);
 }
 
-// Plain "store vector subword into memory"
+// Plain "store vector subword into memory". The element is the register byte
+// the subword starts at, with no alignment requirement: bytes past the end of
+// the register wrap around to its start (eg: SLV at element 14 stores bytes
+// 14, 15, 0 and 1), as verified on hardware by the *_s*v golden tests.
 fn sxv<T: MemInt>(dmem: &mut [u8], base: u32, offset: u32, reg: &VectorReg, element: usize) {
-    let ea = ((base + (offset << T::SIZE_LOG)) & 0xFFF) as usize;
+    let ea = dmem_ea(base, offset, T::SIZE_LOG);
 
     let mut reg = reg.u128();
-    reg = reg.rotate_left(element as u32 * 8);
+    reg = reg.rotate_left(element_to_bit(element) as u32);
     reg >>= 128 - T::SIZE * 8;
 
     T::endian_write_to::<BigEndian>(&mut dmem[ea..ea + T::SIZE], T::truncate_from(reg as u64));
 }
 
+// Register indices are 0-31 for the vector registers, followed by the REG_*
+// constants. Out-of-range indices are logged as errors: reads return zero and
+// writes are ignored.
 impl Cop for SpCop2 {
     fn reg(&self, _cpu: &CpuContext, idx: usize) -> u128 {
         match idx {
@@ -753,35 +1894,95 @@ impl Cop for SpCop2 {
             SpCop2::REG_ACCUM_LO => LittleEndian::read_u128(&self.ctx.accum[0].0),
             SpCop2::REG_ACCUM_MD => LittleEndian::read_u128(&self.ctx.accum[1].0),
             SpCop2::REG_ACCUM_HI => LittleEndian::read_u128(&self.ctx.accum[2].0),
-            _ => self.ctx.vregs[idx].u128(),
+            SpCop2::REG_DIV_OUT => self.ctx.div_out as u128,
+            0..=31 => self.ctx.vregs[idx].u128(),
+            _ => {
+                error!(self.logger, "read of invalid COP2 register"; "reg" => idx);
+                0
+            }
         }
     }
     fn set_reg(&mut self, _cpu: &mut CpuContext, idx: usize, val: u128) {
         match idx {
-            SpCop2::REG_VCO => self.ctx.set_vco(val as u16),
-            SpCop2::REG_VCC => self.ctx.set_vcc(val as u16),
-            SpCop2::REG_VCE => self.ctx.set_vce(val as u8),
+            SpCop2::REG_VCO => self.ctx.set_vco(val as u32),
+            SpCop2::REG_VCC => self.ctx.set_vcc(val as u32),
+            SpCop2::REG_VCE => self.ctx.set_vce(val as u32),
             SpCop2::REG_ACCUM_LO => LittleEndian::write_u128(&mut self.ctx.accum[0].0, val),
             SpCop2::REG_ACCUM_MD => LittleEndian::write_u128(&mut self.ctx.accum[1].0, val),
             SpCop2::REG_ACCUM_HI => LittleEndian::write_u128(&mut self.ctx.accum[2].0, val),
-            _ => self.ctx.vregs[idx].setu128(val),
+            SpCop2::REG_DIV_OUT => self.ctx.div_out = val as u32,
+            0..=31 => self.ctx.vregs[idx].setu128(val),
+            _ => {
+                error!(self.logger, "write to invalid COP2 register"; "reg" => idx, "val" => val);
+            }
+        }
+    }
+    fn try_reg(&self, cpu: &CpuContext, idx: usize) -> std::result::Result<u128, CopError> {
+        match idx {
+            0..=SpCop2::REG_DIV_OUT => Ok(self.reg(cpu, idx)),
+            _ => Err(CopError::BadRegister(idx)),
+        }
+    }
+    fn try_set_reg(
+        &mut self,
+        cpu: &mut CpuContext,
+        idx: usize,
+        val: u128,
+    ) -> std::result::Result<(), CopError> {
+        match idx {
+            0..=SpCop2::REG_DIV_OUT => {
+                self.set_reg(cpu, idx, val);
+                Ok(())
+            }
+            _ => Err(CopError::BadRegister(idx)),
         }
     }
+    fn take_error(&mut self) -> Option<CopError> {
+        self.error.take()
+    }
 
     fn op(&mut self, cpu: &mut CpuContext, op: u32, t: &dbg::Tracer) -> dbg::Result<()> {
-        unsafe { self.uop(cpu, op, t) }
+        self.execute(cpu, op, t)
     }
 
     fn lwc(
         &mut self,
         op: u32,
         ctx: &mut CpuContext,
-        _bus: &Bus,
+        bus: &Bus,
         t: &dbg::Tracer,
     ) -> dbg::Result<()> {
-        let sp = Sp::get_mut();
-        let mut dmem = &mut sp.dmem;
+        let opcode = op;
         let (base, vtidx, op, element, offset) = SpCop2::oploadstore(op, ctx);
+        self.trace_loadstore(false, op, base, vtidx, element, offset);
+        if let Some(addr) = self.faulting(opcode, ctx) {
+            error!(self.logger, "injected fault in VU load"; "op" => opcode.hex());
+            self.error = Some(CopError::AddressFault(addr));
+            return t.break_here("injected fault in VU load");
+        }
+        if let Some(ref mut stats) = self.stats {
+            stats.count_load(op);
+        }
+        let mut scratch;
+        let mut dmem = if self.dmem_via_bus {
+            scratch = [0u8; DMEM_ALLOC_SIZE];
+            load_via_bus(&mut scratch, bus, load_window(op, base, offset), t)?;
+            &mut scratch[..]
+        } else if let Some(ref mut dmem) = self.dmem {
+            &mut dmem[..]
+        } else {
+            Sp::get_mut().vu_dmem()
+        };
+        if let Some(ref mut oplog) = self.oplog {
+            let (addr, len) = load_window(op, base, offset);
+            let data = (addr..addr + len).map(|a| dmem[a & (DMEM_SIZE - 1)]).collect();
+            oplog.push(OpLogEntry::Load {
+                opcode,
+                base,
+                addr,
+                data,
+            });
+        }
         let vt = &mut self.ctx.vregs[vtidx];
         match op {
             0x00 => lxv::<u8>(vt, element as usize, &mut dmem, base, offset), // LBV
@@ -790,31 +1991,47 @@ impl Cop for SpCop2 {
             0x03 => lxv::<u64>(vt, element as usize, &mut dmem, base, offset), // LDV
             0x04 => {
                 // LQV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = dmem_ea(base, offset, 4);
                 let qw_start = ea & !0xF;
                 let ea_idx = ea & 0xF;
 
+                // LQV loads the bytes between EA and the end of the quadword,
+                // starting at register byte `element`. Bytes past the end of
+                // the register are not loaded.
                 let mut mem = BigEndian::read_u128(&dmem[qw_start..qw_start + 0x10]);
                 mem <<= ea_idx * 8;
-                write_partial_right::<LittleEndian>(&mut vt.0, mem, element as usize * 8, 128);
+                let nbits = (16 - ea_idx) * 8;
+                let shift = element_to_bit(element as usize);
+                write_partial_right::<LittleEndian>(&mut vt.0, mem, shift, nbits);
             }
             0x05 => {
                 // LRV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = dmem_ea(base, offset, 4);
                 let qw_start = ea & !0xF;
                 let ea_idx = ea & 0xF;
 
-                let mem = BigEndian::read_u128(&dmem[qw_start..qw_start + 0x10]);
+                // LRV loads the bytes between the start of the quadword and EA,
+                // right-justifying them so that the last one lands at
+                // register byte 15 (moved forward by the element). Bytes that
+                // would fall past the end of the register are not loaded: there
+                // is no wrap-around, so an aligned EA (or a large element)
+                // loads nothing at all.
                 let sh = (16 - ea_idx) + element as usize;
-                write_partial_right::<LittleEndian>(&mut vt.0, mem, sh * 8, 128);
+                if sh < 16 {
+                    let mem = BigEndian::read_u128(&dmem[qw_start..qw_start + 0x10]);
+                    write_partial_right::<LittleEndian>(&mut vt.0, mem, element_to_bit(sh), 128);
+                }
             }
             0x06 => {
                 // LPV
-                let ea = ((base + (offset << 3)) & 0xFFF) as usize;
+                // The 8 bytes are read from the 16-byte window starting at the
+                // aligned double-word, beginning at the address rotated back by
+                // the element: they wrap around the end of the window.
+                let ea = dmem_ea(base, offset, 3);
                 let qw_start = ea & !0x7;
                 let mut ea_idx = ea & 7;
 
-                ea_idx = (ea_idx - element as usize) & 0xF;
+                ea_idx = ea_idx.wrapping_sub(element as usize) & 0xF;
                 for e in 0..8 {
                     let mem = dmem[(qw_start + ea_idx) & 0xFFF] as u16;
                     self.ctx.vregs[vtidx].setlane(e, mem << 8);
@@ -824,11 +2041,11 @@ impl Cop for SpCop2 {
             }
             0x07 => {
                 // LUV
-                let ea = ((base + (offset << 3)) & 0xFFF) as usize;
+                let ea = dmem_ea(base, offset, 3);
                 let qw_start = ea & !0x7;
                 let mut ea_idx = ea & 7;
 
-                ea_idx = (ea_idx - element as usize) & 0xF;
+                ea_idx = ea_idx.wrapping_sub(element as usize) & 0xF;
                 for e in 0..8 {
                     let mem = dmem[(qw_start + ea_idx) & 0xFFF] as u16;
                     self.ctx.vregs[vtidx].setlane(e, mem << 7);
@@ -838,11 +2055,11 @@ impl Cop for SpCop2 {
             }
             0x08 => {
                 // LHV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = dmem_ea(base, offset, 4);
                 let qw_start = ea & !0x7;
                 let mut ea_idx = ea & 0x7;
 
-                ea_idx = (ea_idx - element as usize) & 0xF;
+                ea_idx = ea_idx.wrapping_sub(element as usize) & 0xF;
                 for e in 0..8 {
                     let mem = dmem[(qw_start + ea_idx) & 0xFFF] as u16;
                     self.ctx.vregs[vtidx].setlane(e, mem << 7);
@@ -852,11 +2069,11 @@ impl Cop for SpCop2 {
             }
             0x09 => {
                 // LFV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
+                let ea = dmem_ea(base, offset, 4);
                 let qw_start = ea & !0x7;
 
                 let mut high: u64 = 0;
-                let mut ea_idx = ((ea & 0x7) - element as usize) & 0xF;
+                let mut ea_idx = (ea & 0x7).wrapping_sub(element as usize) & 0xF;
                 for e in 0..4 {
                     let mem = dmem[(qw_start + ea_idx) & 0xFFF] as u64;
                     high <<= 16;
@@ -866,7 +2083,7 @@ impl Cop for SpCop2 {
                 }
 
                 let mut low: u64 = 0;
-                let mut ea_idx = ((ea & 0x7) - element as usize + 8) & 0xF;
+                let mut ea_idx = (ea & 0x7).wrapping_sub(element as usize).wrapping_add(8) & 0xF;
                 for e in 0..4 {
                     let mem = dmem[(qw_start + ea_idx) & 0xFFF] as u64;
                     low <<= 16;
@@ -875,27 +2092,32 @@ impl Cop for SpCop2 {
                     ea_idx &= 0xF;
                 }
 
+                // The element already selects the lanes in `new`: only the 8
+                // bytes starting at register byte `element` are loaded (less,
+                // if they fall past its end), and the other lanes are kept.
                 let new = ((high as u128) << 64) | low as u128;
-
-                let mask: u128 = (0xFFFFFFFFFFFFFFFF0000000000000000) >> (element * 8);
-                let r = self.ctx.vregs[vtidx].u128();
-                self.ctx.vregs[vtidx].setu128((r & !mask) | (new & mask));
+                let shift = element_to_bit(element as usize);
+                write_partial_right::<LittleEndian>(
+                    &mut self.ctx.vregs[vtidx].0,
+                    new << shift,
+                    shift,
+                    64,
+                );
             }
             0x0B => {
                 // LTV
-                let ea = (base + (offset << 4)) & 0xFFF;
+                let ea = dmem_ea(base, offset, 4) as u32;
                 let qw_start = ea as usize & !0x7;
-                let mut mem = if qw_start != 0xFF8 {
-                    BigEndian::read_u128(&dmem[qw_start..qw_start + 0x10])
-                } else {
-                    // Handle wrap around in DMEM
-                    ((BigEndian::read_u64(&dmem[0xFF8..0x1000]) as u128) << 64)
-                        | BigEndian::read_u64(&dmem[0x0..0x8]) as u128
-                };
+                // The 16 bytes starting at the double-word wrap around the
+                // end of DMEM (eg: at 0xFF8 the last 8 are read from 0x0).
+                let mut mem: u128 = 0;
+                for i in 0..0x10 {
+                    mem = (mem << 8) | dmem[(qw_start + i) & 0xFFF] as u128;
+                }
 
                 let vtbase = vtidx & !7;
                 let mut vtoff = element as usize >> 1;
-                mem = mem.rotate_left((element + (ea & 0x8)) * 8);
+                mem = mem.rotate_left(element_to_bit((element + (ea & 0x8)) as usize) as u32);
 
                 for e in 0..8 {
                     self.ctx.vregs[vtbase + vtoff].setlane(e, (mem >> (128 - 16)) as u16);
@@ -904,154 +2126,49 @@ impl Cop for SpCop2 {
                     vtoff &= 7;
                 }
             }
-            _ => return t.panic(&format!("unimplemented VU load opcode={}", op.hex())),
+            _ => {
+                error!(self.logger, "unimplemented VU load opcode={}", op.hex());
+                self.halt(ctx, CopError::UnimplementedOp(opcode));
+                return t.break_here("unimplemented VU load opcode");
+            }
         }
         Ok(())
     }
     fn swc(
         &mut self,
         op: u32,
-        ctx: &CpuContext,
-        _bus: &mut Bus,
+        ctx: &mut CpuContext,
+        bus: &mut Bus,
         t: &dbg::Tracer,
     ) -> dbg::Result<()> {
-        let sp = Sp::get_mut();
-        let mut dmem = &mut sp.dmem;
+        let opcode = op;
         let (base, vtidx, op, element, offset) = SpCop2::oploadstore(op, ctx);
-        let vt = &self.ctx.vregs[vtidx];
-        match op {
-            0x00 => sxv::<u8>(&mut dmem, base, offset, vt, element as usize), // SBV
-            0x01 => sxv::<u16>(&mut dmem, base, offset, vt, element as usize), // SSV
-            0x02 => sxv::<u32>(&mut dmem, base, offset, vt, element as usize), // SLV
-            0x03 => sxv::<u64>(&mut dmem, base, offset, vt, element as usize), // SDV
-            0x04 => {
-                // SQV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
-                let qw_start = ea & !0xF;
-                let ea_idx = ea & 0xF;
-
-                let mut reg = vt.u128();
-                reg = reg.rotate_left(element * 8);
-
-                let memptr = &mut dmem[qw_start..qw_start + 0x10];
-                write_partial_right::<BigEndian>(memptr, reg, ea_idx * 8, 128);
-            }
-            0x05 => {
-                // SRV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
-                let qw_start = ea & !0xF;
-                let ea_idx = ea & 0xF;
-
-                let mut reg = vt.u128();
-                reg = reg.rotate_left(element * 8);
-
-                let memptr = &mut dmem[qw_start..qw_start + 0x10];
-                write_partial_left::<BigEndian>(memptr, reg, (16 - ea_idx) * 8);
-            }
-            0x06 => {
-                // SPV
-                let ea = ((base + (offset << 3)) & 0xFFF) as usize;
-
-                let memptr = &mut dmem[ea..ea + 0x10];
-                for e in 0 as usize..8 as usize {
-                    let eidx = (e + element as usize) & 0xF;
-                    memptr[e] = ((vt.lane(eidx & 0x7) << (eidx >> 3)) >> 8) as u8;
-                }
-            }
-            0x07 => {
-                // SUV
-                let ea = ((base + (offset << 3)) & 0xFFF) as usize;
-
-                let memptr = &mut dmem[ea..ea + 0x10];
-                for e in 0 as usize..8 as usize {
-                    let eidx = (e + element as usize) & 0xF;
-                    memptr[e] = ((vt.lane(eidx & 0x7) >> (eidx >> 3)) >> 7) as u8;
-                }
-            }
-            0x08 => {
-                // SHV
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
-                let qw_start = ea as usize & !0x7;
-                let ea_idx = ea & 0x7;
-
-                let memptr = &mut dmem[qw_start..qw_start + 0x10];
-                for e in 0 as usize..8 as usize {
-                    let eidx = (e * 2 + element as usize) & 0xF;
-                    let midx = (e * 2 + ea_idx) & 0xF;
-                    let v = ((vt.byte(eidx) as u16) << 8) | vt.byte((eidx + 1) & 0xF) as u16;
-                    memptr[midx] = (v >> 7) as u8;
-                }
-            }
-            0x09 => {
-                // SFV
-                // FIXME: this is dumped through experimentation. Surely there's no
-                // table in the silicon... figure it out the pattern and the logic.
-                const LANES: [[isize; 4]; 16] = [
-                    [0, 1, 2, 3],     // e0
-                    [6, 7, 4, 5],     // e1
-                    [-1, -1, -1, -1], // e2
-                    [-1, -1, -1, -1], // e3
-                    [1, 2, 3, 0],     // e4
-                    [7, 4, 5, 6],     // e5
-                    [-1, -1, -1, -1], // e6
-                    [-1, -1, -1, -1], // e7
-                    [4, 5, 6, 7],     // e8
-                    [-1, -1, -1, -1], // e9
-                    [-1, -1, -1, -1], // e10
-                    [3, 0, 1, 2],     // e11
-                    [5, 6, 7, 4],     // e12
-                    [-1, -1, -1, -1], // e13
-                    [-1, -1, -1, -1], // e14
-                    [0, 1, 2, 3],     // e15
-                ];
-
-                let ea = ((base + (offset << 4)) & 0xFFF) as usize;
-                let qw_start = ea as usize & !0x7;
-                let ea_idx = ea & 0x7;
-
-                let memptr = &mut dmem[qw_start..qw_start + 0x10];
-                for e in 0 as usize..4 as usize {
-                    let eidx = LANES[element as usize][e];
-                    let v = if eidx < 0 {
-                        0 as u16
-                    } else {
-                        vt.lane(eidx as usize) as u16
-                    };
-                    let midx = (e * 4 + ea_idx) & 0xF;
-                    memptr[midx] = (v >> 7) as u8;
-                }
-            }
-            0x0A => {
-                // SWV
-                let ea = (base + (offset << 4)) & 0xFFF;
-                let qw_start = ea as usize & !0x7;
-
-                let mut reg = vt.u128();
-                reg = reg.rotate_right((ea & 7) * 8);
-                reg = reg.rotate_left(element * 8);
-                BigEndian::write_u128(&mut dmem[qw_start..qw_start + 0x10], reg);
-            }
-            0x0B => {
-                // STV
-                let ea = (base + (offset << 4)) & 0xFFF;
-                let qw_start = ea as usize & !0x7;
-                let mut mem: u128 = 0;
-
-                let vtbase = vtidx & !7;
-                let mut vtoff = element as usize >> 1;
-
-                for e in 0..8 {
-                    let r = self.ctx.vregs[vtbase + vtoff].lane(e);
-                    mem <<= 16;
-                    mem |= r as u128;
-                    vtoff += 1;
-                    vtoff &= 7;
-                }
-
-                mem = mem.rotate_right((ea & 7) * 8);
-                BigEndian::write_u128(&mut dmem[qw_start..qw_start + 0x10], mem);
-            }
-            _ => return t.panic(&format!("unimplemented VU store opcode={}", op.hex())),
+        self.trace_loadstore(true, op, base, vtidx, element, offset);
+        if let Some(addr) = self.faulting(opcode, ctx) {
+            error!(self.logger, "injected fault in VU store"; "op" => opcode.hex());
+            self.error = Some(CopError::AddressFault(addr));
+            return t.break_here("injected fault in VU store");
+        }
+        if let Some(ref mut stats) = self.stats {
+            stats.count_store(op);
+        }
+        if let Some(ref mut oplog) = self.oplog {
+            oplog.push(OpLogEntry::Store { opcode, base });
+        }
+        let vu = &*self.ctx;
+        let store = |dmem: &mut [u8]| SpCop2::store(vu, dmem, op, base, vtidx, element, offset);
+        let done = if self.dmem_via_bus {
+            let shift = VSTORE_OPS.get(op as usize).map_or(0, |o| o.1);
+            store_via_bus(bus, dmem_ea(base, offset, shift), t, store)?
+        } else if let Some(ref mut dmem) = self.dmem {
+            store(dmem)
+        } else {
+            store(Sp::get_mut().vu_dmem())
+        };
+        if !done {
+            error!(self.logger, "unimplemented VU store opcode={}", op.hex());
+            self.halt(ctx, CopError::UnimplementedOp(opcode));
+            return t.break_here("unimplemented VU store opcode");
         }
         Ok(())
     }
@@ -1068,7 +2185,7 @@ impl Cop for SpCop2 {
     fn sdc(
         &mut self,
         _op: u32,
-        _ctx: &CpuContext,
+        _ctx: &mut CpuContext,
         _bus: &mut Bus,
         _t: &dbg::Tracer,
     ) -> dbg::Result<()> {
@@ -1126,3 +2243,15 @@ impl dbg::RegisterView for SpCop2 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_vector_reg_invalid_lane() {
+        VectorReg::default().lane(8);
+    }
+}
@@ -1,7 +1,11 @@
 extern crate emu;
 
+use super::cop2::SpCop2;
+use super::instr::Cop2Instr;
 use emu::dbg::{DecodedInsn, Operand};
 use mips64::REG_NAMES;
+use std::fmt;
+use std::str::FromStr;
 
 // Decoder constants
 pub(crate) const VREG_NAMES: [&'static str; 32] = [
@@ -12,6 +16,95 @@ pub(crate) const VREG_NAMES: [&'static str; 32] = [
 
 pub(crate) const ACC_NAMES: [&str; 3] = ["acc_lo", "acc_md", "acc_hi"];
 
+// Control registers accessed by CFC2/CTC2, indexed by the rd field (which
+// Cop2Instr::decode restricts to 0-2).
+const CTRL_NAMES: [&str; 3] = ["vco", "vcc", "vce"];
+
+/// A register of the vector unit, as exposed to the debugger through
+/// `Cop::reg` and `Cop::set_reg`. It is displayed and parsed with the names
+/// used by the disassembler ("v3", "vcc", "acc_lo", ...).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cop2Reg {
+    /// Vector register (0..=31).
+    V(usize),
+    Vco,
+    Vcc,
+    Vce,
+    AccLo,
+    AccMd,
+    AccHi,
+    DivOut,
+}
+
+impl Cop2Reg {
+    /// Index of the register for `Cop::reg` and `Cop::set_reg`.
+    pub fn index(self) -> usize {
+        match self {
+            Cop2Reg::V(n) => n,
+            Cop2Reg::Vco => SpCop2::REG_VCO,
+            Cop2Reg::Vcc => SpCop2::REG_VCC,
+            Cop2Reg::Vce => SpCop2::REG_VCE,
+            Cop2Reg::AccLo => SpCop2::REG_ACCUM_LO,
+            Cop2Reg::AccMd => SpCop2::REG_ACCUM_MD,
+            Cop2Reg::AccHi => SpCop2::REG_ACCUM_HI,
+            Cop2Reg::DivOut => SpCop2::REG_DIV_OUT,
+        }
+    }
+
+    /// Inverse of `index`. Returns None for an invalid index.
+    pub fn from_index(idx: usize) -> Option<Cop2Reg> {
+        match idx {
+            0..=31 => Some(Cop2Reg::V(idx)),
+            SpCop2::REG_VCO => Some(Cop2Reg::Vco),
+            SpCop2::REG_VCC => Some(Cop2Reg::Vcc),
+            SpCop2::REG_VCE => Some(Cop2Reg::Vce),
+            SpCop2::REG_ACCUM_LO => Some(Cop2Reg::AccLo),
+            SpCop2::REG_ACCUM_MD => Some(Cop2Reg::AccMd),
+            SpCop2::REG_ACCUM_HI => Some(Cop2Reg::AccHi),
+            SpCop2::REG_DIV_OUT => Some(Cop2Reg::DivOut),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Cop2Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Cop2Reg::V(n) => VREG_NAMES[n],
+            Cop2Reg::Vco => "vco",
+            Cop2Reg::Vcc => "vcc",
+            Cop2Reg::Vce => "vce",
+            Cop2Reg::AccLo => ACC_NAMES[0],
+            Cop2Reg::AccMd => ACC_NAMES[1],
+            Cop2Reg::AccHi => ACC_NAMES[2],
+            Cop2Reg::DivOut => "div_out",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Cop2Reg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cop2Reg, String> {
+        match s {
+            "vco" => Ok(Cop2Reg::Vco),
+            "vcc" => Ok(Cop2Reg::Vcc),
+            "vce" => Ok(Cop2Reg::Vce),
+            "div_out" => Ok(Cop2Reg::DivOut),
+            _ => {
+                if let Some(n) = VREG_NAMES.iter().position(|&r| r == s) {
+                    Ok(Cop2Reg::V(n))
+                } else if let Some(n) = ACC_NAMES.iter().position(|&r| r == s) {
+                    Ok([Cop2Reg::AccLo, Cop2Reg::AccMd, Cop2Reg::AccHi][n])
+                } else {
+                    Err(format!("unknown COP2 register: {}", s))
+                }
+            }
+        }
+    }
+}
+
 const VMEM_FMT: &'static str = "{}[e{}],{}({})";
 const VMOV_FMT: &'static str = "{}[e{}],{}[e{}]";
 const VREG2_FMT: &'static str = "{},{}[e{}]";
@@ -20,17 +113,104 @@ const VREG3_FMT: &'static str = "{},{},{}[e{}]";
 pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
     use self::Operand::*;
 
-    let op = opcode >> 26;
-    let func = opcode & 0x3F;
-    let e = ((opcode >> 21) & 0xF) as u8;
-    let rsx = (opcode >> 11) & 0x1f;
-    let rdx = (opcode >> 6) & 0x1f;
-    // let grs = REG_NAMES[((opcode >> 11) & 0x1f) as usize].into();
-    let grt = REG_NAMES[((opcode >> 16) & 0x1f) as usize].into();
-    // let grd = REG_NAMES[((opcode >> 6) & 0x1f) as usize].into();
-    let vrs = VREG_NAMES[((opcode >> 11) & 0x1f) as usize].into();
-    let vrt = VREG_NAMES[((opcode >> 16) & 0x1f) as usize].into();
-    let vrd = VREG_NAMES[((opcode >> 6) & 0x1f) as usize].into();
+    match Cop2Instr::decode(opcode) {
+        Cop2Instr::Compute {
+            func,
+            vd,
+            vs,
+            vt,
+            e,
+        } => decode_compute(func, vd, vs, vt, e as u8),
+        Cop2Instr::Mfc2 { rt, vs, e } => {
+            DecodedInsn::new3("mfc2", IReg(REG_NAMES[rt]), OReg(VREG_NAMES[vs]), Imm8(e as u8))
+                .with_fmt(VREG2_FMT)
+        }
+        Cop2Instr::Mtc2 { rt, vs, e } => {
+            DecodedInsn::new3("mtc2", IReg(REG_NAMES[rt]), OReg(VREG_NAMES[vs]), Imm8(e as u8))
+                .with_fmt(VREG2_FMT)
+        }
+        Cop2Instr::Cfc2 { rt, rd } => {
+            DecodedInsn::new2("cfc2", OReg(REG_NAMES[rt]), IReg(CTRL_NAMES[rd]))
+        }
+        Cop2Instr::Ctc2 { rt, rd } => {
+            DecodedInsn::new2("ctc2", OReg(REG_NAMES[rt]), IReg(CTRL_NAMES[rd]))
+        }
+        Cop2Instr::Load {
+            op,
+            vt,
+            base,
+            e,
+            offset,
+        } => {
+            let vrt = VREG_NAMES[vt];
+            let base = REG_NAMES[base];
+            let e = e as u8;
+            let off = offset as u16;
+
+            let vloadinsn_new = |name, off| {
+                DecodedInsn::new4(name, OReg(vrt), Imm8(e), Imm16(off), IReg(base))
+                    .with_fmt(VMEM_FMT)
+            };
+            match op {
+                0x00 => vloadinsn_new("lbv", off.wrapping_mul(1)),
+                0x01 => vloadinsn_new("lsv", off.wrapping_mul(2)),
+                0x02 => vloadinsn_new("llv", off.wrapping_mul(4)),
+                0x03 => vloadinsn_new("ldv", off.wrapping_mul(8)),
+                0x04 => vloadinsn_new("lqv", off.wrapping_mul(16)),
+                0x05 => vloadinsn_new("lrv", off.wrapping_mul(16)),
+                0x06 => vloadinsn_new("lpv", off.wrapping_mul(8)),
+                0x07 => vloadinsn_new("luv", off.wrapping_mul(8)),
+                0x08 => vloadinsn_new("lhv", off.wrapping_mul(16)),
+                0x09 => vloadinsn_new("lfv", off.wrapping_mul(16)),
+                0x0B => vloadinsn_new("ltv", off.wrapping_mul(16)),
+                _ => DecodedInsn::new1("lwc2", Imm32(op)),
+            }
+        }
+        Cop2Instr::Store {
+            op,
+            vt,
+            base,
+            e,
+            offset,
+        } => {
+            let vrt = VREG_NAMES[vt];
+            let base = REG_NAMES[base];
+            let e = e as u8;
+            let off = offset as u16;
+
+            let vstoreinsn_new = |name, off| {
+                DecodedInsn::new4(name, IReg(vrt), Imm8(e), Imm16(off), IReg(base))
+                    .with_fmt(VMEM_FMT)
+            };
+            match op {
+                0x00 => vstoreinsn_new("sbv", off.wrapping_mul(1)),
+                0x01 => vstoreinsn_new("ssv", off.wrapping_mul(2)),
+                0x02 => vstoreinsn_new("slv", off.wrapping_mul(4)),
+                0x03 => vstoreinsn_new("sdv", off.wrapping_mul(8)),
+                0x04 => vstoreinsn_new("sqv", off.wrapping_mul(16)),
+                0x05 => vstoreinsn_new("srv", off.wrapping_mul(16)),
+                0x06 => vstoreinsn_new("spv", off.wrapping_mul(8)),
+                0x07 => vstoreinsn_new("suv", off.wrapping_mul(8)),
+                0x08 => vstoreinsn_new("shv", off.wrapping_mul(16)),
+                0x09 => vstoreinsn_new("sfv", off.wrapping_mul(16)),
+                0x0A => vstoreinsn_new("swv", off.wrapping_mul(16)),
+                0x0B => vstoreinsn_new("stv", off.wrapping_mul(16)),
+                _ => DecodedInsn::new1("swc2", Imm32(op)),
+            }
+        }
+        Cop2Instr::Unknown(_) if opcode >> 26 == 0x12 => {
+            DecodedInsn::new1("cop2su?", Imm8(((opcode >> 21) & 0xF) as u8))
+        }
+        Cop2Instr::Unknown(_) => DecodedInsn::new0("unkcop2?"),
+    }
+}
+
+fn decode_compute(func: u32, vd: usize, vs: usize, vt: usize, e: u8) -> DecodedInsn {
+    use self::Operand::*;
+
+    let vrs = VREG_NAMES[vs];
+    let vrt = VREG_NAMES[vt];
+    let vrd = VREG_NAMES[vd];
 
     let vreg2insn_new =
         |name| DecodedInsn::new3(name, IOReg(vrd), IReg(vrt), Imm8(e)).with_fmt(VREG2_FMT);
@@ -70,141 +250,61 @@ pub(crate) fn decode(opcode: u32, _pc: u64) -> DecodedInsn {
         }
     };
 
-    match op {
-        0x12 => {
-            if opcode & (1 << 25) != 0 {
-                match func {
-                    0x00 => vmulinsn_new("vmulf"),
-                    0x01 => vmulinsn_new("vmulu"),
-                    0x04 => vmulinsn_new("vmudl"),
-                    0x05 => vmulinsn_new("vmudm"),
-                    0x06 => vmulinsn_new("vmudn"),
-                    0x07 => vmulinsn_new("vmudh"),
-                    0x08 => vmulinsn_new("vmacf"),
-                    0x09 => vmulinsn_new("vmacu"),
-                    0x0C => vmulinsn_new("vmadl"),
-                    0x0D => vmulinsn_new("vmadm"),
-                    0x0E => vmulinsn_new("vmadn"),
-                    0x0F => vmulinsn_new("vmadh"),
-                    0x10 => vreg3insn_new("vadd"),
-                    0x11 => vreg3insn_new("vsub"),
-                    0x13 => vreg3insn_new("vabs"),
-                    0x14 => vreg3insn_new("vaddc"),
-                    0x15 => vreg3insn_new("vsubc"),
-                    0x17 => vreg3insn_new("vsubb"),
-                    0x19 => vreg3insn_new("vsucb"),
-                    0x1D => match e {
-                        8 => DecodedInsn::new2("vsar", OReg(vrd), IReg(ACC_NAMES[0])),
-                        9 => DecodedInsn::new2("vsar", OReg(vrd), IReg(ACC_NAMES[1])),
-                        10 => DecodedInsn::new2("vsar", OReg(vrd), IReg(ACC_NAMES[2])),
-                        _ => DecodedInsn::new2("vsar?", OReg(vrd), Imm8(e)),
-                    },
-                    0x20 => vreg3insn_new("vlt"),
-                    0x21 => vreg3insn_new("veq"),
-                    0x22 => vreg3insn_new("vne"),
-                    0x23 => vreg3insn_new("vge"),
-                    0x24 => vreg3insn_new("vcl"),
-                    0x25 => vreg3insn_new("vch"),
-                    0x26 => vreg3insn_new("vcr"),
-                    0x27 => vreg3insn_new("vmrg"),
-                    0x28 => vreg3insn_new("vand"),
-                    0x29 => vreg3insn_new("vnand"),
-                    0x2A => vreg3insn_new("vor"),
-                    0x2B => vreg3insn_new("vnor"),
-                    0x2C => vreg3insn_new("vxor"),
-                    0x2D => vreg3insn_new("vnxor"),
-
-                    0x30 => vreg2insn_new("vrcp"),
-                    0x31 => vreg2insn_new("vrcpl"),
-                    0x32 => vreg2insn_new("vrcph"),
-                    0x33 => DecodedInsn::new4(
-                        "vmov",
-                        IOReg(vrd),
-                        Imm8(rsx as u8 & 0xF),
-                        IReg(vrt),
-                        Imm8(e),
-                    )
-                    .with_fmt(VMOV_FMT),
-                    0x34 => vreg2insn_new("vsqr"),
-                    0x35 => vreg2insn_new("vsqrl"),
-                    0x36 => vreg2insn_new("vsqrh"),
-                    0x37 => vreg2insn_new("vnop"),
-                    _ => DecodedInsn::new1("cop2", Imm32(func)),
-                }
-            } else {
-                match e {
-                    0x0 => DecodedInsn::new3("mfc2", IReg(grt), OReg(vrs), Imm8(rdx as u8 >> 1))
-                        .with_fmt(VREG2_FMT),
-                    0x2 => match rsx {
-                        0 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vco")),
-                        1 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vcc")),
-                        2 => DecodedInsn::new2("cfc2", OReg(grt), IReg("vce")),
-                        _ => DecodedInsn::new2("cfc2?", OReg(grt), Imm8(rsx as u8)),
-                    },
-                    0x4 => DecodedInsn::new3("mtc2", IReg(grt), OReg(vrs), Imm8(rdx as u8 >> 1))
-                        .with_fmt(VREG2_FMT),
-                    0x6 => match rsx {
-                        0 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vco")),
-                        1 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vcc")),
-                        2 => DecodedInsn::new2("ctc2", OReg(grt), IReg("vce")),
-                        _ => DecodedInsn::new2("ctc2?", OReg(grt), Imm8(rsx as u8)),
-                    },
-                    _ => DecodedInsn::new1("cop2su?", Imm8(e)),
-                }
-            }
-        }
-        0x32 => {
-            let oploadstore = (opcode >> 11) & 0x1F;
-            let e = ((opcode >> 7) & 0xF) as u8;
-            let base = REG_NAMES[((opcode >> 21) & 0x1F) as usize];
-            let off = (opcode & 0x7F) as i32;
-            let off = ((off << 25) >> 25) as u16;
-
-            let vloadinsn_new = |name, off| {
-                DecodedInsn::new4(name, OReg(vrt), Imm8(e), Imm16(off), IReg(base))
-                    .with_fmt(VMEM_FMT)
-            };
-            match oploadstore {
-                0x00 => vloadinsn_new("lbv", off * 1),
-                0x01 => vloadinsn_new("lsv", off * 2),
-                0x02 => vloadinsn_new("llv", off * 4),
-                0x03 => vloadinsn_new("ldv", off * 8),
-                0x04 => vloadinsn_new("lqv", off * 16),
-                0x05 => vloadinsn_new("lrv", off * 16),
-                0x06 => vloadinsn_new("lpv", off * 8),
-                0x07 => vloadinsn_new("luv", off * 8),
-                0x08 => vloadinsn_new("lhv", off * 16),
-                0x09 => vloadinsn_new("lfv", off * 16),
-                0x0B => vloadinsn_new("ltv", off * 16),
-                _ => DecodedInsn::new1("lwc2", Imm32(oploadstore)),
-            }
-        }
-        0x3A => {
-            let oploadstore = (opcode >> 11) & 0x1F;
-            let e = ((opcode >> 7) & 0xF) as u8;
-            let base = REG_NAMES[((opcode >> 21) & 0x1F) as usize];
-            let off = (opcode & 0x7F) as u16;
+    match func {
+        0x00 => vmulinsn_new("vmulf"),
+        0x01 => vmulinsn_new("vmulu"),
+        0x04 => vmulinsn_new("vmudl"),
+        0x05 => vmulinsn_new("vmudm"),
+        0x06 => vmulinsn_new("vmudn"),
+        0x07 => vmulinsn_new("vmudh"),
+        0x08 => vmulinsn_new("vmacf"),
+        0x09 => vmulinsn_new("vmacu"),
+        0x0C => vmulinsn_new("vmadl"),
+        0x0D => vmulinsn_new("vmadm"),
+        0x0E => vmulinsn_new("vmadn"),
+        0x0F => vmulinsn_new("vmadh"),
+        0x10 => vreg3insn_new("vadd"),
+        0x11 => vreg3insn_new("vsub"),
+        0x13 => vreg3insn_new("vabs"),
+        0x14 => vreg3insn_new("vaddc"),
+        0x15 => vreg3insn_new("vsubc"),
+        0x17 => vreg3insn_new("vsubb"),
+        0x19 => vreg3insn_new("vsucb"),
+        // e=8 selects the high slice of the accumulator, e=10 the low one.
+        0x1D => match e {
+            8..=10 => DecodedInsn::new2("vsar", OReg(vrd), IReg(ACC_NAMES[2 - (e as usize - 8)])),
+            _ => DecodedInsn::new2("vsar?", OReg(vrd), Imm8(e)),
+        },
+        0x20 => vreg3insn_new("vlt"),
+        0x21 => vreg3insn_new("veq"),
+        0x22 => vreg3insn_new("vne"),
+        0x23 => vreg3insn_new("vge"),
+        0x24 => vreg3insn_new("vcl"),
+        0x25 => vreg3insn_new("vch"),
+        0x26 => vreg3insn_new("vcr"),
+        0x27 => vreg3insn_new("vmrg"),
+        0x28 => vreg3insn_new("vand"),
+        0x29 => vreg3insn_new("vnand"),
+        0x2A => vreg3insn_new("vor"),
+        0x2B => vreg3insn_new("vnor"),
+        0x2C => vreg3insn_new("vxor"),
+        0x2D => vreg3insn_new("vnxor"),
 
-            let vstoreinsn_new = |name, off| {
-                DecodedInsn::new4(name, IReg(vrt), Imm8(e), Imm16(off), IReg(base))
-                    .with_fmt(VMEM_FMT)
-            };
-            match oploadstore {
-                0x00 => vstoreinsn_new("sbv", off * 1),
-                0x01 => vstoreinsn_new("ssv", off * 2),
-                0x02 => vstoreinsn_new("slv", off * 4),
-                0x03 => vstoreinsn_new("sdv", off * 8),
-                0x04 => vstoreinsn_new("sqv", off * 16),
-                0x05 => vstoreinsn_new("srv", off * 16),
-                0x06 => vstoreinsn_new("spv", off * 8),
-                0x07 => vstoreinsn_new("suv", off * 8),
-                0x08 => vstoreinsn_new("shv", off * 16),
-                0x09 => vstoreinsn_new("sfv", off * 16),
-                0x0A => vstoreinsn_new("swv", off * 16),
-                0x0B => vstoreinsn_new("stv", off * 16),
-                _ => DecodedInsn::new1("swc2", Imm32(oploadstore)),
-            }
-        }
-        _ => DecodedInsn::new0("unkcop2?"),
+        0x30 => vreg2insn_new("vrcp"),
+        0x31 => vreg2insn_new("vrcpl"),
+        0x32 => vreg2insn_new("vrcph"),
+        0x33 => DecodedInsn::new4(
+            "vmov",
+            IOReg(vrd),
+            Imm8(vs as u8 & 0xF),
+            IReg(vrt),
+            Imm8(e),
+        )
+        .with_fmt(VMOV_FMT),
+        0x34 => vreg2insn_new("vsqr"),
+        0x35 => vreg2insn_new("vsqrl"),
+        0x36 => vreg2insn_new("vsqrh"),
+        0x37 => vreg2insn_new("vnop"),
+        _ => DecodedInsn::new1("cop2", Imm32(func)),
     }
 }
@@ -0,0 +1,292 @@
+/// A COP2 (vector unit) instruction, decoded from its 32-bit encoding.
+///
+/// Register fields are indices in either the GPR or the vector register file,
+/// depending on the variant. `e` is always the element specifier.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cop2Instr {
+    /// Computational opcode (VMULF, VADD, VRCP, ...), selected by `func`.
+    Compute {
+        func: u32,
+        vd: usize,
+        vs: usize,
+        vt: usize,
+        e: usize,
+    },
+    /// Move the 16-bit element `e` of `vs` into GPR `rt`.
+    Mfc2 { rt: usize, vs: usize, e: usize },
+    /// Move GPR `rt` into the 16-bit element `e` of `vs`.
+    Mtc2 { rt: usize, vs: usize, e: usize },
+    /// Move the control register `rd` (0=VCO, 1=VCC, 2=VCE) into GPR `rt`.
+    Cfc2 { rt: usize, rd: usize },
+    /// Move GPR `rt` into the control register `rd`.
+    Ctc2 { rt: usize, rd: usize },
+    /// LWC2 opcode. `offset` is sign-extended, but not yet scaled by the
+    /// access size of `op`.
+    Load {
+        op: u32,
+        vt: usize,
+        base: usize,
+        e: usize,
+        offset: i32,
+    },
+    /// SWC2 opcode. Fields are the same of `Load`.
+    Store {
+        op: u32,
+        vt: usize,
+        base: usize,
+        e: usize,
+        offset: i32,
+    },
+    /// Any other encoding, including reserved encodings of known opcodes.
+    Unknown(u32),
+}
+
+impl Cop2Instr {
+    /// Decode an opcode. Reserved encodings are decoded as `Unknown`, so that
+    /// they are not executed as if they were valid.
+    pub fn decode(op: u32) -> Cop2Instr {
+        use self::Cop2Instr::*;
+
+        let rt = ((op >> 16) & 0x1F) as usize;
+        let rs = ((op >> 11) & 0x1F) as usize;
+
+        match op >> 26 {
+            0x12 if op & (1 << 25) != 0 => {
+                Compute {
+                    func: op & 0x3F,
+                    vd: ((op >> 6) & 0x1F) as usize,
+                    vs: rs,
+                    vt: rt,
+                    e: ((op >> 21) & 0xF) as usize,
+                }
+            }
+            0x12 => {
+                let e = ((op >> 7) & 0xF) as usize;
+                match (op >> 21) & 0xF {
+                    0x0 => Mfc2 { rt, vs: rs, e },
+                    0x2 if rs <= 2 => Cfc2 { rt, rd: rs },
+                    0x4 => Mtc2 { rt, vs: rs, e },
+                    0x6 if rs <= 2 => Ctc2 { rt, rd: rs },
+                    _ => Unknown(op),
+                }
+            }
+            0x32 | 0x3A => {
+                let vop = (op >> 11) & 0x1F;
+                let base = ((op >> 21) & 0x1F) as usize;
+                let e = ((op >> 7) & 0xF) as usize;
+                let offset = ((op as i32) << 25) >> 25;
+                if op >> 26 == 0x32 {
+                    Load {
+                        op: vop,
+                        vt: rt,
+                        base,
+                        e,
+                        offset,
+                    }
+                } else {
+                    Store {
+                        op: vop,
+                        vt: rt,
+                        base,
+                        e,
+                        offset,
+                    }
+                }
+            }
+            _ => Unknown(op),
+        }
+    }
+}
+
+/// Class of a COP2 opcode: the `Cop2Instr` variant it decodes to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    Compute,
+    Mfc2,
+    Mtc2,
+    Cfc2,
+    Ctc2,
+    Load,
+    Store,
+}
+
+/// An operand of an opcode, encoded in `bits` bits starting at bit `shift`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OpField {
+    /// Name of the operand: vd, vs, vt, e, de (destination element of the
+    /// single-lane ops), rt, rd, base or offset.
+    pub name: &'static str,
+    pub shift: u32,
+    pub bits: u32,
+}
+
+impl OpField {
+    /// Extract the (unsigned) value of the field from an opcode.
+    pub fn extract(&self, op: u32) -> u32 {
+        (op >> self.shift) & ((1 << self.bits) - 1)
+    }
+}
+
+/// Encoding of an opcode, for tools (assemblers, validators...) that need
+/// a machine-readable description of the instruction set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OpInfo {
+    /// Mnemonic, as printed by the disassembler.
+    pub name: &'static str,
+    pub kind: OpKind,
+    /// An opcode is an instance of this one if `op & mask == value`.
+    pub mask: u32,
+    pub value: u32,
+    /// Operands that are not fixed by `mask`.
+    pub fields: &'static [OpField],
+    /// For loads and stores, the offset (sign-extended) is shifted left by
+    /// `scale` to compute the address; it is zero for the other opcodes.
+    pub scale: u32,
+}
+
+const fn field(name: &'static str, shift: u32, bits: u32) -> OpField {
+    OpField { name, shift, bits }
+}
+
+const VREG3: &[OpField] = &[
+    field("e", 21, 4),
+    field("vt", 16, 5),
+    field("vs", 11, 5),
+    field("vd", 6, 5),
+];
+// Single-lane ops (VRCP, VMOV, ...): the vs field selects the lane of vd.
+const VLANE: &[OpField] = &[
+    field("e", 21, 4),
+    field("vt", 16, 5),
+    field("de", 11, 5),
+    field("vd", 6, 5),
+];
+const VSAR: &[OpField] = &[field("e", 21, 4), field("vd", 6, 5)];
+const VMOVE: &[OpField] = &[field("rt", 16, 5), field("vs", 11, 5), field("e", 7, 4)];
+const VCTRL: &[OpField] = &[field("rt", 16, 5), field("rd", 11, 5)];
+const VMEM: &[OpField] = &[
+    field("base", 21, 5),
+    field("vt", 16, 5),
+    field("e", 7, 4),
+    field("offset", 0, 7),
+];
+
+const fn compute(name: &'static str, func: u32, fields: &'static [OpField]) -> OpInfo {
+    OpInfo {
+        name,
+        kind: OpKind::Compute,
+        mask: 0xFE00_003F,
+        value: 0x4A00_0000 | func,
+        fields,
+        scale: 0,
+    }
+}
+
+const fn mov(name: &'static str, kind: OpKind, sub: u32, fields: &'static [OpField]) -> OpInfo {
+    OpInfo {
+        name,
+        kind,
+        mask: 0xFFE0_0000,
+        value: 0x4800_0000 | (sub << 21),
+        fields,
+        scale: 0,
+    }
+}
+
+const fn load(name: &'static str, op: u32, scale: u32) -> OpInfo {
+    OpInfo {
+        name,
+        kind: OpKind::Load,
+        mask: 0xFC00_F800,
+        value: 0xC800_0000 | (op << 11),
+        fields: VMEM,
+        scale,
+    }
+}
+
+const fn store(name: &'static str, op: u32, scale: u32) -> OpInfo {
+    OpInfo {
+        name,
+        kind: OpKind::Store,
+        mask: 0xFC00_F800,
+        value: 0xE800_0000 | (op << 11),
+        fields: VMEM,
+        scale,
+    }
+}
+
+/// Encoding and operands of all the COP2 opcodes implemented by the RSP
+/// (computational opcodes, moves, loads and stores). Note that CFC2 and CTC2
+/// are only valid for rd 0-2 (VCO, VCC and VCE), which `mask` can't express.
+pub const OPCODE_INFO: &[OpInfo] = &[
+    compute("vmulf", 0x00, VREG3),
+    compute("vmulu", 0x01, VREG3),
+    compute("vmudl", 0x04, VREG3),
+    compute("vmudm", 0x05, VREG3),
+    compute("vmudn", 0x06, VREG3),
+    compute("vmudh", 0x07, VREG3),
+    compute("vmacf", 0x08, VREG3),
+    compute("vmacu", 0x09, VREG3),
+    compute("vmadl", 0x0C, VREG3),
+    compute("vmadm", 0x0D, VREG3),
+    compute("vmadn", 0x0E, VREG3),
+    compute("vmadh", 0x0F, VREG3),
+    compute("vadd", 0x10, VREG3),
+    compute("vsub", 0x11, VREG3),
+    compute("vabs", 0x13, VREG3),
+    compute("vaddc", 0x14, VREG3),
+    compute("vsubc", 0x15, VREG3),
+    compute("vsubb", 0x17, VREG3),
+    compute("vsucb", 0x19, VREG3),
+    compute("vsar", 0x1D, VSAR),
+    compute("vlt", 0x20, VREG3),
+    compute("veq", 0x21, VREG3),
+    compute("vne", 0x22, VREG3),
+    compute("vge", 0x23, VREG3),
+    compute("vcl", 0x24, VREG3),
+    compute("vch", 0x25, VREG3),
+    compute("vcr", 0x26, VREG3),
+    compute("vmrg", 0x27, VREG3),
+    compute("vand", 0x28, VREG3),
+    compute("vnand", 0x29, VREG3),
+    compute("vor", 0x2A, VREG3),
+    compute("vnor", 0x2B, VREG3),
+    compute("vxor", 0x2C, VREG3),
+    compute("vnxor", 0x2D, VREG3),
+    compute("vrcp", 0x30, VLANE),
+    compute("vrcpl", 0x31, VLANE),
+    compute("vrcph", 0x32, VLANE),
+    compute("vmov", 0x33, VLANE),
+    compute("vsqr", 0x34, VLANE),
+    compute("vsqrl", 0x35, VLANE),
+    compute("vsqrh", 0x36, VLANE),
+    compute("vnop", 0x37, &[]),
+    compute("vnull", 0x3F, &[]),
+    mov("mfc2", OpKind::Mfc2, 0x0, VMOVE),
+    mov("cfc2", OpKind::Cfc2, 0x2, VCTRL),
+    mov("mtc2", OpKind::Mtc2, 0x4, VMOVE),
+    mov("ctc2", OpKind::Ctc2, 0x6, VCTRL),
+    load("lbv", 0x00, 0),
+    load("lsv", 0x01, 1),
+    load("llv", 0x02, 2),
+    load("ldv", 0x03, 3),
+    load("lqv", 0x04, 4),
+    load("lrv", 0x05, 4),
+    load("lpv", 0x06, 3),
+    load("luv", 0x07, 3),
+    load("lhv", 0x08, 4),
+    load("lfv", 0x09, 4),
+    load("ltv", 0x0B, 4),
+    store("sbv", 0x00, 0),
+    store("ssv", 0x01, 1),
+    store("slv", 0x02, 2),
+    store("sdv", 0x03, 3),
+    store("sqv", 0x04, 4),
+    store("srv", 0x05, 4),
+    store("spv", 0x06, 3),
+    store("suv", 0x07, 3),
+    store("shv", 0x08, 4),
+    store("sfv", 0x09, 4),
+    store("swv", 0x0A, 4),
+    store("stv", 0x0B, 4),
+];
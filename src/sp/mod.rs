@@ -1,13 +1,31 @@
 mod sp;
 pub use self::sp::*;
+#[cfg(feature = "cop2")]
 mod decode;
+#[cfg(feature = "cop2")]
+pub use self::decode::Cop2Reg;
+mod instr;
+pub use self::instr::{Cop2Instr, OpField, OpInfo, OpKind, OPCODE_INFO};
 
 /// NOTE: please do not add tests here. To test ops, add them at the integration level
 /// (tests/spvector.rs) so that they can more easily cover all the different implementations
-/// (including JIT).
-mod accumulator;
+/// (including JIT). The only exception are internal checks that no opcode can reach (e.g. the
+/// debug assertion on out-of-range lanes in cop2.rs).
 mod cop0;
+
+// Vector unit (COP2), optional via the "cop2" feature. The computation of
+// its ops lives in the no_std rspvu crate.
+#[cfg(feature = "cop2")]
 mod cop2;
-mod vclip;
-mod vmul;
-mod vrcp;
+#[cfg(feature = "cop2")]
+pub use self::cop2::{SpCop2, SpCop2Context, StateDiff};
+#[cfg(feature = "cop2")]
+mod oplog;
+#[cfg(feature = "cop2")]
+pub use self::oplog::{OpLog, OpLogEntry};
+#[cfg(feature = "cop2")]
+mod stats;
+#[cfg(feature = "cop2")]
+pub use self::stats::OpStats;
+#[cfg(feature = "cop2")]
+mod timing;
@@ -0,0 +1,133 @@
+use super::cop2::{SpCop2, SpCop2Context};
+use super::instr::Cop2Instr;
+use super::sp::DMEM_SIZE;
+
+use crate::errors::*;
+use emu::bus::be::Bus;
+use emu::dbg::Tracer;
+use mips64::{Cop, CpuContext};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+/// An opcode executed by the vector unit, together with the inputs it reads
+/// from outside of COP2, so that it can be executed again in isolation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OpLogEntry {
+    /// Computational opcode or move. `gpr` is the value of GPR rt for MTC2
+    /// and CTC2, and zero otherwise.
+    Op { opcode: u32, gpr: u64 },
+    /// LWC2 opcode. `base` is the value of the base GPR, and `data` the DMEM
+    /// bytes read by the load, starting at `addr` (wrapping around the end
+    /// of DMEM).
+    Load {
+        opcode: u32,
+        base: u32,
+        addr: usize,
+        data: Vec<u8>,
+    },
+    /// SWC2 opcode. `base` is the value of the base GPR.
+    Store { opcode: u32, base: u32 },
+}
+
+/// A log of the opcodes executed by the vector unit, recorded with
+/// `SpCop2::start_recording`. It contains the COP2 state at the start and at
+/// the end of the recording, so that it can be replayed on a different
+/// instance (or after a reset) and checked to reach the same final state.
+/// Logs are saved as JSON, to be attached to bug reports.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OpLog {
+    initial: SpCop2Context,
+    entries: Vec<OpLogEntry>,
+    last: Option<SpCop2Context>,
+}
+
+impl OpLog {
+    pub(crate) fn new(initial: SpCop2Context) -> OpLog {
+        OpLog {
+            initial,
+            entries: Vec::new(),
+            last: None,
+        }
+    }
+
+    pub(crate) fn push(&mut self, entry: OpLogEntry) {
+        self.entries.push(entry);
+    }
+
+    pub(crate) fn finish(&mut self, last: SpCop2Context) {
+        self.last = Some(last);
+    }
+
+    pub fn entries(&self) -> &[OpLogEntry] {
+        &self.entries
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        serde_json::to_writer(File::create(path)?, self)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<OpLog> {
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Replay the log on the specified vector unit: its state is set to the
+    /// one at the start of the recording, DMEM is filled with the data read by
+    /// the recorded loads, and all opcodes are executed again. The data goes
+    /// to the private DMEM of a standalone unit, and otherwise through the bus
+    /// (which must map DMEM at address 0). Fails if the final state doesn't
+    /// match the recorded one.
+    pub fn replay(&self, cop2: &mut SpCop2, bus: &mut Bus) -> Result<()> {
+        let last = match self.last {
+            Some(ref last) => last,
+            None => bail!("opcode log is incomplete: recording was not stopped"),
+        };
+
+        let t = Tracer::null();
+        let mut ctx = CpuContext::default();
+        cop2.restore(self.initial);
+        for (n, entry) in self.entries.iter().enumerate() {
+            let res = match *entry {
+                OpLogEntry::Op { opcode, gpr } => {
+                    let insn = Cop2Instr::decode(opcode);
+                    if let Cop2Instr::Mtc2 { rt, .. } | Cop2Instr::Ctc2 { rt, .. } = insn {
+                        ctx.regs[rt] = gpr;
+                    }
+                    cop2.op(&mut ctx, opcode, &t)
+                }
+                OpLogEntry::Load {
+                    opcode,
+                    base,
+                    addr,
+                    ref data,
+                } => {
+                    for (i, &b) in data.iter().enumerate() {
+                        let a = (addr + i) & (DMEM_SIZE - 1);
+                        match cop2.load_dmem() {
+                            Some(dmem) => dmem[a] = b,
+                            None => bus.write::<u8>(a as u32, b),
+                        }
+                    }
+                    ctx.regs[((opcode >> 21) & 0x1F) as usize] = base as u64;
+                    cop2.lwc(opcode, &mut ctx, bus, &t)
+                }
+                OpLogEntry::Store { opcode, base } => {
+                    ctx.regs[((opcode >> 21) & 0x1F) as usize] = base as u64;
+                    cop2.swc(opcode, &mut ctx, bus, &t)
+                }
+            };
+            if res.is_err() {
+                bail!("replay stopped at opcode {}", n);
+            }
+        }
+
+        if let Some(diff) = cop2.snapshot().diff(last) {
+            bail!(
+                "COP2 state after replay does not match the recording: {}",
+                diff
+            );
+        }
+        Ok(())
+    }
+}
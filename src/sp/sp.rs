@@ -1,9 +1,12 @@
 use super::super::mi::{IrqMask, Mi};
 use super::super::r4300::R4300;
 use super::cop0::SpCop0;
+#[cfg(feature = "cop2")]
 use super::cop2::SpCop2;
 use crate::errors::*;
+use byteorder::{BigEndian, ByteOrder};
 use emu::bus::be::{Bus, Device, Mem, Reg32};
+use emu::dbg;
 use emu::int::Numerics;
 use emu::memint::MemInt;
 use mips64;
@@ -31,6 +34,30 @@ bitflags! {
     }
 }
 
+#[cfg(feature = "cop2")]
+type VectorUnit = SpCop2;
+#[cfg(not(feature = "cop2"))]
+type VectorUnit = mips64::CopNull;
+
+#[cfg(feature = "cop2")]
+fn new_vu(logger: slog::Logger) -> Result<VectorUnit> {
+    SpCop2::new("RSP-VU", logger)
+}
+#[cfg(not(feature = "cop2"))]
+fn new_vu(_logger: slog::Logger) -> Result<VectorUnit> {
+    Ok(mips64::CopNull {})
+}
+
+// Cycles lost by next when executed right after prev (see Sp::run_cycles).
+#[cfg(feature = "cop2")]
+fn stall_cycles(prev: u32, next: u32) -> u64 {
+    SpCop2::stall_cycles(prev, next).into()
+}
+#[cfg(not(feature = "cop2"))]
+fn stall_cycles(_prev: u32, _next: u32) -> u64 {
+    0
+}
+
 pub struct RSPCPUConfig;
 pub struct ArchRSP;
 
@@ -53,7 +80,7 @@ impl mips64::Config for RSPCPUConfig {
     type Arch = ArchRSP; // Specific arch based on MIPS1 but with less ops
     type Cop0 = SpCop0;
     type Cop1 = mips64::CopNull;
-    type Cop2 = SpCop2;
+    type Cop2 = VectorUnit;
     type Cop3 = mips64::CopNull;
     fn pc_mask(pc: u32) -> u32 {
         (pc & 0xFFF) | 0x1000
@@ -80,7 +107,7 @@ impl RSPCPU {
                 (
                     SpCop0::new("RSP-COP0", logger.new(o!()))?,
                     mips64::CopNull {},
-                    SpCop2::new("RSP-VU", logger.new(o!()))?,
+                    new_vu(logger.new(o!()))?,
                     mips64::CopNull {},
                 ),
             ),
@@ -112,6 +139,12 @@ impl DerefMut for RSPCPU {
     }
 }
 
+// Size of DMEM as seen by the RSP, and size of its backing buffer, which is
+// a little bit larger (see Sp::dmem). Keep in sync with the mem attribute.
+pub(crate) const DMEM_SIZE: usize = 0x1000;
+#[cfg(feature = "cop2")]
+pub(crate) const DMEM_ALLOC_SIZE: usize = DMEM_SIZE + 0x10;
+
 #[derive(DeviceBE)]
 pub struct Sp {
     // SP DMEM (4K)
@@ -151,9 +184,35 @@ pub struct Sp {
     #[reg(bank = 1, offset = 0x1C, init = 0x0, rwmask = 0x1, rcb)]
     reg_semaphore: Reg32,
 
+    // Last opcode executed by run_cycles, which can stall the next one.
+    last_op: u32,
+
     logger: slog::Logger,
 }
 
+/// Why Sp::run_cycles returned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The RSP executed a BREAK (or the vector unit halted it because of an
+    /// error, see Cop::take_error).
+    Break,
+    /// The RSP is halted, but not because of a BREAK (eg: it was never
+    /// started, or the host halted it).
+    Halt,
+    /// The next instruction does not fit in the cycle budget.
+    Budget,
+}
+
+/// Result of Sp::run_cycles.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CycleRun {
+    /// Number of instructions executed.
+    pub insns: usize,
+    /// Cycles taken by those instructions, including stalls.
+    pub cycles: u64,
+    pub reason: StopReason,
+}
+
 impl Sp {
     pub fn new(logger: slog::Logger) -> Result<Box<Sp>> {
         // Create the RSP internal MIPS CPU and its associated bus
@@ -172,9 +231,111 @@ impl Sp {
             reg_rsp_pc: Reg32::default(),
             reg_dma_full: Reg32::default(),
             reg_semaphore: Reg32::default(),
+            last_op: 0,
         }))
     }
 
+    // Access DMEM on behalf of the vector unit. Load/store opcodes index
+    // it with 12-bit addresses and use the slack past the end to handle
+    // wrap-around, so the backing buffer must be DMEM_ALLOC_SIZE bytes.
+    #[cfg(feature = "cop2")]
+    pub(crate) fn vu_dmem(&mut self) -> &mut [u8] {
+        assert!(
+            self.dmem.len() >= DMEM_ALLOC_SIZE,
+            "SP DMEM too small for the vector unit: {} bytes, {} required",
+            self.dmem.len(),
+            DMEM_ALLOC_SIZE
+        );
+        &mut self.dmem
+    }
+
+    /// Fill DMEM as a RDRAM -> RSP DMA would do with the same parameters:
+    /// `count` rows of `width` bytes are read from `src`, skipping `skip`
+    /// bytes after each row, and written back-to-back into DMEM starting at
+    /// `addr`. The DMA registers and RDRAM are not involved, but the transfer
+    /// follows the same rules: `addr` is aligned to 8 bytes, `width` is rounded
+    /// up to a multiple of 8 bytes, and addresses wrap at the end of DMEM.
+    ///
+    /// Panics if `src` is shorter than the transfer.
+    pub fn dma_load_dmem(
+        &mut self,
+        addr: u32,
+        src: &[u8],
+        width: usize,
+        count: usize,
+        skip: usize,
+    ) {
+        let width = ((width.max(1) - 1) | 0x7) + 1;
+        let mut dst = (addr & !0x7) as usize;
+        for i in 0..count {
+            for &b in &src[i * (width + skip)..][..width] {
+                self.dmem[dst & (DMEM_SIZE - 1)] = b;
+                dst += 1;
+            }
+        }
+    }
+
+    /// Return a copy of `len` bytes of DMEM starting at `addr`, with each
+    /// `U`-sized word laid out in the byte order `O`. DMEM is big-endian, so
+    /// `BigEndian` returns the bytes unchanged, while `LittleEndian` swaps the
+    /// bytes within each word. This is meant for tools inspecting DMEM: the
+    /// emulated DMEM is not affected. Addresses wrap at the end of DMEM.
+    ///
+    /// Panics if `len` is not a multiple of the word size.
+    pub fn dmem_view<U: MemInt, O: ByteOrder>(&self, addr: usize, len: usize) -> Vec<u8> {
+        assert!(len % U::SIZE == 0, "DMEM view length not a multiple of word size");
+        let mut view = vec![0u8; len];
+        let mut word = [0u8; 8];
+        for (i, dst) in view.chunks_mut(U::SIZE).enumerate() {
+            for (j, b) in word[..U::SIZE].iter_mut().enumerate() {
+                *b = self.dmem[(addr + i * U::SIZE + j) & (DMEM_SIZE - 1)];
+            }
+            U::endian_write_to::<O>(dst, U::endian_read_from::<BigEndian>(&word[..U::SIZE]));
+        }
+        view
+    }
+
+    /// Run the RSP for at most `budget` cycles, one instruction at a time,
+    /// until it halts. Each instruction takes one cycle, plus the stall
+    /// caused by a dependency on the result of the previous one (see
+    /// SpCop2::stall_cycles), even across calls. Execution stops before an
+    /// instruction that does not fit in the remaining budget, so fewer than
+    /// `budget` cycles may be used.
+    ///
+    /// Stalls are only accounted in the result: the RSP clock still
+    /// advances by one cycle per instruction.
+    pub fn run_cycles(&mut self, budget: u64, t: &dbg::Tracer) -> dbg::Result<CycleRun> {
+        let cpu = RSPCPU::get_mut();
+        let mut run = CycleRun {
+            insns: 0,
+            cycles: 0,
+            reason: StopReason::Budget,
+        };
+        loop {
+            let status = self.get_status();
+            if status.contains(StatusFlags::HALT) {
+                run.reason = if status.contains(StatusFlags::BROKE) {
+                    StopReason::Break
+                } else {
+                    StopReason::Halt
+                };
+                return Ok(run);
+            }
+
+            let pc = cpu.ctx().get_pc() as usize & 0xFFC;
+            let op = BigEndian::read_u32(&self.imem[pc..pc + 4]);
+            let cost = 1 + stall_cycles(self.last_op, op);
+            if run.cycles + cost > budget {
+                return Ok(run);
+            }
+            let clock = cpu.ctx().clock;
+            cpu.run(clock + 1, t)?;
+            self.last_op = op;
+            run.insns += 1;
+            run.cycles += cost;
+        }
+    }
+
     pub(crate) fn get_status(&self) -> StatusFlags {
         StatusFlags::from_bits(self.reg_status.get()).unwrap()
     }
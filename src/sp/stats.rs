@@ -0,0 +1,55 @@
+/// Histogram of the opcodes executed by the vector unit, collected when
+/// enabled with `SpCop2::enable_stats`. This is meant to profile microcode:
+/// computational opcodes are counted by their func field, and LWC2/SWC2
+/// opcodes by their opcode field (LBV=0, LSV=1, ...). Moves (MFC2, MTC2,
+/// CFC2, CTC2) are not counted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpStats {
+    compute: [u64; 64],
+    load: [u64; 32],
+    store: [u64; 32],
+}
+
+impl Default for OpStats {
+    fn default() -> OpStats {
+        OpStats {
+            compute: [0; 64],
+            load: [0; 32],
+            store: [0; 32],
+        }
+    }
+}
+
+impl OpStats {
+    pub(crate) fn count_compute(&mut self, func: u32) {
+        self.compute[func as usize & 0x3F] += 1;
+    }
+
+    pub(crate) fn count_load(&mut self, op: u32) {
+        self.load[op as usize & 0x1F] += 1;
+    }
+
+    pub(crate) fn count_store(&mut self, op: u32) {
+        self.store[op as usize & 0x1F] += 1;
+    }
+
+    /// Executions of each computational opcode, indexed by func.
+    pub fn compute(&self) -> &[u64; 64] {
+        &self.compute
+    }
+
+    /// Executions of each LWC2 opcode, indexed by opcode.
+    pub fn load(&self) -> &[u64; 32] {
+        &self.load
+    }
+
+    /// Executions of each SWC2 opcode, indexed by opcode.
+    pub fn store(&self) -> &[u64; 32] {
+        &self.store
+    }
+
+    /// Total number of counted opcodes.
+    pub fn total(&self) -> u64 {
+        self.compute.iter().chain(&self.load).chain(&self.store).sum()
+    }
+}
@@ -0,0 +1,88 @@
+use super::cop2::SpCop2;
+use super::instr::Cop2Instr;
+
+// Resources tracked by the hazard model, as a bitmask of `Cop::reg` indices.
+// The divider state (both DivIn and DivOut) uses the REG_DIV_OUT bit: it is
+// how VRCP/VRSQ pass their result to VRCPH/VRSQH.
+const DIV: u64 = 1 << SpCop2::REG_DIV_OUT;
+const FLAGS: u64 = (1 << SpCop2::REG_VCO) | (1 << SpCop2::REG_VCC) | (1 << SpCop2::REG_VCE);
+const ACC: u64 =
+    (1 << SpCop2::REG_ACCUM_LO) | (1 << SpCop2::REG_ACCUM_MD) | (1 << SpCop2::REG_ACCUM_HI);
+
+// Latencies of the vector unit, in cycles. An op with latency 1 produces its
+// result in time for the next op; longer latencies stall dependent ops.
+const MUL_LATENCY: u32 = 3;
+const DIV_LATENCY: u32 = 4;
+
+fn vreg(r: usize) -> u64 {
+    1 << r
+}
+
+// Registers read and written by an opcode, as resource bitmasks.
+fn deps(op: u32) -> (u64, u64) {
+    match Cop2Instr::decode(op) {
+        Cop2Instr::Compute {
+            func, vd, vs, vt, ..
+        } => {
+            let (vs, vt, vd) = (vreg(vs), vreg(vt), vreg(vd));
+            match func {
+                // VMUL*/VMUD* overwrite the accumulator, VMAC*/VMAD* add to it.
+                0x00..=0x07 => (vs | vt, vd | ACC),
+                0x08..=0x0F => (vs | vt | ACC, vd | ACC),
+                0x1D => (ACC, vd),
+                0x10..=0x27 => (vs | vt | FLAGS, vd | ACC | FLAGS),
+                0x28..=0x2F => (vs | vt, vd | ACC),
+                // VMOV doesn't use the divider; VNOP, VNULL and the reserved
+                // opcodes among them (0x37-0x3F) access nothing.
+                0x33 => (vt, vd | ACC),
+                0x30..=0x36 => (vt | DIV, vd | ACC | DIV),
+                _ => (0, 0),
+            }
+        }
+        Cop2Instr::Mfc2 { vs, .. } => (vreg(vs), 0),
+        Cop2Instr::Mtc2 { vs, .. } => (0, vreg(vs)),
+        Cop2Instr::Cfc2 { .. } => (FLAGS, 0),
+        Cop2Instr::Ctc2 { .. } => (0, FLAGS),
+        // LTV/STV access a group of 8 registers.
+        Cop2Instr::Load { op: 0x0B, vt, .. } => (0, 0xFF << (vt & !7)),
+        Cop2Instr::Store { op: 0x0B, vt, .. } => (0xFF << (vt & !7), 0),
+        Cop2Instr::Load { vt, .. } => (0, vreg(vt)),
+        Cop2Instr::Store { vt, .. } => (vreg(vt), 0),
+        Cop2Instr::Unknown(_) => (0, 0),
+    }
+}
+
+impl SpCop2 {
+    /// Latency of a COP2 opcode in cycles: the number of cycles after which
+    /// its results can be used by another opcode. Multiplications and the
+    /// divider ops (VRCP, VRSQ and their variants) take longer than the other
+    /// ops, which complete in a single cycle.
+    ///
+    /// This is a model of the pipeline meant for a scheduler that co-issues
+    /// scalar and vector opcodes; execution itself is not affected.
+    pub fn latency(op: u32) -> u32 {
+        match Cop2Instr::decode(op) {
+            Cop2Instr::Compute { func, .. } => match func {
+                0x00..=0x0F => MUL_LATENCY,
+                0x33 => 1,
+                0x30..=0x36 => DIV_LATENCY,
+                _ => 1,
+            },
+            _ => 1,
+        }
+    }
+
+    /// Number of cycles `next` stalls when issued right after `prev`: zero if
+    /// it does not depend on any result of `prev` (a vector register, the
+    /// flags, the accumulator or the divider state), otherwise the part of
+    /// the latency of `prev` not yet elapsed.
+    pub fn stall_cycles(prev: u32, next: u32) -> u32 {
+        let (_, written) = deps(prev);
+        let (read, _) = deps(next);
+        if written & read != 0 {
+            SpCop2::latency(prev) - 1
+        } else {
+            0
+        }
+    }
+}
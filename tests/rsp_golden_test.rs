@@ -1,3 +1,5 @@
+// Vector unit tests: these need the "cop2" feature.
+#![cfg(feature = "cop2")]
 #![feature(pin)]
 
 #[macro_use]
@@ -0,0 +1,2913 @@
+// Vector unit tests: these need the "cop2" feature.
+#![cfg(feature = "cop2")]
+#![feature(pin)]
+#![feature(test)]
+
+#[macro_use]
+extern crate slog;
+
+extern crate byteorder;
+extern crate emu;
+extern crate mips64;
+extern crate r64emu;
+extern crate test;
+
+use byteorder::{BigEndian, LittleEndian};
+use emu::bus::be::{Bus, Device, Mem, MemFlags};
+use emu::dbg::{Debugger, TraceEvent, Tracer, WatchpointCondition, WatchpointType};
+use mips64::{Cop, CopError, CpuContext};
+use r64emu::dp::Dp;
+use r64emu::r4300::R4300;
+use r64emu::sp::{
+    Cop2Instr, Cop2Reg, CycleRun, OpInfo, OpKind, OpLog, OpLogEntry, Sp, SpCop2, StateDiff,
+    StopReason, OPCODE_INFO, RSPCPU,
+};
+use slog::{Discard, Drain, Level, Never, OwnedKVList, Record, KV};
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use test::Bencher;
+
+#[path = "../examples/rsp_step.rs"]
+mod rsp_step;
+
+fn make_sp(logger: slog::Logger) {
+    R4300::new(logger.new(o!())).register();
+    Dp::new(logger.new(o!())).register();
+    Sp::new(logger.new(o!())).unwrap().register();
+
+    // Simplified bus mapping for R4300: just SP registers.
+    {
+        let bus = &mut R4300::get_mut().bus;
+        bus.map_device(0x0400_0000, Sp::get(), 0).unwrap();
+        bus.map_device(0x0404_0000, Sp::get(), 1).unwrap();
+        bus.map_device(0x0408_0000, Sp::get(), 2).unwrap();
+    }
+    // Standard bus mapping for RSP.
+    RSPCPU::get_mut().map_bus().unwrap();
+}
+
+const SP_STATUS: u32 = 0x0404_0010;
+const SP_STATUS_HALT: u32 = 1 << 0;
+const SP_STATUS_BROKE: u32 = 1 << 1;
+const SP_PC: u32 = 0x0408_0000;
+
+// Encoders for the COP2 opcodes, so that tests can be written in a
+// assembly-like fashion.
+fn vu(func: u32, vd: usize, vs: usize, vt: usize, e: usize) -> u32 {
+    (0x12 << 26)
+        | (1 << 25)
+        | ((e as u32) << 21)
+        | ((vt as u32) << 16)
+        | ((vs as u32) << 11)
+        | ((vd as u32) << 6)
+        | func
+}
+
+fn cop2_move(op: u32, rt: usize, rd: usize, e: usize) -> u32 {
+    (0x12 << 26) | (op << 21) | ((rt as u32) << 16) | ((rd as u32) << 11) | ((e as u32) << 7)
+}
+
+fn lwc2(op: u32, vt: usize, e: usize, offset: i32, base: usize) -> u32 {
+    (0x32 << 26)
+        | ((base as u32) << 21)
+        | ((vt as u32) << 16)
+        | (op << 11)
+        | ((e as u32) << 7)
+        | (offset as u32 & 0x7F)
+}
+
+fn swc2(op: u32, vt: usize, e: usize, offset: i32, base: usize) -> u32 {
+    (0x3A << 26)
+        | ((base as u32) << 21)
+        | ((vt as u32) << 16)
+        | (op << 11)
+        | ((e as u32) << 7)
+        | (offset as u32 & 0x7F)
+}
+
+// Build a vector register value from its eight lanes (lane 0 first).
+fn lanes(l: [u16; 8]) -> u128 {
+    l.iter().fold(0, |acc, &x| (acc << 16) | x as u128)
+}
+
+// Xorshift64 generator for randomized tests. It is seeded with a constant, so
+// that failures are reproducible.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            *b = self.next() as u8;
+        }
+    }
+}
+
+const VADD: u32 = 0x10;
+const VMULF: u32 = 0x00;
+const VMULU: u32 = 0x01;
+const VMACF: u32 = 0x08;
+const VMUDL: u32 = 0x04;
+const VMADL: u32 = 0x0C;
+const VMADM: u32 = 0x0D;
+const VMADN: u32 = 0x0E;
+const VMUDH: u32 = 0x07;
+const VMADH: u32 = 0x0F;
+const VSUB: u32 = 0x11;
+const VADDC: u32 = 0x14;
+const VSUBC: u32 = 0x15;
+const VSAR: u32 = 0x1D;
+const VLT: u32 = 0x20;
+const VGE: u32 = 0x23;
+const VEQ: u32 = 0x21;
+const VNE: u32 = 0x22;
+const VCH: u32 = 0x25;
+const VMRG: u32 = 0x27;
+const VAND: u32 = 0x28;
+const VOR: u32 = 0x2A;
+const VXOR: u32 = 0x2C;
+const VRCP: u32 = 0x30;
+const VRCPH: u32 = 0x32;
+const VRSQ: u32 = 0x34;
+const VNULL: u32 = 0x3F;
+
+const MFC2: u32 = 0x0;
+const CFC2: u32 = 0x2;
+const MTC2: u32 = 0x4;
+const CTC2: u32 = 0x6;
+
+const LQV: u32 = 0x04;
+const LRV: u32 = 0x05;
+const LPV: u32 = 0x06;
+const LUV: u32 = 0x07;
+const LFV: u32 = 0x09;
+const LTV: u32 = 0x0B;
+const SSV: u32 = 0x01;
+const SDV: u32 = 0x03;
+const SQV: u32 = 0x04;
+const SRV: u32 = 0x05;
+const SFV: u32 = 0x09;
+const STV: u32 = 0x0B;
+
+// A log record captured by CaptureDrain, with its key/value pairs (both of
+// the record and of the logger) already formatted as strings.
+#[derive(Clone)]
+struct Captured {
+    level: Level,
+    msg: String,
+    kv: Vec<(String, String)>,
+}
+
+impl Captured {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.kv
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct KvCollector(Vec<(String, String)>);
+
+impl slog::Serializer for KvCollector {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), fmt::format(*val)));
+        Ok(())
+    }
+}
+
+// A drain that stores all records in memory, so that tests can inspect them.
+struct CaptureDrain(Arc<Mutex<Vec<Captured>>>);
+
+impl CaptureDrain {
+    // Create a logger that captures all its records, and the handle to query
+    // them.
+    fn logger() -> (slog::Logger, CaptureDrain) {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let logger = slog::Logger::root(CaptureDrain(records.clone()), o!());
+        (logger, CaptureDrain(records))
+    }
+
+    // Return the records captured so far with the specified message, in the
+    // order they were logged.
+    fn find(&self, msg: &str) -> Vec<Captured> {
+        let records = self.0.lock().unwrap();
+        records.iter().filter(|r| r.msg == msg).cloned().collect()
+    }
+}
+
+impl Drain for CaptureDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), Never> {
+        let mut kv = KvCollector(Vec::new());
+        record.kv().serialize(record, &mut kv).unwrap();
+        values.serialize(record, &mut kv).unwrap();
+        self.0.lock().unwrap().push(Captured {
+            level: record.level(),
+            msg: fmt::format(*record.msg()),
+            kv: kv.0,
+        });
+        Ok(())
+    }
+}
+
+struct TestRsp {
+    ctx: CpuContext,
+}
+
+impl TestRsp {
+    fn new() -> Self {
+        Self::with_logger(slog::Logger::root(Discard, o!()))
+    }
+
+    fn with_logger(logger: slog::Logger) -> Self {
+        make_sp(logger);
+        TestRsp {
+            ctx: CpuContext::default(),
+        }
+    }
+
+    fn run(&mut self, ops: &[u32]) {
+        let cpu = &mut **RSPCPU::get_mut();
+        let t = Tracer::null();
+        for &op in ops {
+            match op >> 26 {
+                0x12 => cpu.cop2.op(&mut self.ctx, op, &t).unwrap(),
+                0x32 => cpu.cop2.lwc(op, &mut self.ctx, &cpu.bus, &t).unwrap(),
+                0x3A => cpu.cop2.swc(op, &mut self.ctx, &mut cpu.bus, &t).unwrap(),
+                _ => panic!("unsupported opcode in test: {:x}", op),
+            }
+        }
+    }
+
+    fn status(&self) -> u32 {
+        R4300::get().bus.read::<u32>(SP_STATUS)
+    }
+
+    fn start(&mut self) {
+        R4300::get_mut().bus.write::<u32>(SP_STATUS, (1 << 0) | (1 << 2)); // clear HALT and BROKE
+    }
+
+    fn vreg(&self, idx: usize) -> u128 {
+        RSPCPU::get().cop2.reg(&self.ctx, idx)
+    }
+
+    fn set_vreg(&mut self, idx: usize, val: u128) {
+        RSPCPU::get_mut().cop2.set_reg(&mut self.ctx, idx, val);
+    }
+
+    fn dmem(&self) -> &'static mut [u8] {
+        &mut Sp::get_mut().dmem[..0x1000]
+    }
+}
+
+#[test]
+fn lrv_srv_roundtrip() {
+    let mut rsp = TestRsp::new();
+    let v1: u128 = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+    rsp.set_vreg(1, v1);
+    rsp.ctx.regs[1] = 0x13;
+
+    // Unaligned store and reload of a full register, crossing the
+    // quadword boundary at 0x20.
+    rsp.run(&[
+        swc2(SQV, 1, 0, 0, 1),
+        swc2(SRV, 1, 0, 1, 1),
+        lwc2(LQV, 2, 0, 0, 1),
+        lwc2(LRV, 2, 0, 1, 1),
+    ]);
+    assert_eq!(&rsp.dmem()[0x13..0x23], &v1.to_be_bytes()[..]);
+    assert_eq!(rsp.vreg(2), v1);
+}
+
+#[test]
+fn sqv_srv_partial_quadwords() {
+    let mut rsp = TestRsp::new();
+    let v1: u128 = 0x8011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+    let bytes = v1.to_be_bytes();
+    rsp.set_vreg(1, v1);
+
+    // At every alignment k, SQV writes the bytes from k to the end of the
+    // quadword and SRV the k bytes before it (none for k=0), rotated by the
+    // element; the other bytes are left untouched.
+    for &e in &[0, 3, 15] {
+        for k in 0..16 {
+            for b in rsp.dmem()[0x100..0x120].iter_mut() {
+                *b = 0x5A;
+            }
+            rsp.ctx.regs[1] = 0x100 + k as u64;
+            rsp.run(&[swc2(SQV, 1, e, 0, 1), swc2(SRV, 1, e, 1, 1)]);
+            for i in 0..16 {
+                let sqv = if i >= k { bytes[(i - k + e) & 15] } else { 0x5A };
+                let srv = if i < k { bytes[(i + 16 - k + e) & 15] } else { 0x5A };
+                assert_eq!(rsp.dmem()[0x100 + i], sqv, "SQV e={} k={} i={}", e, k, i);
+                assert_eq!(rsp.dmem()[0x110 + i], srv, "SRV e={} k={} i={}", e, k, i);
+            }
+
+            // With element 0, the two halves reload into the register.
+            if e == 0 {
+                rsp.set_vreg(2, 0);
+                rsp.run(&[lwc2(LQV, 2, 0, 0, 1), lwc2(LRV, 2, 0, 1, 1)]);
+                assert_eq!(rsp.vreg(2), v1, "k={}", k);
+            }
+        }
+    }
+}
+
+#[test]
+fn lqv_element_and_length() {
+    let mut rsp = TestRsp::new();
+    for i in 0..32 {
+        rsp.dmem()[0x100 + i] = 0x10 + i as u8;
+    }
+    let fill: u128 = !0;
+    rsp.ctx.regs[1] = 0x100;
+    rsp.ctx.regs[2] = 0x108;
+    rsp.ctx.regs[3] = 0x10C;
+
+    rsp.set_vreg(2, fill);
+    rsp.run(&[lwc2(LQV, 2, 0, 0, 1)]);
+    assert_eq!(rsp.vreg(2), 0x1011_1213_1415_1617_1819_1A1B_1C1D_1E1F);
+
+    // Element 8: only the first half of the quadword fits in the register.
+    rsp.set_vreg(2, fill);
+    rsp.run(&[lwc2(LQV, 2, 8, 0, 1)]);
+    assert_eq!(rsp.vreg(2), 0xFFFF_FFFF_FFFF_FFFF_1011_1213_1415_1617);
+
+    // Unaligned EA: loading stops at the end of the quadword.
+    rsp.set_vreg(2, fill);
+    rsp.run(&[lwc2(LQV, 2, 0, 0, 2)]);
+    assert_eq!(rsp.vreg(2), 0x1819_1A1B_1C1D_1E1F_FFFF_FFFF_FFFF_FFFF);
+
+    rsp.set_vreg(2, fill);
+    rsp.run(&[lwc2(LQV, 2, 4, 0, 3)]);
+    assert_eq!(rsp.vreg(2), 0xFFFF_FFFF_1C1D_1E1F_FFFF_FFFF_FFFF_FFFF);
+}
+
+#[test]
+fn lrv_out_of_register() {
+    let mut rsp = TestRsp::new();
+    for (i, b) in rsp.dmem()[0x30..0x40].iter_mut().enumerate() {
+        *b = 0x80 + i as u8;
+    }
+    let v3: u128 = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+    rsp.set_vreg(3, v3);
+
+    // Aligned address: nothing is loaded.
+    rsp.ctx.regs[1] = 0x30;
+    rsp.run(&[lwc2(LRV, 3, 0, 0, 1)]);
+    assert_eq!(rsp.vreg(3), v3);
+
+    // Element pushes all bytes past the end of the register.
+    rsp.ctx.regs[1] = 0x33;
+    rsp.run(&[lwc2(LRV, 3, 13, 0, 1)]);
+    assert_eq!(rsp.vreg(3), v3);
+
+    // Element moves the loaded bytes forward; the last one is dropped.
+    rsp.run(&[lwc2(LRV, 3, 1, 0, 1)]);
+    assert_eq!(rsp.vreg(3), 0x0011_2233_4455_6677_8899_AABB_CCDD_8081);
+
+    rsp.run(&[lwc2(LRV, 3, 0, 0, 1)]);
+    assert_eq!(rsp.vreg(3), 0x0011_2233_4455_6677_8899_AABB_CC80_8182);
+}
+
+#[test]
+fn unimplemented_op_breaks() {
+    let mut rsp = TestRsp::new();
+    rsp.start();
+    assert_eq!(rsp.status() & (SP_STATUS_HALT | SP_STATUS_BROKE), 0);
+
+    // COP2 non-VU opcode with rs=1 (DMFC2) does not exist on RSP.
+    rsp.run(&[(0x12 << 26) | (1 << 21)]);
+    assert_eq!(
+        rsp.status() & (SP_STATUS_HALT | SP_STATUS_BROKE),
+        SP_STATUS_HALT | SP_STATUS_BROKE
+    );
+}
+
+#[test]
+fn reserved_encodings_break() {
+    let mut rsp = TestRsp::new();
+    for &op in &[
+        cop2_move(CFC2, 1, 3, 0), // there are only three control registers
+        cop2_move(CTC2, 1, 31, 0),
+    ] {
+        assert_eq!(Cop2Instr::decode(op), Cop2Instr::Unknown(op));
+
+        rsp.set_vreg(1, 0x1234);
+        rsp.ctx.regs[1] = 0x5678;
+        rsp.start();
+        rsp.run(&[op]);
+        assert_eq!(
+            rsp.status() & (SP_STATUS_HALT | SP_STATUS_BROKE),
+            SP_STATUS_HALT | SP_STATUS_BROKE
+        );
+        assert_eq!(rsp.vreg(1), 0x1234);
+        assert_eq!(rsp.ctx.regs[1], 0x5678);
+    }
+}
+
+#[test]
+fn vlt_vge_select() {
+    let mut rsp = TestRsp::new();
+    let vs = [
+        0x8000, 0x7FFF, 0x0001, 0xFFFF, 0x8000, 0x1234, 0x0000, 0x7FFF,
+    ];
+    let vt = [
+        0x7FFF, 0x8000, 0xFFFF, 0x0001, 0x8000, 0x1234, 0x0000, 0x7FFF,
+    ];
+    rsp.set_vreg(0, lanes(vs));
+    rsp.set_vreg(1, lanes(vt));
+
+    rsp.set_vreg(SpCop2::REG_VCO, 0);
+    rsp.run(&[vu(VLT, 2, 0, 1, 0)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x8000, 0x8000, 0xFFFF, 0xFFFF, 0x8000, 0x1234, 0x0000, 0x7FFF])
+    );
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0x09);
+
+    rsp.set_vreg(SpCop2::REG_VCO, 0);
+    rsp.run(&[vu(VGE, 2, 0, 1, 0)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x7FFF, 0x7FFF, 0x0001, 0x0001, 0x8000, 0x1234, 0x0000, 0x7FFF])
+    );
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0xF6);
+
+    // Carry and NE on lane 4 (a tie) flip the selection of that lane.
+    rsp.set_vreg(SpCop2::REG_VCO, 0x1010);
+    rsp.run(&[vu(VLT, 2, 0, 1, 0)]);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0x19);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCO), 0);
+
+    rsp.set_vreg(SpCop2::REG_VCO, 0x1010);
+    rsp.run(&[vu(VGE, 2, 0, 1, 0)]);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0xE6);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCO), 0);
+}
+
+#[test]
+fn compare_scalar_element() {
+    // With a scalar element (e=11: lane 3), vt lane 3 is compared with every
+    // lane of vs, which is used as-is.
+    let mut rsp = TestRsp::new();
+    let vs = [1, 5, 3, 7, 3, 0, 8, 2];
+    rsp.set_vreg(1, lanes(vs));
+    rsp.set_vreg(2, lanes([9, 9, 9, 3, 9, 9, 9, 9]));
+
+    let cases = [
+        (VLT, [1, 3, 3, 3, 3, 0, 3, 2], 0xA1),
+        (VEQ, [3; 8], 0x14),
+        (VNE, vs, 0xEB),
+        (VGE, [3, 5, 3, 7, 3, 3, 8, 3], 0x5E),
+    ];
+    for &(func, res, vcc) in cases.iter() {
+        rsp.set_vreg(SpCop2::REG_VCO, 0);
+        rsp.run(&[vu(func, 3, 1, 2, 11)]);
+        assert_eq!(rsp.vreg(3), lanes(res), "func={:x}", func);
+        assert_eq!(rsp.vreg(SpCop2::REG_VCC), vcc, "func={:x}", func);
+        assert_eq!(rsp.vreg(1), lanes(vs));
+    }
+}
+
+#[test]
+fn vch_vcc_halves() {
+    let mut rsp = TestRsp::new();
+
+    // Reference model of VCH for one lane, returning (LE, GE): LE goes into
+    // the low byte of VCC, GE into the high byte.
+    let vch = |vs: u16, vt: u16| {
+        let (vs, vt) = (vs as i16 as i32, vt as i16 as i32);
+        if (vs ^ vt) < 0 {
+            (vs + vt <= 0, vt < 0)
+        } else {
+            (vt < 0, vs - vt >= 0)
+        }
+    };
+
+    for &(vs, vt) in &[
+        (
+            [0x8000, 0x7FFF, 0x0001, 0xFFFF, 0x8000, 0x1234, 0x0000, 0x7FFF],
+            [0x7FFF, 0x8000, 0xFFFF, 0x0001, 0x8000, 0x1234, 0x0000, 0x7FFF],
+        ),
+        (
+            [0x0005, 0xFFFB, 0x0005, 0xFFFB, 0x0003, 0xFFFD, 0x0000, 0xFFFF],
+            [0x0003, 0xFFFD, 0xFFFB, 0x0005, 0xFFFA, 0x0006, 0xFFFF, 0x0000],
+        ),
+    ] {
+        rsp.set_vreg(0, lanes(vs));
+        rsp.set_vreg(1, lanes(vt));
+        rsp.run(&[vu(VCH, 2, 0, 1, 0), cop2_move(CFC2, 1, 1, 0)]);
+
+        let mut vcc = 0u16;
+        for (i, (&s, &t)) in vs.iter().zip(&vt).enumerate() {
+            let (le, ge) = vch(s, t);
+            vcc |= ((le as u16) << i) | ((ge as u16) << (i + 8));
+        }
+        assert_eq!(rsp.ctx.regs[1] as u16, vcc, "vs={:x?} vt={:x?}", vs, vt);
+        assert_eq!(rsp.vreg(SpCop2::REG_VCC), vcc as u128);
+    }
+}
+
+#[test]
+fn vadd_saturate_extremes() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(
+        0,
+        lanes([0x8000, 0x7FFF, 0x8000, 0x7FFF, 0x0001, 0xFFFF, 0x8000, 0x7FFF]),
+    );
+    rsp.set_vreg(
+        1,
+        lanes([0x8000, 0x7FFF, 0x7FFF, 0x8000, 0x7FFF, 0x8000, 0xFFFF, 0x0001]),
+    );
+    // Carry set on lanes 0 and 1.
+    rsp.set_vreg(SpCop2::REG_VCO, 0x0003);
+    rsp.run(&[vu(VADD, 2, 0, 1, 0)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x8000, 0x7FFF, 0xFFFF, 0xFFFF, 0x7FFF, 0x8000, 0x8000, 0x7FFF])
+    );
+}
+
+#[test]
+#[cfg(feature = "trace-vu")]
+fn lqv_trace_record() {
+    let (logger, records) = CaptureDrain::logger();
+    let mut rsp = TestRsp::with_logger(logger);
+    rsp.ctx.regs[1] = 0x100;
+    rsp.run(&[lwc2(LQV, 3, 0, 2, 1)]);
+
+    let loads = records.find("VU load");
+    assert_eq!(loads.len(), 1);
+    assert_eq!(loads[0].level, Level::Trace);
+    assert_eq!(loads[0].get("op"), Some("lqv"));
+    assert_eq!(loads[0].get("ea"), Some("0x00000120"));
+    assert_eq!(loads[0].get("element"), Some("0"));
+    assert_eq!(loads[0].get("vt"), Some("v3"));
+}
+
+static TEST_RCP_ROM: [u16; 4] = [0x1111, 0x2222, 0x3333, 0x4444];
+static TEST_RSQ_ROM: [u16; 4] = [0x0010, 0x0020, 0x0030, 0x0040];
+
+#[test]
+fn vrcp_vrsq_synthetic_rom() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 =
+        SpCop2::with_reciprocal_rom("test", logger, &TEST_RCP_ROM, &TEST_RSQ_ROM).unwrap();
+    let mut ctx = CpuContext::default();
+    let t = Tracer::null();
+
+    // Inputs have the leading one at bit 14, so the mantissa bits that form
+    // the ROM index are bits 5-6 for RCP and bit 6 for RSQ, and the result
+    // is not shifted (RCP) or shifted left by 7 (RSQ).
+    let x = lanes([0x4000, 0x4020, 0x4040, 0x4060, 0xBFA0, 0, 0, 0]);
+    cop2.set_reg(&mut ctx, 1, x);
+    for i in 0..5 {
+        cop2.op(&mut ctx, vu(VRCP, 2, i, 1, 8 + i), &t).unwrap();
+        cop2.op(&mut ctx, vu(VRSQ, 3, i, 1, 8 + i), &t).unwrap();
+    }
+    // Fetch the high part of the last (negative) RCP result.
+    cop2.op(&mut ctx, vu(VRCP, 2, 4, 1, 12), &t).unwrap();
+    cop2.op(&mut ctx, vu(VRCPH, 2, 5, 1, 0), &t).unwrap();
+
+    assert_eq!(
+        cop2.reg(&ctx, 2),
+        lanes([0x1111, 0x2222, 0x3333, 0x4444, 0xBBBB, 0xFFFE, 0, 0])
+    );
+    assert_eq!(
+        cop2.reg(&ctx, 3),
+        lanes([0x0800, 0x0800, 0x1000, 0x1000, 0xEFFF, 0, 0, 0])
+    );
+}
+
+#[test]
+fn cfc2_ctc2_zero_register() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(SpCop2::REG_VCO, 0x1234);
+    rsp.run(&[cop2_move(CFC2, 0, 0, 0)]);
+    assert_eq!(rsp.ctx.regs[0], 0);
+
+    // Even if $zero got somehow dirty, CTC2 must read it as 0.
+    rsp.ctx.regs[0] = 0xFFFF;
+    rsp.set_vreg(SpCop2::REG_VCC, 0x00FF);
+    rsp.run(&[cop2_move(CTC2, 0, 1, 0)]);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0);
+}
+
+#[test]
+fn ctc2_cfc2_roundtrip() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::new("test", logger).unwrap();
+    let mut ctx = CpuContext::with_gprs(&[(1, 0x8001), (2, 0x1234), (3, 0x1A5)]);
+    let t = Tracer::null();
+
+    for rd in 0..3 {
+        cop2.op(&mut ctx, cop2_move(CTC2, rd + 1, rd, 0), &t).unwrap();
+        cop2.op(&mut ctx, cop2_move(CFC2, rd + 4, rd, 0), &t).unwrap();
+    }
+    // VCO and VCC are 16 bits and sign-extended, VCE is 8 bits.
+    assert_eq!(ctx.regs[4], 0xFFFF_FFFF_FFFF_8001);
+    assert_eq!(ctx.regs[5], 0x1234);
+    assert_eq!(ctx.regs[6], 0xA5);
+}
+
+#[test]
+fn ctc2_masks_flag_widths() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::new("test", logger).unwrap();
+    let mut ctx = CpuContext::with_gprs(&[(1, 0xFFFF), (2, 0x1_8001)]);
+    let t = Tracer::null();
+
+    // VCE is 8 bits wide: only the low 8 bits of the GPR are written.
+    cop2.op(&mut ctx, cop2_move(CTC2, 1, 2, 0), &t).unwrap();
+    cop2.op(&mut ctx, cop2_move(CFC2, 3, 2, 0), &t).unwrap();
+    assert_eq!(ctx.regs[3], 0xFF);
+    assert_eq!(cop2.reg(&ctx, SpCop2::REG_VCE), 0xFF);
+
+    // VCO and VCC are 16 bits wide.
+    cop2.op(&mut ctx, cop2_move(CTC2, 2, 0, 0), &t).unwrap();
+    assert_eq!(cop2.reg(&ctx, SpCop2::REG_VCO), 0x8001);
+    cop2.op(&mut ctx, cop2_move(CTC2, 2, 1, 0), &t).unwrap();
+    assert_eq!(cop2.reg(&ctx, SpCop2::REG_VCC), 0x8001);
+
+    // Writes through the debugger are masked the same way.
+    cop2.set_reg(&mut ctx, SpCop2::REG_VCE, 0xFFFF);
+    assert_eq!(cop2.reg(&ctx, SpCop2::REG_VCE), 0xFF);
+}
+
+#[test]
+fn standalone_cop2() {
+    // No Sp (nor RSP) is created in this test.
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::standalone("test", logger.new(o!())).unwrap();
+    let mut bus = Bus::new(logger);
+    let mut ctx = CpuContext::with_gprs(&[(1, 0x100)]);
+    let t = Tracer::null();
+
+    let a = lanes([1, 2, 3, 4, 0x7FFF, 0x8000, 0xFFFF, 0x1234]);
+    let b = lanes([10, 20, 30, 40, 1, 0xFFFF, 0xFFFF, 0x1111]);
+    cop2.set_reg(&mut ctx, 1, a);
+    cop2.set_reg(&mut ctx, 2, b);
+    cop2.op(&mut ctx, vu(VADD, 3, 1, 2, 0), &t).unwrap();
+    let sum = lanes([11, 22, 33, 44, 0x7FFF, 0x8000, 0xFFFE, 0x2345]);
+    assert_eq!(cop2.reg(&ctx, 3), sum);
+
+    // Loads and stores use the private DMEM.
+    cop2.swc(swc2(SQV, 3, 0, 0, 1), &mut ctx, &mut bus, &t).unwrap();
+    assert_eq!(&cop2.dmem_mut().unwrap()[0x100..0x110], &sum.to_be_bytes()[..]);
+    cop2.dmem_mut().unwrap()[0x10F] = 0x99;
+    cop2.lwc(lwc2(LQV, 4, 0, 0, 1), &mut ctx, &bus, &t).unwrap();
+    assert_eq!(cop2.reg(&ctx, 4), (sum & !0xFF) | 0x99);
+
+    // Errors are reported without halting an RSP that doesn't exist.
+    let op = vu(0x3E, 1, 2, 3, 0);
+    cop2.op(&mut ctx, op, &t).unwrap();
+    assert_eq!(cop2.take_error(), Some(CopError::UnimplementedOp(op)));
+}
+
+#[test]
+fn op_vector_only() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::standalone("test", logger).unwrap();
+    let mut ctx = CpuContext::default();
+
+    cop2.set_reg(&mut ctx, 1, lanes([1, 2, 3, 4, 5, 6, 7, 0x7FFF]));
+    cop2.set_reg(&mut ctx, 2, lanes([10, 20, 30, 40, 50, 60, 70, 1]));
+    cop2.op_vector_only(vu(VADD, 3, 1, 2, 0)).unwrap();
+    assert_eq!(cop2.reg(&ctx, 3), lanes([11, 22, 33, 44, 55, 66, 77, 0x7FFF]));
+    assert_eq!(cop2.take_error(), None);
+}
+
+#[test]
+fn op_vector_only_rejects_moves() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::standalone("test", logger).unwrap();
+    let op = cop2_move(CFC2, 1, 0, 0);
+    assert_eq!(cop2.op_vector_only(op), Err(CopError::NotComputational(op)));
+    assert_eq!(cop2.take_error(), None);
+}
+
+#[test]
+fn vco_flags_roundtrip() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(0, 0);
+
+    // Carry only: VADD adds the carry of each lane.
+    rsp.ctx.regs[1] = 0x00A5;
+    rsp.run(&[cop2_move(CTC2, 1, 0, 0), cop2_move(CFC2, 2, 0, 0)]);
+    assert_eq!(rsp.ctx.regs[2], 0x00A5);
+    rsp.run(&[vu(VADD, 3, 0, 0, 0)]);
+    assert_eq!(rsp.vreg(3), lanes([1, 0, 1, 0, 0, 1, 0, 1]));
+
+    // NE only: VEQ fails on lanes with NE set, even if they are equal.
+    rsp.ctx.regs[1] = 0x5A00;
+    rsp.run(&[cop2_move(CTC2, 1, 0, 0), cop2_move(CFC2, 2, 0, 0)]);
+    assert_eq!(rsp.ctx.regs[2], 0x5A00);
+    rsp.run(&[vu(VEQ, 3, 0, 0, 0), cop2_move(CFC2, 2, 1, 0)]);
+    assert_eq!(rsp.ctx.regs[2], 0x00A5);
+}
+
+#[test]
+fn vmrg_writes_accum_lo() {
+    let mut rsp = TestRsp::new();
+    let md = lanes([0xAAAA; 8]);
+    let hi = lanes([0x5555; 8]);
+    rsp.set_vreg(0, lanes([1, 2, 3, 4, 5, 6, 7, 8]));
+    rsp.set_vreg(1, lanes([0x1001, 0x1002, 0x1003, 0x1004, 0x1005, 0x1006, 0x1007, 0x1008]));
+    rsp.set_vreg(SpCop2::REG_ACCUM_MD, md);
+    rsp.set_vreg(SpCop2::REG_ACCUM_HI, hi);
+    rsp.set_vreg(SpCop2::REG_VCC, 0x00A5);
+
+    rsp.run(&[vu(VMRG, 2, 0, 1, 0)]);
+    let merged = lanes([1, 0x1002, 3, 0x1004, 0x1005, 6, 0x1007, 8]);
+    assert_eq!(rsp.vreg(2), merged);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), merged);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), md);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_HI), hi);
+}
+
+#[test]
+fn loadstore_address_wrap() {
+    let mut rsp = TestRsp::new();
+    for (i, b) in rsp.dmem()[0x10..0x20].iter_mut().enumerate() {
+        *b = 0x40 + i as u8;
+    }
+
+    // Base near the top of the 32-bit space: the address wraps to 0x10.
+    rsp.ctx.regs[1] = 0xFFFF_FFF0;
+    rsp.run(&[lwc2(LQV, 4, 0, 2, 1)]);
+    assert_eq!(rsp.vreg(4), 0x4041_4243_4445_4647_4849_4A4B_4C4D_4E4F);
+
+    // 0xFFFF_FFFF + (1 << 1) wraps to 0x1.
+    rsp.ctx.regs[1] = 0xFFFF_FFFF;
+    rsp.run(&[swc2(SSV, 4, 0, 1, 1)]);
+    assert_eq!(&rsp.dmem()[0x0..0x4], &[0x00, 0x40, 0x41, 0x00]);
+}
+
+#[test]
+fn lxv_no_sign_extension() {
+    const LBV: u32 = 0x00;
+    const LSV: u32 = 0x01;
+    const LLV: u32 = 0x02;
+    let fill = 0x5A5A_5A5A_5A5A_5A5A_5A5A_5A5A_5A5A_5A5Au128;
+    let mut rsp = TestRsp::new();
+    rsp.dmem()[0x40..0x48].copy_from_slice(&[0xFF, 0x80, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0]);
+    rsp.ctx.regs[1] = 0x40;
+
+    // A negative byte only changes the addressed register byte: the other
+    // half of lane 0 is not sign-extended.
+    rsp.set_vreg(1, fill);
+    rsp.run(&[lwc2(LBV, 1, 0, 0, 1)]);
+    assert_eq!(rsp.vreg(1), 0xFF5A_5A5A_5A5A_5A5A_5A5A_5A5A_5A5A_5A5A);
+    rsp.set_vreg(1, fill);
+    rsp.run(&[lwc2(LBV, 1, 1, 0, 1)]);
+    assert_eq!(rsp.vreg(1), 0x5AFF_5A5A_5A5A_5A5A_5A5A_5A5A_5A5A_5A5A);
+
+    // Same for halfwords and words, even when they span two lanes.
+    rsp.ctx.regs[1] = 0x41;
+    rsp.set_vreg(1, fill);
+    rsp.run(&[lwc2(LSV, 1, 3, 0, 1)]);
+    assert_eq!(rsp.vreg(1), 0x5A5A_5A80_015A_5A5A_5A5A_5A5A_5A5A_5A5A);
+    rsp.ctx.regs[1] = 0x43;
+    rsp.set_vreg(1, fill);
+    rsp.run(&[lwc2(LLV, 1, 4, 0, 1)]);
+    assert_eq!(rsp.vreg(1), 0x5A5A_5A5A_FFFF_FFFF_5A5A_5A5A_5A5A_5A5A);
+}
+
+#[test]
+fn load_widths_random_boundaries() {
+    let mut rsp = TestRsp::new();
+    let mut rng = XorShift(0x5EED_0000_1234_ABCD);
+
+    // Every alignment within a quadword, and every address that makes the
+    // access wrap around the end of DMEM.
+    let addrs: Vec<usize> = (0x230..0x240).chain(0xFF0..0x1000).collect();
+    for op in 0..4 {
+        // LBV, LSV, LLV, LDV
+        let size = 1 << op;
+        for &addr in &addrs {
+            for e in 0..16 {
+                let mut old = [0u8; 16];
+                let mut mem = [0u8; 8];
+                rng.fill(&mut old);
+                rng.fill(&mut mem);
+                for (i, &b) in mem[..size].iter().enumerate() {
+                    rsp.dmem()[(addr + i) & 0xFFF] = b;
+                }
+                rsp.set_vreg(1, u128::from_be_bytes(old));
+                rsp.ctx.regs[1] = addr as u64;
+                rsp.run(&[lwc2(op, 1, e, 0, 1)]);
+
+                // Bytes that fall past the end of the register are dropped.
+                let mut expected = old;
+                let n = size.min(16 - e);
+                expected[e..e + n].copy_from_slice(&mem[..n]);
+                assert_eq!(
+                    rsp.vreg(1),
+                    u128::from_be_bytes(expected),
+                    "op={} addr={:x} e={}",
+                    op,
+                    addr,
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn sdv_element_wraps_register() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(5, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+    rsp.ctx.regs[1] = 0x40;
+
+    // Element 10 takes the last six bytes of the register, and then wraps
+    // around to the first two.
+    rsp.run(&[swc2(SDV, 5, 10, 0, 1)]);
+    assert_eq!(
+        &rsp.dmem()[0x40..0x48],
+        &[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11]
+    );
+}
+
+#[test]
+fn vsar_reads_accumulator() {
+    let mut rsp = TestRsp::new();
+    let lo = lanes([0x1111, 0x1112, 0x1113, 0x1114, 0x1115, 0x1116, 0x1117, 0x1118]);
+    let md = lanes([0x2221, 0x2222, 0x2223, 0x2224, 0x2225, 0x2226, 0x2227, 0x2228]);
+    let hi = lanes([0x3331, 0x3332, 0x3333, 0x3334, 0x3335, 0x3336, 0x3337, 0x3338]);
+    rsp.set_vreg(SpCop2::REG_ACCUM_LO, lo);
+    rsp.set_vreg(SpCop2::REG_ACCUM_MD, md);
+    rsp.set_vreg(SpCop2::REG_ACCUM_HI, hi);
+    rsp.set_vreg(1, lanes([0xFFFF; 8]));
+
+    rsp.run(&[vu(VSAR, 2, 1, 1, 8), vu(VSAR, 3, 1, 1, 9), vu(VSAR, 4, 1, 1, 10)]);
+    assert_eq!(rsp.vreg(2), hi);
+    assert_eq!(rsp.vreg(3), md);
+    assert_eq!(rsp.vreg(4), lo);
+
+    // The accumulator is left untouched.
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lo);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), md);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_HI), hi);
+}
+
+#[test]
+fn vsar_other_elements_zero() {
+    let mut rsp = TestRsp::new();
+    let lo = lanes([0x1111; 8]);
+    let md = lanes([0x2222; 8]);
+    let hi = lanes([0x3333; 8]);
+    rsp.set_vreg(SpCop2::REG_ACCUM_LO, lo);
+    rsp.set_vreg(SpCop2::REG_ACCUM_MD, md);
+    rsp.set_vreg(SpCop2::REG_ACCUM_HI, hi);
+    rsp.set_vreg(1, lanes([0xFFFF; 8]));
+
+    for e in 0..16 {
+        rsp.set_vreg(2, 0x1234);
+        rsp.run(&[vu(VSAR, 2, 1, 1, e)]);
+        let exp = match e {
+            8 => hi,
+            9 => md,
+            10 => lo,
+            _ => 0,
+        };
+        assert_eq!(rsp.vreg(2), exp, "element {}", e);
+        assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lo, "element {}", e);
+    }
+}
+
+#[test]
+fn dmem_via_bus_watchpoints() {
+    let mut rsp = TestRsp::new();
+    let mut dbg = Debugger::new(&vec!["RSP".to_owned()]);
+    dbg.add_watchpoint("RSP", 0x108, "", WatchpointType::Read, WatchpointCondition::Always);
+    dbg.add_watchpoint("RSP", 0x201, "", WatchpointType::Write, WatchpointCondition::Always);
+    dbg.add_watchpoint("RSP", 0x203, "", WatchpointType::Write, WatchpointCondition::Always);
+    let t = dbg.new_tracer();
+
+    for i in 0..16 {
+        rsp.dmem()[0x100 + i] = i as u8;
+    }
+    rsp.set_vreg(4, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+    rsp.ctx.regs[1] = 0x100;
+    rsp.ctx.regs[2] = 0x200;
+
+    let cpu = &mut **RSPCPU::get_mut();
+    let (cop2, bus) = (&mut cpu.cop2, &mut cpu.bus);
+
+    // The direct path does not go through the tracer.
+    cop2.lwc(lwc2(LQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap();
+    cop2.swc(swc2(SSV, 4, 0, 0, 2), &mut rsp.ctx, bus, &t).unwrap();
+
+    cop2.set_dmem_via_bus(true);
+    match *cop2.lwc(lwc2(LQV, 3, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap_err() {
+        TraceEvent::WatchpointRead(ref name, _) => assert_eq!(name, "RSP"),
+        ref ev => panic!("unexpected trace event: {:?}", ev),
+    }
+    cop2.lwc(lwc2(LQV, 3, 0, 0, 1), &mut rsp.ctx, bus, &Tracer::null()).unwrap();
+
+    // SSV writes 0x200-0x201, so it does not hit the watchpoint at 0x203.
+    match *cop2.swc(swc2(SSV, 4, 0, 0, 2), &mut rsp.ctx, bus, &t).unwrap_err() {
+        TraceEvent::WatchpointWrite(ref name, _) => assert_eq!(name, "RSP"),
+        ref ev => panic!("unexpected trace event: {:?}", ev),
+    }
+    cop2.swc(swc2(SSV, 4, 0, 1, 2), &mut rsp.ctx, bus, &Tracer::null()).unwrap();
+    cop2.set_dmem_via_bus(false);
+
+    assert_eq!(rsp.vreg(3), rsp.vreg(2));
+    assert_eq!(&rsp.dmem()[0x200..0x205], &[0x00, 0x11, 0x00, 0x11, 0x00]);
+}
+
+#[test]
+fn op_stats_histogram() {
+    let mut rsp = TestRsp::new();
+    rsp.ctx.regs[1] = 0x100;
+    assert!(RSPCPU::get().cop2.stats().is_none());
+
+    RSPCPU::get_mut().cop2.enable_stats(true);
+    rsp.run(&[
+        lwc2(LQV, 1, 0, 0, 1),
+        lwc2(LQV, 2, 0, 1, 1),
+        vu(VADD, 3, 1, 2, 0),
+        vu(VMULF, 4, 1, 2, 0),
+        vu(VADD, 5, 3, 4, 0),
+        cop2_move(MTC2, 1, 5, 0),
+        swc2(SQV, 5, 0, 2, 1),
+        swc2(SSV, 5, 0, 0, 1),
+    ]);
+    {
+        let stats = RSPCPU::get().cop2.stats().unwrap();
+        assert_eq!(stats.compute()[VADD as usize], 2);
+        assert_eq!(stats.compute()[VMULF as usize], 1);
+        assert_eq!(stats.load()[LQV as usize], 2);
+        assert_eq!(stats.store()[SQV as usize], 1);
+        assert_eq!(stats.store()[SSV as usize], 1);
+        assert_eq!(stats.total(), 7);
+    }
+
+    // Enabling again resets the counters.
+    RSPCPU::get_mut().cop2.enable_stats(true);
+    assert_eq!(RSPCPU::get().cop2.stats().unwrap().total(), 0);
+    RSPCPU::get_mut().cop2.enable_stats(false);
+    assert!(RSPCPU::get().cop2.stats().is_none());
+}
+
+#[test]
+fn injected_loadstore_fault() {
+    let mut rsp = TestRsp::new();
+    let dbg = Debugger::new(&vec!["RSP".to_owned()]);
+    let t = dbg.new_tracer();
+
+    for i in 0..16 {
+        rsp.dmem()[0x100 + i] = i as u8;
+    }
+    rsp.set_vreg(2, 0x1234);
+    rsp.ctx.regs[1] = 0x104;
+
+    let cpu = &mut **RSPCPU::get_mut();
+    let (cop2, bus) = (&mut cpu.cop2, &mut cpu.bus);
+
+    // LQV from 0x104 reads 0x104-0x10F: a fault at 0x10F hits, one at 0x103
+    // doesn't.
+    cop2.inject_fault(Some(0x10F));
+    match *cop2.lwc(lwc2(LQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap_err() {
+        TraceEvent::GenericBreak(ref msg) => assert_eq!(msg, "injected fault in VU load"),
+        ref ev => panic!("unexpected trace event: {:?}", ev),
+    }
+    assert_eq!(cop2.reg(&rsp.ctx, 2), 0x1234);
+    assert!(cop2.swc(swc2(SQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).is_err());
+    assert_eq!(rsp.dmem()[0x104], 0x04);
+
+    // The bytes past the end of the quadword are left untouched.
+    cop2.inject_fault(Some(0x103));
+    cop2.lwc(lwc2(LQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap();
+    assert_eq!(cop2.reg(&rsp.ctx, 2), 0x0405_0607_0809_0A0B_0C0D_0E0F_0000_1234);
+    cop2.inject_fault(None);
+}
+
+#[test]
+fn cop_errors() {
+    let mut rsp = TestRsp::new();
+    rsp.ctx.regs[1] = 0x104;
+    let t = Tracer::null();
+    let cpu = &mut **RSPCPU::get_mut();
+    let (cop2, bus) = (&mut cpu.cop2, &mut cpu.bus);
+    assert_eq!(cop2.take_error(), None);
+
+    // Without a debugger attached, errors are only reported by take_error.
+    let func = (0..0x40u32)
+        .find(|&f| !SpCop2::supported_ops().contains(&(f as u8)))
+        .unwrap();
+    let op = vu(func, 2, 0, 1, 0);
+    cop2.op(&mut rsp.ctx, op, &t).unwrap();
+    assert_eq!(cop2.take_error(), Some(CopError::UnimplementedOp(op)));
+    assert_eq!(cop2.take_error(), None);
+
+    let dmfc2 = (0x12 << 26) | (1 << 21);
+    cop2.op(&mut rsp.ctx, dmfc2, &t).unwrap();
+    assert_eq!(cop2.take_error(), Some(CopError::UnimplementedOp(dmfc2)));
+
+    assert_eq!(cop2.try_reg(&rsp.ctx, SpCop2::REG_ACCUM_HI), Ok(0));
+    assert_eq!(cop2.try_reg(&rsp.ctx, 39), Err(CopError::BadRegister(39)));
+    assert_eq!(cop2.try_set_reg(&mut rsp.ctx, 39, 1), Err(CopError::BadRegister(39)));
+    assert_eq!(cop2.try_set_reg(&mut rsp.ctx, 3, 0x55), Ok(()));
+    assert_eq!(cop2.reg(&rsp.ctx, 3), 0x55);
+
+    // The fault is reported at the DMEM address, whatever the mirror.
+    cop2.inject_fault(Some(0x1108));
+    cop2.lwc(lwc2(LQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap();
+    assert_eq!(cop2.take_error(), Some(CopError::AddressFault(0x108)));
+    cop2.swc(swc2(SQV, 2, 0, 0, 1), &mut rsp.ctx, bus, &t).unwrap();
+    assert_eq!(cop2.take_error(), Some(CopError::AddressFault(0x108)));
+    cop2.inject_fault(None);
+}
+
+#[test]
+fn supported_ops_match_implementation() {
+    let mut rsp = TestRsp::new();
+    for func in 0..0x40 {
+        rsp.start();
+        rsp.run(&[vu(func, 2, 0, 1, 0)]);
+        let halted = rsp.status() & SP_STATUS_HALT != 0;
+        let supported = SpCop2::supported_ops().contains(&(func as u8));
+        assert_eq!(halted, !supported, "func={:x}", func);
+    }
+}
+
+#[test]
+fn run_cycles_budget() {
+    let mut rsp = TestRsp::new();
+    let t = Tracer::null();
+    let program = [
+        0x2401_0000u32, // li at,0
+        0x2421_0001,    // loop: addiu at,at,1
+        vu(VMULF, 3, 1, 2, 0),
+        vu(VADD, 4, 3, 3, 0), // stalls 2 cycles, waiting for v3
+        0x0800_0001, // j loop
+        0x0000_0000, // nop
+    ];
+    for (i, op) in program.iter().enumerate() {
+        Sp::get_mut().imem[i * 4..i * 4 + 4].copy_from_slice(&op.to_be_bytes());
+    }
+    let run = |budget| Sp::get_mut().run_cycles(budget, &t).unwrap();
+    let at = || RSPCPU::get().ctx().regs[1];
+
+    // The RSP starts halted.
+    let halted = CycleRun {
+        insns: 0,
+        cycles: 0,
+        reason: StopReason::Halt,
+    };
+    assert_eq!(run(100), halted);
+    R4300::get_mut().bus.write::<u32>(SP_PC, 0);
+    rsp.start();
+
+    // li, then a full iteration (7 cycles), then addiu and vmulf: vadd
+    // doesn't fit in the last 2 cycles, nor in a budget of 2 cycles.
+    let budget = |insns, cycles| CycleRun {
+        insns,
+        cycles,
+        reason: StopReason::Budget,
+    };
+    assert_eq!(run(12), budget(8, 10));
+    assert_eq!(at(), 2);
+    assert_eq!(run(2), budget(0, 0));
+    assert_eq!(run(5), budget(3, 5));
+    assert_eq!(at(), 2);
+
+    // Replace the jump with a BREAK: addiu, vmulf, vadd (with its stall),
+    // and break.
+    Sp::get_mut().imem[0x10..0x14].copy_from_slice(&0x0000_000Du32.to_be_bytes());
+    let broke = CycleRun {
+        insns: 4,
+        cycles: 6,
+        reason: StopReason::Break,
+    };
+    assert_eq!(run(100), broke);
+    assert_eq!(at(), 3);
+}
+
+#[test]
+fn rsp_step_example() {
+    let imem: Vec<u8> = [
+        0x3401_1234u32, // li at,0x1234
+        cop2_move(MTC2, 1, 1, 0),
+        vu(VADD, 2, 1, 1, 8),
+        0x0000_000D, // break
+    ]
+    .iter()
+    .flat_map(|op| op.to_be_bytes().to_vec())
+    .collect();
+    rsp_step::setup(slog::Logger::root(Discard, o!()), &imem, &[]);
+
+    let v2 = "0x24681234123412341234123412341234";
+    assert_eq!(
+        rsp_step::trace(100),
+        vec![
+            "000: li at,0x1234 at=0x1234".to_owned(),
+            "004: mtc2 at,v1[e0] v1=0x12340000000000000000000000000000".to_owned(),
+            format!("008: vadd v2,v1,v1[e8] v2={} acc_lo={}", v2, v2),
+            "00c: break".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn disasm_vsar_and_negative_offsets() {
+    let logger = slog::Logger::root(Discard, o!());
+    let cop2 = SpCop2::new("test", logger).unwrap();
+    let cases = [
+        // e=8 reads the high slice of the accumulator, e=10 the low one.
+        (vu(VSAR, 17, 0, 0, 8), "vsar v17,acc_hi"),
+        (vu(VSAR, 17, 0, 0, 9), "vsar v17,acc_md"),
+        (vu(VSAR, 17, 0, 0, 10), "vsar v17,acc_lo"),
+        // Offsets are scaled by the access size, and can be negative.
+        (lwc2(LQV, 30, 0, -1, 29), "lqv v30[e0],0xfff0(sp)"),
+        (swc2(SSV, 2, 14, -1, 5), "ssv v2[e14],0xfffe(a1)"),
+    ];
+    for &(op, expected) in cases.iter() {
+        let got = cop2.decode(op, 0).disasm().replace('\t', " ");
+        assert_eq!(got, expected, "opcode={:08x}", op);
+    }
+}
+
+#[test]
+fn decode_cop2_instr() {
+    assert_eq!(
+        Cop2Instr::decode(vu(VADD, 3, 4, 5, 9)),
+        Cop2Instr::Compute {
+            func: VADD,
+            vd: 3,
+            vs: 4,
+            vt: 5,
+            e: 9
+        }
+    );
+    assert_eq!(
+        Cop2Instr::decode(cop2_move(MFC2, 7, 5, 3)),
+        Cop2Instr::Mfc2 { rt: 7, vs: 5, e: 3 }
+    );
+    assert_eq!(
+        Cop2Instr::decode(cop2_move(CTC2, 9, 1, 0)),
+        Cop2Instr::Ctc2 { rt: 9, rd: 1 }
+    );
+    assert_eq!(
+        Cop2Instr::decode(lwc2(LQV, 2, 8, -3, 31)),
+        Cop2Instr::Load {
+            op: LQV,
+            vt: 2,
+            base: 31,
+            e: 8,
+            offset: -3
+        }
+    );
+    assert_eq!(
+        Cop2Instr::decode(swc2(SDV, 6, 10, 63, 1)),
+        Cop2Instr::Store {
+            op: SDV,
+            vt: 6,
+            base: 1,
+            e: 10,
+            offset: 63
+        }
+    );
+
+    // DMFC2 does not exist on the RSP.
+    let dmfc2 = (0x12 << 26) | (1 << 21);
+    assert_eq!(Cop2Instr::decode(dmfc2), Cop2Instr::Unknown(dmfc2));
+}
+
+// Kind and operands (named as in OpField) of a decoded opcode, or None if
+// the RSP does not implement it.
+fn decoded_operands(op: u32) -> Option<(OpKind, Vec<(&'static str, u32)>)> {
+    let u = |v: usize| v as u32;
+    let mem = |vt, base, e, offset: i32| {
+        let offset = offset as u32 & 0x7F;
+        vec![("vt", u(vt)), ("base", u(base)), ("e", u(e)), ("offset", offset)]
+    };
+    match Cop2Instr::decode(op) {
+        Cop2Instr::Compute {
+            func,
+            vd,
+            vs,
+            vt,
+            e,
+        } if SpCop2::supported_ops().contains(&(func as u8)) => {
+            let ops = vec![("vd", u(vd)), ("vs", u(vs)), ("de", u(vs)), ("vt", u(vt)), ("e", u(e))];
+            Some((OpKind::Compute, ops))
+        }
+        Cop2Instr::Compute { .. } => None,
+        Cop2Instr::Mfc2 { rt, vs, e } => {
+            Some((OpKind::Mfc2, vec![("rt", u(rt)), ("vs", u(vs)), ("e", u(e))]))
+        }
+        Cop2Instr::Mtc2 { rt, vs, e } => {
+            Some((OpKind::Mtc2, vec![("rt", u(rt)), ("vs", u(vs)), ("e", u(e))]))
+        }
+        Cop2Instr::Cfc2 { rt, rd } => Some((OpKind::Cfc2, vec![("rt", u(rt)), ("rd", u(rd))])),
+        Cop2Instr::Ctc2 { rt, rd } => Some((OpKind::Ctc2, vec![("rt", u(rt)), ("rd", u(rd))])),
+        // LWV does not exist, and opcodes above LTV/STV are reserved.
+        Cop2Instr::Load { op: 0x0A, .. } => None,
+        Cop2Instr::Load { op: vop, .. } | Cop2Instr::Store { op: vop, .. } if vop > 0x0B => None,
+        Cop2Instr::Load {
+            vt, base, e, offset, ..
+        } => Some((OpKind::Load, mem(vt, base, e, offset))),
+        Cop2Instr::Store {
+            vt, base, e, offset, ..
+        } => Some((OpKind::Store, mem(vt, base, e, offset))),
+        Cop2Instr::Unknown(_) => None,
+    }
+}
+
+#[test]
+fn opcode_info_table() {
+    let entries = |op: u32| -> Vec<&OpInfo> {
+        OPCODE_INFO
+            .iter()
+            .filter(|info| op & info.mask == info.value)
+            .collect()
+    };
+
+    // Random COP2, LWC2 and SWC2 opcodes: each implemented opcode must match
+    // exactly one entry, of the same kind and with the same operands.
+    let mut rng = XorShift(0x0692);
+    let mut found = vec![false; OPCODE_INFO.len()];
+    for i in 0..0x20000 {
+        let primary = [0x12u32, 0x32, 0x3A][i % 3];
+        let op = (primary << 26) | (rng.next() as u32 & 0x03FF_FFFF);
+        let matches = entries(op);
+        let (kind, operands) = match decoded_operands(op) {
+            Some(decoded) => decoded,
+            None => {
+                // The table can't express that CFC2/CTC2 only have 3 control
+                // registers: check that they are the only matches.
+                let ctrl = |info: &&OpInfo| info.kind == OpKind::Cfc2 || info.kind == OpKind::Ctc2;
+                assert!(
+                    matches.iter().all(|info| ctrl(info) && (op >> 11) & 0x1F > 2),
+                    "{:08x}: {:?}",
+                    op,
+                    matches
+                );
+                continue;
+            }
+        };
+        assert_eq!(matches.len(), 1, "{:08x}: {:?}", op, matches);
+        let info = matches[0];
+        assert_eq!(info.kind, kind, "{:08x}: {}", op, info.name);
+        for field in info.fields {
+            let decoded = operands.iter().find(|&&(name, _)| name == field.name);
+            assert_eq!(
+                decoded.map(|&(_, v)| v),
+                Some(field.extract(op)),
+                "{:08x}: {} {}",
+                op,
+                info.name,
+                field.name
+            );
+        }
+        found[OPCODE_INFO.iter().position(|e| e == info).unwrap()] = true;
+    }
+    for (info, found) in OPCODE_INFO.iter().zip(found) {
+        assert!(found, "{} never decoded", info.name);
+    }
+
+    // The value of each entry is itself an instance of the opcode.
+    for info in OPCODE_INFO {
+        assert_eq!(decoded_operands(info.value).unwrap().0, info.kind, "{}", info.name);
+    }
+}
+
+#[test]
+fn mfc2_mtc2_elements() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(5, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+
+    // Element 3 is an odd byte offset: it spans lanes 1 and 2.
+    rsp.run(&[cop2_move(MFC2, 1, 5, 3)]);
+    assert_eq!(rsp.ctx.regs[1], 0x3344);
+
+    // The value is sign-extended.
+    rsp.run(&[cop2_move(MFC2, 2, 5, 8)]);
+    assert_eq!(rsp.ctx.regs[2], 0xFFFF_FFFF_FFFF_8899);
+
+    // Element 15 wraps around to the first byte of the register.
+    rsp.run(&[cop2_move(MFC2, 3, 5, 15)]);
+    assert_eq!(rsp.ctx.regs[3], 0xFFFF_FFFF_FFFF_FF00);
+
+    // Move back: only the two addressed bytes are written.
+    rsp.run(&[cop2_move(MTC2, 1, 6, 3), cop2_move(MTC2, 2, 6, 8)]);
+    assert_eq!(rsp.vreg(6), 0x0000_0033_4400_0000_8899_0000_0000_0000);
+
+    // At element 15, MTC2 does not wrap around.
+    rsp.run(&[cop2_move(MTC2, 3, 6, 15)]);
+    assert_eq!(rsp.vreg(6), 0x0000_0033_4400_0000_8899_0000_0000_00FF);
+}
+
+#[test]
+fn mfc2_spanning_reads() {
+    let mut rsp = TestRsp::new();
+
+    // Element 15 takes its low byte from byte 0: the sign comes only
+    // from byte 15.
+    rsp.set_vreg(5, 0x8000_0000_0000_0000_0000_0000_0000_007F);
+    rsp.run(&[cop2_move(MFC2, 1, 5, 15)]);
+    assert_eq!(rsp.ctx.regs[1], 0x7F80);
+
+    rsp.set_vreg(5, 0x7F00_0000_0000_0000_0000_0000_0000_0080);
+    rsp.run(&[cop2_move(MFC2, 1, 5, 15)]);
+    assert_eq!(rsp.ctx.regs[1], 0xFFFF_FFFF_FFFF_807F);
+
+    // Every element matches a byte-level read of the register.
+    let v: u128 = 0x0081_0283_0485_0687_0889_0A8B_0C8D_0E8F;
+    let bytes = v.to_be_bytes();
+    rsp.set_vreg(5, v);
+    for e in 0..16 {
+        rsp.run(&[cop2_move(MFC2, 1, 5, e)]);
+        let val = (u16::from(bytes[e]) << 8) | u16::from(bytes[(e + 1) & 15]);
+        assert_eq!(rsp.ctx.regs[1], val as i16 as i64 as u64, "element {}", e);
+    }
+}
+
+#[test]
+fn vreg_byte_and_lane_order() {
+    let mut rsp = TestRsp::new();
+    let v1: u128 = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+    rsp.set_vreg(1, v1);
+    assert_eq!(rsp.vreg(1), v1);
+
+    // Bytes are numbered from the most significant one, both in DMEM and
+    // for the element of MFC2.
+    rsp.ctx.regs[2] = 0x100;
+    rsp.run(&[swc2(SQV, 1, 0, 0, 2)]);
+    assert_eq!(&rsp.dmem()[0x100..0x110], &v1.to_be_bytes()[..]);
+    rsp.run(&[cop2_move(MFC2, 3, 1, 0)]);
+    assert_eq!(rsp.ctx.regs[3], 0x0011);
+    rsp.run(&[cop2_move(MFC2, 3, 1, 14)]);
+    assert_eq!(rsp.ctx.regs[3], 0xEEFF_u16 as i16 as i64 as u64);
+
+    // Lanes computed with SSE end up in the same order.
+    rsp.set_vreg(4, lanes([1; 8]));
+    rsp.run(&[vu(VADD, 5, 1, 4, 0), lwc2(LQV, 6, 0, 0, 2)]);
+    assert_eq!(rsp.vreg(5), v1 + lanes([1; 8]));
+    assert_eq!(rsp.vreg(6), v1);
+}
+
+#[test]
+fn logical_ops_element_broadcast() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(0, 0);
+    rsp.set_vreg(1, lanes([0xFFFF; 8]));
+    rsp.set_vreg(2, lanes([10, 11, 12, 13, 14, 15, 16, 17]));
+
+    rsp.run(&[vu(VXOR, 3, 0, 2, 3)]); // 1q
+    assert_eq!(rsp.vreg(3), lanes([11, 11, 13, 13, 15, 15, 17, 17]));
+    rsp.run(&[vu(VOR, 3, 0, 2, 4)]); // 0h
+    assert_eq!(rsp.vreg(3), lanes([10, 10, 10, 10, 14, 14, 14, 14]));
+    rsp.run(&[vu(VAND, 3, 1, 2, 8 + 5)]); // 5
+    assert_eq!(rsp.vreg(3), lanes([15; 8]));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lanes([15; 8]));
+}
+
+#[test]
+fn velem_truth_table() {
+    #[rustfmt::skip]
+    const TABLE: [[u16; 8]; 16] = [
+        [0, 1, 2, 3, 4, 5, 6, 7], // none
+        [0, 1, 2, 3, 4, 5, 6, 7], // none
+        [0, 0, 2, 2, 4, 4, 6, 6], // 0q
+        [1, 1, 3, 3, 5, 5, 7, 7], // 1q
+        [0, 0, 0, 0, 4, 4, 4, 4], // 0h
+        [1, 1, 1, 1, 5, 5, 5, 5], // 1h
+        [2, 2, 2, 2, 6, 6, 6, 6], // 2h
+        [3, 3, 3, 3, 7, 7, 7, 7], // 3h
+        [0; 8], [1; 8], [2; 8], [3; 8], [4; 8], [5; 8], [6; 8], [7; 8],
+    ];
+
+    // VOR with a zero vs returns the lanes of vt selected by the element.
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(0, 0);
+    rsp.set_vreg(1, lanes([0, 1, 2, 3, 4, 5, 6, 7]));
+    for (e, &expected) in TABLE.iter().enumerate() {
+        rsp.run(&[vu(VOR, 2, 0, 1, e)]);
+        assert_eq!(rsp.vreg(2), lanes(expected), "e={}", e);
+    }
+}
+
+#[test]
+fn logical_ops_scalar_elements() {
+    let mut rsp = TestRsp::new();
+    let vs = [0x00F0, 0x0F00, 0xF000, 0x000F, 0x5555, 0xAAAA, 0x1234, 0xFFFF];
+    let vt = [0x0011, 0x0022, 0x0033, 0x0044, 0x0055, 0x0066, 0x0077, 0x0088];
+    rsp.set_vreg(1, lanes(vs));
+    rsp.set_vreg(2, lanes(vt));
+
+    // VAND with a scalar element: lane 2 of vt is ANDed into every lane.
+    rsp.run(&[vu(VAND, 3, 1, 2, 10)]);
+    let mut exp = vs;
+    exp.iter_mut().for_each(|x| *x &= vt[2]);
+    assert_eq!(rsp.vreg(3), lanes(exp));
+
+    // Source lane of vt used for each destination lane, per element.
+    let select = |e: usize, i: usize| match e {
+        2 => i & !1,
+        4 => i & !3,
+        _ => e & 7,
+    };
+    for &e in &[2, 4, 8, 15] {
+        rsp.run(&[vu(VXOR, 3, 1, 2, e), vu(VOR, 4, 1, 2, e)]);
+        let (mut xor, mut or) = (vs, vs);
+        for (i, (x, o)) in xor.iter_mut().zip(or.iter_mut()).enumerate() {
+            *x ^= vt[select(e, i)];
+            *o |= vt[select(e, i)];
+        }
+        assert_eq!(rsp.vreg(3), lanes(xor), "VXOR e={}", e);
+        assert_eq!(rsp.vreg(4), lanes(or), "VOR e={}", e);
+        assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lanes(or), "VOR e={}", e);
+    }
+}
+
+#[test]
+fn vor_move_idiom() {
+    let mut rsp = TestRsp::new();
+    let val = lanes([0x8001, 0x7FFF, 0x1234, 0, 0xFFFF, 0x5555, 0xAAAA, 0x0F0F]);
+    rsp.set_vreg(1, val);
+    rsp.set_vreg(2, val);
+    for r in SpCop2::REG_ACCUM_LO..=SpCop2::REG_ACCUM_HI {
+        rsp.set_vreg(r, 0x1111_2222_3333_4444_5555_6666_7777_8888);
+    }
+
+    // The general path (different registers) and the move idiom (same
+    // register, element 0) must give the same result, in both vd and the
+    // accumulator.
+    rsp.run(&[vu(VOR, 3, 1, 2, 0)]);
+    let acc: Vec<u128> = (SpCop2::REG_ACCUM_LO..=SpCop2::REG_ACCUM_HI)
+        .map(|r| rsp.vreg(r))
+        .collect();
+    rsp.set_vreg(SpCop2::REG_ACCUM_LO, 0);
+    rsp.run(&[vu(VOR, 4, 1, 1, 0)]);
+    assert_eq!(rsp.vreg(3), val);
+    assert_eq!(rsp.vreg(4), val);
+    for (i, r) in (SpCop2::REG_ACCUM_LO..=SpCop2::REG_ACCUM_HI).enumerate() {
+        assert_eq!(rsp.vreg(r), acc[i], "{}", Cop2Reg::from_index(r).unwrap());
+    }
+    assert_eq!(acc[0], val);
+}
+
+#[test]
+fn vne_element_broadcast() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(0, lanes([10, 20, 10, 10, 10, 10, 10, 10]));
+    rsp.set_vreg(1, lanes([10, 11, 12, 13, 14, 15, 16, 17]));
+    rsp.set_vreg(SpCop2::REG_VCO, 0);
+
+    // Lanes that compare equal must select the broadcast VT element.
+    rsp.run(&[vu(VNE, 2, 0, 1, 8)]);
+    assert_eq!(rsp.vreg(SpCop2::REG_VCC), 0x02);
+    assert_eq!(rsp.vreg(2), lanes([10, 20, 10, 10, 10, 10, 10, 10]));
+}
+
+#[test]
+fn sqv_element_aligned() {
+    let mut rsp = TestRsp::new();
+    for b in rsp.dmem()[0x50..0x70].iter_mut() {
+        *b = 0x99;
+    }
+    rsp.set_vreg(7, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+    rsp.ctx.regs[1] = 0x50;
+
+    // With an aligned address, SQV stores up to the quadword boundary, that
+    // is a full 16 bytes. The element selects the first register byte, and
+    // the register wraps around (unlike LQV).
+    rsp.run(&[swc2(SQV, 7, 4, 0, 1)]);
+    assert_eq!(
+        &rsp.dmem()[0x50..0x60],
+        &[
+            0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11,
+            0x22, 0x33
+        ]
+    );
+    assert_eq!(&rsp.dmem()[0x60..0x70], &[0x99; 16]);
+
+    // With an unaligned address, fewer bytes are stored.
+    rsp.ctx.regs[1] = 0x6C;
+    rsp.run(&[swc2(SQV, 7, 4, 0, 1)]);
+    assert_eq!(&rsp.dmem()[0x68..0x70], &[0x99, 0x99, 0x99, 0x99, 0x44, 0x55, 0x66, 0x77]);
+}
+
+#[test]
+fn vaddc_vsubc_unsigned_carry() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(
+        0,
+        lanes([0xFFFF, 0x8000, 0x7FFF, 0x0001, 0x8000, 0x0000, 0xFFFE, 0x1234]),
+    );
+    rsp.set_vreg(
+        1,
+        lanes([0x0001, 0x8000, 0x0001, 0xFFFF, 0x7FFF, 0x0000, 0x0001, 0x1234]),
+    );
+
+    // Carries are computed with unsigned comparisons: lanes with the top
+    // bit set must not be treated as negative.
+    rsp.run(&[vu(VADDC, 2, 0, 1, 0)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x0000, 0x0000, 0x8000, 0x0000, 0xFFFF, 0x0000, 0xFFFF, 0x2468])
+    );
+    assert_eq!(rsp.vreg(SpCop2::REG_VCO), 0x0B);
+
+    rsp.run(&[vu(VSUBC, 2, 0, 1, 0)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0xFFFE, 0x0000, 0x7FFE, 0x0002, 0x0001, 0x0000, 0xFFFD, 0x0000])
+    );
+    // Carry (borrow) on lane 3, NE on all lanes but 1, 5 and 7.
+    assert_eq!(rsp.vreg(SpCop2::REG_VCO), 0x5D08);
+}
+
+#[test]
+fn vsub_borrow_saturation() {
+    let mut rsp = TestRsp::new();
+    // Lane 3 goes wrong if the borrow is added to vt (0x7FFF+1 wraps), lane 6
+    // if vs-vt is saturated before subtracting the borrow.
+    let vs = [0x8000, 0x0000, 0x0001, 0x7FFF, 0x8001, 0x8000, 0x7FFF, 0xFFFF];
+    let vt = [0x7FFF, 0x7FFF, 0x7FFF, 0x7FFF, 0x0000, 0x0000, 0xFFFF, 0x8000];
+    rsp.set_vreg(0, lanes(vs));
+    rsp.set_vreg(1, lanes(vt));
+
+    for &borrow in &[1u16, 0] {
+        let mut sub = [0u16; 8];
+        let mut acc = [0u16; 8];
+        for (i, (&s, &t)) in vs.iter().zip(vt.iter()).enumerate() {
+            let diff = i32::from(s as i16) - i32::from(t as i16) - i32::from(borrow);
+            sub[i] = diff.max(-0x8000).min(0x7FFF) as u16;
+            acc[i] = diff as u16;
+        }
+        if borrow == 1 {
+            let exp = [0x8000, 0x8000, 0x8001, 0xFFFF, 0x8000, 0x8000, 0x7FFF, 0x7FFE];
+            assert_eq!(sub, exp);
+        }
+
+        rsp.set_vreg(SpCop2::REG_VCO, u128::from(0xFF * borrow));
+        rsp.run(&[vu(VSUB, 2, 0, 1, 0)]);
+        assert_eq!(rsp.vreg(2), lanes(sub), "borrow={}", borrow);
+        assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lanes(acc), "borrow={}", borrow);
+        assert_eq!(rsp.vreg(SpCop2::REG_VCO), 0);
+    }
+}
+
+#[test]
+fn vsub_vsubc_element_broadcast() {
+    let mut rsp = TestRsp::new();
+    let vs = [0x8000, 0x7FFF, 0x0000, 0x1234, 0xFFFF, 0x8001, 0x4000, 0x0001];
+    let vt = [0x1111, 0x2222, 0x0002, 0x4444, 0x5555, 0x6666, 0x7777, 0x8888];
+    rsp.set_vreg(0, lanes(vs));
+    rsp.set_vreg(1, lanes(vt));
+    rsp.set_vreg(SpCop2::REG_VCO, 0);
+
+    // e=10 broadcasts lane 2 of vt.
+    let t = vt[2];
+    let mut sub = [0u16; 8];
+    let mut subc = [0u16; 8];
+    let mut vco = 0u16;
+    for i in 0..8 {
+        let diff = i32::from(vs[i] as i16) - i32::from(t as i16);
+        sub[i] = diff.max(-0x8000).min(0x7FFF) as u16;
+        subc[i] = vs[i].wrapping_sub(t);
+        if vs[i] < t {
+            vco |= 1 << i;
+        }
+        if vs[i] != t {
+            vco |= 1 << (i + 8);
+        }
+    }
+
+    rsp.run(&[vu(VSUB, 2, 0, 1, 10)]);
+    assert_eq!(rsp.vreg(2), lanes(sub));
+    rsp.run(&[vu(VSUBC, 3, 0, 1, 10)]);
+    assert_eq!(rsp.vreg(3), lanes(subc));
+    assert_eq!(rsp.vreg(SpCop2::REG_VCO), u128::from(vco));
+}
+
+#[test]
+#[should_panic(expected = "SP DMEM too small")]
+fn undersized_dmem() {
+    let mut rsp = TestRsp::new();
+    Sp::get_mut().dmem = Mem::new("sp::dmem::small", 0x800, MemFlags::default(), None);
+    rsp.run(&[lwc2(LQV, 1, 0, 0, 0)]);
+}
+
+#[test]
+fn ltv_odd_elements() {
+    let mut rsp = TestRsp::new();
+    for (i, b) in rsp.dmem()[0x100..0x110].iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    rsp.ctx.regs[1] = 0x100;
+
+    for &e in &[1usize, 3, 5, 7] {
+        for r in 8..16 {
+            rsp.set_vreg(r, 0);
+        }
+        rsp.run(&[lwc2(LTV, 8, e, 0, 1)]);
+
+        // The i-th write goes to lane i of register v8+(e/2+i)%8, and takes
+        // the two bytes starting at e+2*i, wrapping within the quadword.
+        let mut expected = [[0u16; 8]; 8];
+        for i in 0..8 {
+            let b0 = ((e + 2 * i) & 15) as u16;
+            let b1 = ((e + 2 * i + 1) & 15) as u16;
+            expected[(e / 2 + i) & 7][i] = (b0 << 8) | b1;
+        }
+        for r in 0..8 {
+            assert_eq!(rsp.vreg(8 + r), lanes(expected[r]), "e={} v{}", e, 8 + r);
+        }
+    }
+}
+
+#[test]
+fn ltv_stv_dmem_wrap() {
+    let mut rsp = TestRsp::new();
+    for i in 0..8 {
+        rsp.dmem()[0xFF8 + i] = i as u8;
+        rsp.dmem()[i] = 8 + i as u8;
+    }
+    rsp.ctx.regs[1] = 0xFF8;
+
+    // The quadword at 0xFF8 wraps to the start of DMEM. Bit 3 of the address
+    // rotates it by 8 bytes, so lane i of v8+i takes bytes 8+2*i and 9+2*i.
+    rsp.run(&[lwc2(LTV, 8, 0, 0, 1)]);
+    for i in 0..8 {
+        let mut expected = [0u16; 8];
+        let b0 = ((8 + 2 * i) & 15) as u16;
+        expected[i] = (b0 << 8) | (b0 + 1);
+        assert_eq!(rsp.vreg(8 + i), lanes(expected), "v{}", 8 + i);
+    }
+
+    // STV writes the transposed lanes back, wrapping in the same way.
+    for b in rsp.dmem().iter_mut() {
+        *b = 0;
+    }
+    rsp.run(&[swc2(STV, 8, 0, 0, 1)]);
+    assert_eq!(&rsp.dmem()[0xFF8..0x1000], &[8, 9, 10, 11, 12, 13, 14, 15]);
+    assert_eq!(&rsp.dmem()[0x0..0x8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn stv_dmem_wrap_via_bus() {
+    let mut rsp = TestRsp::new();
+    for i in 0..8 {
+        let mut l = [0u16; 8];
+        let b0 = ((8 + 2 * i) & 15) as u16;
+        l[i] = (b0 << 8) | (b0 + 1);
+        rsp.set_vreg(8 + i, lanes(l));
+    }
+    rsp.ctx.regs[1] = 0xFF8;
+
+    // Going through the bus, the bytes that wrap to the start of DMEM are
+    // written as well.
+    RSPCPU::get_mut().cop2.set_dmem_via_bus(true);
+    rsp.run(&[swc2(STV, 8, 0, 0, 1)]);
+    RSPCPU::get_mut().cop2.set_dmem_via_bus(false);
+    assert_eq!(&rsp.dmem()[0xFF8..0x1000], &[8, 9, 10, 11, 12, 13, 14, 15]);
+    assert_eq!(&rsp.dmem()[0x0..0x8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+#[test]
+fn vertex_transform() {
+    let mut rsp = TestRsp::new();
+
+    // Row-vector convention: out[k] = sum(v[j] * m[j][k]).
+    let m = [
+        [1.5, 0.25, 0.0, 0.0],
+        [0.75, 2.0, 0.5, 0.0],
+        [0.125, 1.0, 1.0, 0.0],
+        [10.5, 20.25, 3.0, 1.0],
+    ];
+    // Two vertices, transformed at once (lanes 0-3 and 4-7).
+    let verts = [[3.25, -2.75, 7.0, 1.0], [-100.0, 50.125, 0.375, 1.0]];
+
+    // Convert to s15.16, split into integer and fractional parts.
+    let fixed = |v: f64| (v * 65536.0) as i32;
+    let int = |v: f64| (fixed(v) >> 16) as u16;
+    let frac = |v: f64| fixed(v) as u16;
+
+    // Matrix rows in DMEM: 0x00 integer parts, 0x40 fractional parts. Each
+    // row is repeated in both halves of the register. Vertices at 0x80/0x90.
+    let dmem = rsp.dmem();
+    for j in 0..4 {
+        let row = |f: &dyn Fn(f64) -> u16| {
+            let mut l = [0u16; 8];
+            for (i, x) in l.iter_mut().enumerate() {
+                *x = f(m[j][i & 3]);
+            }
+            lanes(l)
+        };
+        dmem[j * 0x10..j * 0x10 + 0x10].copy_from_slice(&row(&int).to_be_bytes());
+        dmem[0x40 + j * 0x10..0x50 + j * 0x10].copy_from_slice(&row(&frac).to_be_bytes());
+    }
+    let vtx = |f: &dyn Fn(f64) -> u16| {
+        let mut l = [0u16; 8];
+        for (i, x) in l.iter_mut().enumerate() {
+            *x = f(verts[i / 4][i & 3]);
+        }
+        lanes(l)
+    };
+    dmem[0x80..0x90].copy_from_slice(&vtx(&int).to_be_bytes());
+    dmem[0x90..0xA0].copy_from_slice(&vtx(&frac).to_be_bytes());
+
+    // v0-v3: matrix integer rows, v4-v7: matrix fractional rows,
+    // v8: vertex integer parts, v9: vertex fractional parts.
+    rsp.ctx.regs[1] = 0;
+    let mut ops: Vec<u32> = (0..10).map(|r| lwc2(LQV, r, 0, r as i32, 1)).collect();
+    for j in 0..4 {
+        let e = 4 + j; // broadcast component j of each vertex
+        ops.push(vu(if j == 0 { VMUDL } else { VMADL }, 12, 4 + j, 9, e));
+        ops.push(vu(VMADM, 12, j, 9, e));
+        ops.push(vu(VMADN, 11, 4 + j, 8, e));
+        ops.push(vu(VMADH, 10, j, 8, e));
+    }
+    ops.extend(&[
+        vu(VSAR, 13, 0, 0, 8),
+        vu(VSAR, 14, 0, 0, 9),
+        vu(VSAR, 15, 0, 0, 10),
+        swc2(SQV, 10, 0, 16, 1),
+        swc2(SQV, 11, 0, 17, 1),
+        swc2(SQV, 13, 0, 18, 1),
+        swc2(SQV, 14, 0, 19, 1),
+        swc2(SQV, 15, 0, 20, 1),
+    ]);
+    rsp.run(&ops);
+
+    let out = |addr: usize, lane: usize| {
+        let dmem = rsp.dmem();
+        u16::from_be_bytes([dmem[addr + lane * 2], dmem[addr + lane * 2 + 1]])
+    };
+    for (n, v) in verts.iter().enumerate() {
+        for k in 0..4 {
+            // Reference: the exact sum of the partial products, with the
+            // low product truncated as done by VMADL.
+            let mut acc: i64 = 0;
+            for j in 0..4 {
+                let (mi, mf) = (i64::from(int(m[j][k]) as i16), i64::from(frac(m[j][k])));
+                let (vi, vf) = (i64::from(int(v[j]) as i16), i64::from(frac(v[j])));
+                acc += ((mi * vi) << 16) + mi * vf + mf * vi + ((mf * vf) >> 16);
+            }
+            let expected: f64 = (0..4).map(|j| v[j] * m[j][k]).sum();
+            assert!((acc as f64 / 65536.0 - expected).abs() <= 4.0 / 65536.0);
+
+            let lane = n * 4 + k;
+            assert_eq!(out(0x100, lane), (acc >> 16) as u16, "int {}", lane);
+            assert_eq!(out(0x110, lane), acc as u16, "frac {}", lane);
+            assert_eq!(out(0x120, lane), (acc >> 32) as u16, "acc hi {}", lane);
+            assert_eq!(out(0x130, lane), (acc >> 16) as u16, "acc md {}", lane);
+            assert_eq!(out(0x140, lane), acc as u16, "acc lo {}", lane);
+        }
+    }
+}
+
+// A straight-line block of computational opcodes, used to compare per-op
+// and per-block dispatch.
+fn sample_block() -> Vec<u32> {
+    vec![
+        vu(VMULF, 2, 0, 1, 0),
+        vu(VMACF, 3, 1, 2, 8),
+        vu(VADD, 4, 2, 3, 2),
+        vu(VSUB, 5, 4, 0, 0),
+        vu(VAND, 6, 5, 1, 4),
+        vu(VOR, 7, 6, 2, 0),
+        vu(VXOR, 8, 7, 3, 9),
+        vu(VLT, 9, 8, 4, 0),
+    ]
+}
+
+fn vu_state(rsp: &TestRsp) -> Vec<u128> {
+    (0..=SpCop2::REG_ACCUM_HI).map(|r| rsp.vreg(r)).collect()
+}
+
+#[test]
+fn run_block_matches_op() {
+    let mut rsp = TestRsp::new();
+    let init = |rsp: &mut TestRsp| {
+        for r in 0..=SpCop2::REG_ACCUM_HI {
+            rsp.set_vreg(r, 0);
+        }
+        rsp.set_vreg(0, lanes([0x7FFF, 0x8000, 0x1234, 0xFFFF, 0x0001, 0x4000, 0xC000, 0x0000]));
+        rsp.set_vreg(1, lanes([0x7FFF, 0x7FFF, 0x5678, 0x0001, 0xFFFF, 0x4000, 0x4000, 0x8000]));
+    };
+
+    init(&mut rsp);
+    rsp.run(&sample_block());
+    let expected = vu_state(&rsp);
+
+    init(&mut rsp);
+    {
+        let cpu = &mut **RSPCPU::get_mut();
+        cpu.cop2
+            .run_block(&mut rsp.ctx, &sample_block(), &Tracer::null())
+            .unwrap();
+    }
+    assert_eq!(vu_state(&rsp), expected);
+}
+
+#[test]
+fn vrcp_sign_and_special_inputs() {
+    let mut rsp = TestRsp::new();
+    let x = [0x0000, 0x8000, 0x0002, 0xFFFE, 0x1234, 0xEDCC, 0x7FFF, 0x8001];
+    rsp.set_vreg(1, lanes(x));
+    for i in 0..8 {
+        rsp.run(&[vu(VRCP, 2, i, 1, 8 + i), vu(VRCPH, 3, i, 1, 8 + i)]);
+    }
+    let lo = rsp.vreg(2).to_be_bytes();
+    let hi = rsp.vreg(3).to_be_bytes();
+    let res: Vec<u32> = (0..8)
+        .map(|i| u32::from_be_bytes([hi[i * 2], hi[i * 2 + 1], lo[i * 2], lo[i * 2 + 1]]))
+        .collect();
+
+    // 0 and -32768 are special-cased.
+    assert_eq!(res[0], 0x7FFF_FFFF);
+    assert_eq!(res[1], 0xFFFF_0000);
+
+    // A negative input gives the one's complement of the result of its
+    // absolute value.
+    assert_eq!(res[2], 0x3FFF_E000);
+    for i in (2..8).step_by(2) {
+        assert_eq!(res[i + 1], !res[i], "x={:04x}", x[i + 1]);
+    }
+}
+
+#[test]
+fn div_out_register() {
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(1, lanes([0, 0, 0x0002, 0, 0, 0, 0, 0]));
+
+    // VRCP leaves the whole 32-bit result in DivOut, and VRCPH returns its
+    // high half.
+    rsp.run(&[vu(VRCP, 2, 0, 1, 10)]);
+    assert_eq!(rsp.vreg(SpCop2::REG_DIV_OUT), 0x3FFF_E000);
+    rsp.run(&[vu(VRCPH, 3, 0, 1, 8)]);
+    assert_eq!(rsp.vreg(3) >> 112, 0x3FFF);
+
+    // A restored DivOut is seen by VRCPH.
+    rsp.set_vreg(SpCop2::REG_DIV_OUT, 0x1234_5678);
+    rsp.run(&[vu(VRCPH, 3, 1, 1, 8)]);
+    assert_eq!(rsp.vreg(3), lanes([0x3FFF, 0x1234, 0, 0, 0, 0, 0, 0]));
+    assert_eq!("div_out".parse(), Ok(Cop2Reg::DivOut));
+}
+
+#[test]
+fn single_lane_ops_write_de() {
+    const VMOV: u32 = 0x33;
+    let mut rsp = TestRsp::new();
+    let old = lanes([0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777, 0x8888]);
+    rsp.set_vreg(1, lanes([0, 0, 0, 2, 0, 0, 0, 0]));
+
+    // The destination element is the low 3 bits of the vs field: only that
+    // lane of vd is written, whatever the source element.
+    let lane = |v: u128, i: usize| (v >> (112 - i * 16)) as u16;
+    for de in 0..8 {
+        for &(func, res) in &[(VRCP, 0xE000), (VRCPH, 0x3FFF), (VMOV, 0x0002)] {
+            for &vs in &[de, de | 0x18] {
+                rsp.set_vreg(2, old);
+                rsp.run(&[vu(func, 2, vs, 1, 8 + 3)]);
+                let vd = rsp.vreg(2);
+                for i in 0..8 {
+                    let exp = if i == de { res } else { lane(old, i) };
+                    assert_eq!(lane(vd, i), exp, "func={:x} vs={} lane={}", func, vs, i);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn seeded_accumulator_vmacf() {
+    let mut rsp = TestRsp::new();
+    let seed = [
+        0x0000_1234_0000,
+        -0x1_0000,
+        0x0000_7FFF_0000,
+        -0x7FFF_0000,
+        0x7FFF_FFFF_FFFF,
+        -0x8000_0000_0000,
+        0x0000_0000_8000,
+        0,
+    ];
+    RSPCPU::get_mut().cop2.set_accumulators(&seed);
+    for (i, &acc) in seed.iter().enumerate() {
+        assert_eq!(RSPCPU::get().cop2.accumulator(i), acc);
+    }
+
+    // VMACF adds the doubled product (0x4000 * 0x4000 * 2 = 0x2000_0000) to
+    // the seeded accumulator, and clamps its middle part to 16 bits.
+    rsp.set_vreg(1, lanes([0x4000; 8]));
+    rsp.set_vreg(2, lanes([0x4000, 0, 0x4000, 0xC000, 0, 0, 0x4000, 0x4000]));
+    rsp.run(&[vu(VMACF, 3, 1, 2, 0)]);
+    let acc: Vec<i64> = (0..8).map(|i| RSPCPU::get().cop2.accumulator(i)).collect();
+    let exp_acc = [
+        0x0000_3234_0000,
+        -0x1_0000,
+        0x0000_9FFF_0000,
+        -0x9FFF_0000,
+        0x7FFF_FFFF_FFFF,
+        -0x8000_0000_0000,
+        0x0000_2000_8000,
+        0x0000_2000_0000,
+    ];
+    assert_eq!(acc, exp_acc);
+    let exp_vd = [0x3234, 0xFFFF, 0x7FFF, 0x8000, 0x7FFF, 0x8000, 0x2000, 0x2000];
+    assert_eq!(rsp.vreg(3), lanes(exp_vd));
+}
+
+// Reference VMULU: the accumulator is set to vs*vt*2 + 0.5, and vd is its
+// middle part clamped as an unsigned value (negative values give 0).
+fn vmulu_ref(vs: u16, vt: u16) -> (u16, i64) {
+    let acc = i64::from(vs as i16) * i64::from(vt as i16) * 2 + 0x8000;
+    let res = if acc < 0 {
+        0
+    } else if acc >> 16 > 0x7FFF {
+        0xFFFF
+    } else {
+        (acc >> 16) as u16
+    };
+    (res, acc)
+}
+
+#[test]
+fn vmulu_clamp_boundaries() {
+    let mut rsp = TestRsp::new();
+    let mut check = |vs: [u16; 8], vt: [u16; 8]| {
+        rsp.set_vreg(1, lanes(vs));
+        rsp.set_vreg(2, lanes(vt));
+        rsp.run(&[vu(VMULU, 3, 1, 2, 0)]);
+        let mut exp_vd = [0u16; 8];
+        for (i, (&s, &t)) in vs.iter().zip(vt.iter()).enumerate() {
+            let (res, acc) = vmulu_ref(s, t);
+            exp_vd[i] = res;
+            assert_eq!(RSPCPU::get().cop2.accumulator(i), acc, "{:x}*{:x}", s, t);
+        }
+        assert_eq!(rsp.vreg(3), lanes(exp_vd), "{:x?}*{:x?}", vs, vt);
+        exp_vd
+    };
+
+    // Products around zero (accumulator 0x8000, exactly 0, and -2), and
+    // around the 0x7FFF/0xFFFF boundary.
+    let vs = [0, 0xFFFF, 0xFFFF, 0x7FFF, 0x8000, 0x8000, 0x7FFF, 1];
+    let vt = [0x1234, 0x4000, 0x4001, 0x7FFF, 0x8001, 0x8000, 0x8000, 1];
+    assert_eq!(check(vs, vt), [0, 0, 0, 0x7FFE, 0x7FFF, 0xFFFF, 0, 0]);
+
+    let mut rng = XorShift(0x5EED_0691);
+    for _ in 0..64 {
+        let (mut vs, mut vt) = ([0u16; 8], [0u16; 8]);
+        for (s, t) in vs.iter_mut().zip(vt.iter_mut()) {
+            *s = rng.next() as u16;
+            *t = rng.next() as u16;
+        }
+        check(vs, vt);
+    }
+}
+
+#[test]
+fn vregs_flat_buffer() {
+    let mut rsp = TestRsp::new();
+    let mut buf = [0u8; 512];
+    XorShift(0x0694).fill(&mut buf);
+    RSPCPU::get_mut().cop2.import_vregs(&buf);
+    assert_eq!(&RSPCPU::get().cop2.export_vregs()[..], &buf[..]);
+    assert_eq!(rsp.vreg(7).to_be_bytes(), buf[7 * 16..8 * 16]);
+
+    // Each register has the same byte order as in DMEM after SQV.
+    rsp.run(&[swc2(SQV, 5, 0, 0, 0), swc2(SQV, 31, 0, 1, 0)]);
+    assert_eq!(rsp.dmem()[0x00..0x10], buf[5 * 16..6 * 16]);
+    assert_eq!(rsp.dmem()[0x10..0x20], buf[31 * 16..32 * 16]);
+}
+
+#[test]
+fn vu_stall_cycles() {
+    // VRCPH reads the result of VRCP from the divider, even when it works on
+    // different registers.
+    let vrcp = vu(VRCP, 2, 0, 1, 8);
+    assert_eq!(SpCop2::stall_cycles(vrcp, vu(VRCPH, 3, 0, 4, 8)), 3);
+    assert_eq!(SpCop2::stall_cycles(vrcp, vu(VADD, 5, 6, 7, 0)), 0);
+    assert_eq!(SpCop2::stall_cycles(vrcp, vu(VADD, 5, 2, 7, 0)), 3);
+
+    // VMADH accumulates on the result of VMUDH.
+    let vmudh = vu(VMUDH, 2, 0, 1, 0);
+    assert_eq!(SpCop2::stall_cycles(vmudh, vu(VMADH, 3, 4, 5, 0)), 2);
+    assert_eq!(SpCop2::stall_cycles(vmudh, vu(VSAR, 3, 0, 0, 8)), 2);
+    assert_eq!(SpCop2::stall_cycles(vmudh, vu(VAND, 3, 4, 5, 0)), 0);
+    assert_eq!(SpCop2::stall_cycles(vmudh, swc2(SQV, 2, 0, 0, 1)), 2);
+
+    // Single-cycle ops never stall.
+    assert_eq!(SpCop2::latency(vu(VADD, 5, 6, 7, 0)), 1);
+    assert_eq!(SpCop2::stall_cycles(vu(VADD, 5, 6, 7, 0), vu(VADD, 8, 5, 5, 0)), 0);
+}
+
+#[test]
+fn vnull_timing() {
+    // VNULL is a single-cycle op that touches neither its registers nor the
+    // divider, like the reserved opcodes next to it.
+    let vrcp = vu(VRCP, 2, 0, 1, 8);
+    for func in 0x37..=VNULL {
+        let op = vu(func, 2, 3, 2, 8);
+        assert_eq!(SpCop2::latency(op), 1, "func {:#x}", func);
+        assert_eq!(SpCop2::stall_cycles(vrcp, op), 0, "func {:#x}", func);
+        assert_eq!(SpCop2::stall_cycles(op, vu(VRCPH, 2, 0, 2, 8)), 0, "func {:#x}", func);
+    }
+}
+
+#[test]
+fn cop2_reset() {
+    let mut rsp = TestRsp::new();
+    for r in 0..=SpCop2::REG_ACCUM_HI {
+        rsp.set_vreg(r, lanes([0x4000; 8]));
+    }
+    // Leave a reciprocal result in the divider.
+    rsp.run(&[vu(VRCP, 2, 0, 1, 8), vu(VRCPH, 3, 0, 1, 8)]);
+    assert_ne!(rsp.vreg(3) >> 112, 0x4000);
+
+    RSPCPU::get_mut().cop2.reset();
+    assert!(vu_state(&rsp).iter().all(|&r| r == 0));
+
+    // VRCPH reads back the (now cleared) high half of the last result.
+    rsp.run(&[vu(VRCPH, 2, 0, 0, 8)]);
+    assert_eq!(rsp.vreg(2), 0);
+}
+
+thread_local! {
+    // Benchmarks may run on the same thread, and SpCop2 can only be created
+    // once per thread, so share it.
+    static BENCH_COP2: RefCell<SpCop2> =
+        RefCell::new(SpCop2::new("bench", slog::Logger::root(Discard, o!())).unwrap());
+}
+
+#[bench]
+fn bench_op_dispatch(b: &mut Bencher) {
+    let ops = sample_block();
+    let mut ctx = CpuContext::default();
+    let t = Tracer::null();
+    BENCH_COP2.with(|cop2| {
+        let cop2 = &mut *cop2.borrow_mut();
+        b.iter(|| {
+            for &op in &ops {
+                cop2.op(&mut ctx, op, &t).unwrap();
+            }
+        });
+    });
+}
+
+#[bench]
+fn bench_block_dispatch(b: &mut Bencher) {
+    let ops = sample_block();
+    let mut ctx = CpuContext::default();
+    let t = Tracer::null();
+    BENCH_COP2.with(|cop2| {
+        let cop2 = &mut *cop2.borrow_mut();
+        b.iter(|| cop2.run_block(&mut ctx, &ops, &t).unwrap());
+    });
+}
+
+#[test]
+fn scalar_matches_simd() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut simd = SpCop2::standalone("simd", logger.new(o!())).unwrap();
+    let mut scalar = SpCop2::standalone("scalar", logger).unwrap();
+    scalar.disable_simd();
+    let mut ctx = CpuContext::default();
+    let t = Tracer::null();
+    let mut rng = XorShift(0x5CA1_AB1E_0DDB_A115);
+
+    // Random operands for a computational opcode. Registers are picked among
+    // the first eight, so that VD often aliases VS or VT.
+    let random_op = |rng: &mut XorShift, func: u32| {
+        let r = rng.next() as usize;
+        vu(func, r & 7, (r >> 3) & 7, (r >> 6) & 7, (r >> 9) & 15)
+    };
+
+    for round in 0..256 {
+        // Random state, with a quarter of the lanes set to boundary values
+        // to exercise saturation and clamping.
+        let mut vregs = [0u8; 512];
+        rng.fill(&mut vregs);
+        for lane in vregs.chunks_exact_mut(2) {
+            let r = rng.next();
+            if r & 3 == 0 {
+                let val: u16 = [0, 1, 0x7FFF, 0x8000, 0xFFFF][(r >> 2) as usize % 5];
+                lane.copy_from_slice(&val.to_be_bytes());
+            }
+        }
+        let mut accs = [0i64; 8];
+        for acc in accs.iter_mut() {
+            *acc = rng.next() as i64 >> 16;
+        }
+        let flags = [rng.next(), rng.next(), rng.next()];
+        let mut setup = |cop2: &mut SpCop2| {
+            cop2.import_vregs(&vregs);
+            cop2.set_accumulators(&accs);
+            cop2.set_reg(&mut ctx, SpCop2::REG_VCO, flags[0] as u128);
+            cop2.set_reg(&mut ctx, SpCop2::REG_VCC, flags[1] as u128);
+            cop2.set_reg(&mut ctx, SpCop2::REG_VCE, flags[2] as u128);
+            cop2.set_strict_hw(round % 2 == 0);
+        };
+        setup(&mut simd);
+        setup(&mut scalar);
+
+        // Each opcode runs on the state left by the previous ones, so that
+        // flags and divider state are also produced by the opcodes.
+        for &func in SpCop2::supported_ops() {
+            let op = random_op(&mut rng, func as u32);
+            simd.op_vector_only(op).unwrap();
+            scalar.op_vector_only(op).unwrap();
+            let (a, b) = (simd.snapshot(), scalar.snapshot());
+            assert!(a == b, "op={:08x}: {}", op, a.diff(&b).unwrap());
+        }
+
+        // Blocks go through the scalar implementation too.
+        let funcs = SpCop2::supported_ops();
+        let block: Vec<u32> = (0..4)
+            .map(|_| {
+                let func = funcs[rng.next() as usize % funcs.len()];
+                random_op(&mut rng, func as u32)
+            })
+            .collect();
+        simd.run_block(&mut ctx, &block, &t).unwrap();
+        scalar.run_block(&mut ctx, &block, &t).unwrap();
+        let (a, b) = (simd.snapshot(), scalar.snapshot());
+        assert!(a == b, "block={:08x?}: {}", block, a.diff(&b).unwrap());
+    }
+    assert_eq!(scalar.take_error(), None);
+}
+
+#[test]
+fn vmadn_vmadl_accumulate_clamp() {
+    let mut rsp = TestRsp::new();
+    let lane = |v: u128, i: usize| (v >> (112 - i * 16)) as u16;
+    let acc48 = |hi: u16, md: u16, lo: u16| {
+        let v = (u64::from(hi) << 32) | (u64::from(md) << 16) | u64::from(lo);
+        ((v << 16) as i64) >> 16
+    };
+    // Unsigned clamp of the low slice, on the signed 32-bit value of the
+    // mid/high slices.
+    let clamp = |acc: i64| match acc >> 16 {
+        x if x < -0x8000 => 0,
+        x if x > 0x7FFF => 0xFFFF,
+        _ => acc as u16,
+    };
+
+    // Each lane: accumulator (hi, md, lo), vs, vt.
+    let vmadn_cases: [((u16, u16, u16), u16, u16); 8] = [
+        ((0x0000, 0x0000, 0xFFFF), 0x0001, 0x0001), // carry from lo into md
+        ((0x0000, 0xFFFF, 0xFFFF), 0x0001, 0x0001), // carry from md into hi
+        ((0x0000, 0x0000, 0x0000), 0xFFFF, 0x7FFF), // vs is unsigned
+        ((0x0000, 0x0000, 0x0000), 0xFFFF, 0xFFFF), // vt is signed: borrow
+        ((0xFFFF, 0x8000, 0x0000), 0x0001, 0xFFFF), // below -0x8000: 0
+        ((0x0000, 0x7FFF, 0xFFFF), 0x0001, 0x0001), // above 0x7FFF: 0xFFFF
+        ((0x7FFF, 0xFFFF, 0xFFFF), 0x0001, 0x0001), // 48-bit wrap
+        ((0x0000, 0x0001, 0x1234), 0x8000, 0xFFFE), // borrow from md
+    ];
+    let vmadl_cases: [((u16, u16, u16), u16, u16); 8] = [
+        ((0x0000, 0x0000, 0xFFFF), 0xFFFF, 0xFFFF), // carry from lo into md
+        ((0x0000, 0xFFFF, 0xFFFF), 0x0100, 0x0100), // carry from md into hi
+        ((0xFFFF, 0xFFFF, 0xFFFF), 0x0100, 0x0100), // -1 + 1
+        ((0xFFFF, 0xFFFF, 0xFFF0), 0x8000, 0x0010), // negative, in range
+        ((0x0000, 0x7FFF, 0xFFFF), 0x0100, 0x0100), // above 0x7FFF: 0xFFFF
+        ((0xFFFF, 0x8000, 0x5555), 0x0000, 0x1234), // exactly -0x8000: kept
+        ((0x0000, 0x0000, 0x0000), 0xFFFF, 0x0001), // product truncated away
+        ((0x1234, 0x5678, 0x9ABC), 0xFFFF, 0xFFFF), // both operands unsigned
+    ];
+
+    for &(func, cases) in &[(VMADN, &vmadn_cases), (VMADL, &vmadl_cases)] {
+        let field = |f: &dyn Fn(&((u16, u16, u16), u16, u16)) -> u16| {
+            let mut l = [0u16; 8];
+            for (x, c) in l.iter_mut().zip(cases.iter()) {
+                *x = f(c);
+            }
+            lanes(l)
+        };
+        rsp.set_vreg(SpCop2::REG_ACCUM_HI, field(&|c| (c.0).0));
+        rsp.set_vreg(SpCop2::REG_ACCUM_MD, field(&|c| (c.0).1));
+        rsp.set_vreg(SpCop2::REG_ACCUM_LO, field(&|c| (c.0).2));
+        rsp.set_vreg(1, field(&|c| c.1));
+        rsp.set_vreg(2, field(&|c| c.2));
+        rsp.run(&[vu(func, 3, 1, 2, 0)]);
+
+        for (i, &((hi, md, lo), vs, vt)) in cases.iter().enumerate() {
+            let prod = if func == VMADN {
+                i64::from(vs) * i64::from(vt as i16)
+            } else {
+                (i64::from(vs) * i64::from(vt)) >> 16
+            };
+            let acc = ((acc48(hi, md, lo) + prod) << 16) >> 16;
+            let res = (
+                lane(rsp.vreg(SpCop2::REG_ACCUM_HI), i),
+                lane(rsp.vreg(SpCop2::REG_ACCUM_MD), i),
+                lane(rsp.vreg(SpCop2::REG_ACCUM_LO), i),
+            );
+            let expected = ((acc >> 32) as u16, (acc >> 16) as u16, acc as u16);
+            assert_eq!(res, expected, "acc {:x} {}", func, i);
+            assert_eq!(lane(rsp.vreg(3), i), clamp(acc), "vd {:x} {}", func, i);
+        }
+    }
+
+    // Spot-check the carry lanes of VMADL against hand-computed values.
+    let acc_md = rsp.vreg(SpCop2::REG_ACCUM_MD);
+    let acc_hi = rsp.vreg(SpCop2::REG_ACCUM_HI);
+    assert_eq!((lane(acc_hi, 0), lane(acc_md, 0)), (0, 1));
+    assert_eq!((lane(acc_hi, 1), lane(acc_md, 1)), (1, 0));
+    assert_eq!(lane(rsp.vreg(3), 0), 0xFFFD);
+    assert_eq!(lane(rsp.vreg(3), 1), 0xFFFF);
+}
+
+#[test]
+fn oplog_record_replay() {
+    let mut rsp = TestRsp::new();
+    for i in 0..16 {
+        rsp.dmem()[0x40 + i] = 0x30 + i as u8;
+    }
+    // State before the recording is captured as well.
+    rsp.set_vreg(1, lanes([0x4000, 0x2000, 0x1000, 0x0800, 0xC000, 0xE000, 0xF000, 0x7FFF]));
+
+    RSPCPU::get_mut().cop2.start_recording();
+    rsp.ctx.regs[1] = 0x40;
+    rsp.ctx.regs[2] = 0x1234;
+    rsp.ctx.regs[3] = 0x00F0;
+    rsp.run(&[
+        lwc2(LQV, 2, 0, 0, 1),
+        cop2_move(MTC2, 2, 3, 4),
+        cop2_move(CTC2, 3, 1, 0), // VCC
+        vu(VMRG, 4, 2, 1, 0),
+        vu(VMULF, 5, 2, 3, 0),
+        swc2(SQV, 5, 0, 1, 1),
+    ]);
+    let log = RSPCPU::get_mut().cop2.stop_recording().unwrap();
+    assert_eq!(log.entries().len(), 6);
+    let ctc2 = OpLogEntry::Op {
+        opcode: cop2_move(CTC2, 3, 1, 0),
+        gpr: 0xF0,
+    };
+    assert_eq!(log.entries()[2], ctc2);
+    let expected: Vec<u128> = (1..6).map(|r| rsp.vreg(r)).collect();
+
+    let path = std::env::temp_dir().join(format!("r64emu-oplog-{}.json", std::process::id()));
+    log.save(&path).unwrap();
+
+    // Replay on a clean vector unit and DMEM.
+    RSPCPU::get_mut().cop2.reset();
+    for b in rsp.dmem().iter_mut() {
+        *b = 0;
+    }
+    let loaded = OpLog::load(&path).unwrap();
+    assert_eq!(loaded, log);
+    {
+        let cpu = &mut **RSPCPU::get_mut();
+        loaded.replay(&mut cpu.cop2, &mut cpu.bus).unwrap();
+    }
+    let got: Vec<u128> = (1..6).map(|r| rsp.vreg(r)).collect();
+    assert_eq!(got, expected);
+    assert_eq!(&rsp.dmem()[0x50..0x60], &expected[4].to_be_bytes()[..]);
+
+    // A log whose inputs were altered doesn't reach the recorded state.
+    let json = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, json.replace("\"gpr\":4660", "\"gpr\":4661")).unwrap();
+    let altered = OpLog::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_ne!(altered, log);
+    let cpu = &mut **RSPCPU::get_mut();
+    assert!(altered.replay(&mut cpu.cop2, &mut cpu.bus).is_err());
+}
+
+#[test]
+fn oplog_replay_standalone() {
+    let mut rsp = TestRsp::new();
+    for i in 0..16 {
+        rsp.dmem()[0x40 + i] = 0x30 + i as u8;
+    }
+    RSPCPU::get_mut().cop2.start_recording();
+    rsp.ctx.regs[1] = 0x40;
+    rsp.run(&[lwc2(LQV, 2, 0, 0, 1), vu(VADD, 3, 2, 2, 0), swc2(SQV, 3, 0, 1, 1)]);
+    let log = RSPCPU::get_mut().cop2.stop_recording().unwrap();
+
+    // A standalone unit reads the recorded loads from its private DMEM.
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::standalone("test", logger.new(o!())).unwrap();
+    let mut bus = Bus::new(logger);
+    log.replay(&mut cop2, &mut bus).unwrap();
+    let ctx = CpuContext::default();
+    assert_eq!(cop2.reg(&ctx, 2), rsp.vreg(2));
+    assert_eq!(cop2.reg(&ctx, 3), rsp.vreg(3));
+    let dmem = cop2.dmem_mut().unwrap();
+    assert_eq!(&dmem[0x40..0x60], &rsp.dmem()[0x40..0x60]);
+}
+
+#[test]
+fn lfv_sfv_partial_transfer() {
+    let mut rsp = TestRsp::new();
+    for i in 0..16 {
+        rsp.dmem()[0x200 + i] = ((i as u8) << 4) | 1;
+    }
+    let old = lanes([0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777, 0x8888]);
+    rsp.ctx.regs[1] = 0x200;
+
+    // Element 0 loads lanes 0-3 from every fourth byte, element 8 loads lanes
+    // 4-7. The other lanes are preserved.
+    rsp.set_vreg(2, old);
+    rsp.set_vreg(3, old);
+    rsp.run(&[lwc2(LFV, 2, 0, 0, 1), lwc2(LFV, 3, 8, 0, 1)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x0080, 0x2080, 0x4080, 0x6080, 0x5555, 0x6666, 0x7777, 0x8888])
+    );
+    assert_eq!(
+        rsp.vreg(3),
+        lanes([0x1111, 0x2222, 0x3333, 0x4444, 0x0080, 0x2080, 0x4080, 0x6080])
+    );
+
+    // SFV only writes every fourth byte of the quadword.
+    let v1 = lanes([0x1080, 0x2100, 0x3180, 0x4200, 0x5280, 0x6300, 0x7380, 0x0400]);
+    rsp.set_vreg(1, v1);
+    for b in rsp.dmem()[0x2F0..0x330].iter_mut() {
+        *b = 0xAA;
+    }
+    rsp.ctx.regs[1] = 0x300;
+    rsp.run(&[swc2(SFV, 1, 0, 0, 1), swc2(SFV, 1, 8, 1, 1)]);
+    let mut expected = [0xAAu8; 0x40];
+    for (i, &b) in [0x21, 0x42, 0x63, 0x84].iter().enumerate() {
+        expected[0x10 + i * 4] = b;
+    }
+    for (i, &b) in [0xA5, 0xC6, 0xE7, 0x08].iter().enumerate() {
+        expected[0x20 + i * 4] = b;
+    }
+    assert_eq!(&rsp.dmem()[0x2F0..0x330], &expected[..]);
+}
+
+#[test]
+fn cop2_reg_names() {
+    let mut rsp = TestRsp::new();
+    let mut count = 0;
+    for idx in 0..64 {
+        let reg = match Cop2Reg::from_index(idx) {
+            Some(reg) => reg,
+            None => continue,
+        };
+        count += 1;
+        let name = reg.to_string();
+        assert_eq!(name.parse::<Cop2Reg>(), Ok(reg), "{}", name);
+        assert_eq!(reg.index(), idx, "{}", name);
+    }
+    assert_eq!(count, 39);
+
+    assert_eq!("v3".parse::<Cop2Reg>().unwrap().index(), 3);
+    assert_eq!("v31".parse(), Ok(Cop2Reg::V(31)));
+    assert_eq!("acc_lo".parse::<Cop2Reg>().unwrap().index(), SpCop2::REG_ACCUM_LO);
+    assert_eq!(Cop2Reg::AccHi.to_string(), "acc_hi");
+    for bad in &["", "v32", "V3", "acc", "vcx", "r3"] {
+        assert!(bad.parse::<Cop2Reg>().is_err(), "{}", bad);
+    }
+
+    // Indices address the registers through the Cop interface.
+    let vcc = "vcc".parse::<Cop2Reg>().unwrap().index();
+    rsp.set_vreg(vcc, 0x1234);
+    assert_eq!(rsp.vreg(vcc), 0x1234);
+}
+
+#[test]
+fn vaddc_clears_ne() {
+    let mut rsp = TestRsp::new();
+    let vals = [0x0000, 0x0001, 0x1234, 0x7FFF, 0x8000, 0x8001, 0xFFFE, 0xFFFF];
+
+    // Each run adds one value of vs to all the values of vt.
+    for &a in &vals {
+        rsp.set_vreg(0, lanes([a; 8]));
+        rsp.set_vreg(1, lanes(vals));
+        rsp.set_vreg(SpCop2::REG_VCO, 0xFFFF);
+        rsp.run(&[vu(VADDC, 2, 0, 1, 0)]);
+
+        let mut res = [0u16; 8];
+        let mut carry = 0u128;
+        for (i, &b) in vals.iter().enumerate() {
+            let sum = u32::from(a) + u32::from(b);
+            res[i] = sum as u16;
+            if sum > 0xFFFF {
+                carry |= 1 << i;
+            }
+        }
+        assert_eq!(rsp.vreg(2), lanes(res), "vs={:x}", a);
+        // NE (bits 8-15) is always cleared, carry is the unsigned overflow.
+        assert_eq!(rsp.vreg(SpCop2::REG_VCO), carry, "vs={:x}", a);
+    }
+}
+
+#[test]
+fn cop2_reg_out_of_range() {
+    let logger = slog::Logger::root(Discard, o!());
+    let mut cop2 = SpCop2::new("test", logger).unwrap();
+    let mut ctx = CpuContext::default();
+
+    for idx in 0..=SpCop2::REG_ACCUM_HI {
+        cop2.set_reg(&mut ctx, idx, 0x12);
+    }
+
+    // Invalid indices read as zero, and writing them changes nothing.
+    assert_eq!(cop2.reg(&ctx, 40), 0);
+    cop2.set_reg(&mut ctx, 40, !0);
+    assert_eq!(cop2.reg(&ctx, 40), 0);
+    for idx in 0..=SpCop2::REG_ACCUM_HI {
+        assert_eq!(cop2.reg(&ctx, idx), 0x12, "reg={}", idx);
+    }
+}
+
+#[test]
+fn vmudh_vmadh_accumulator_halves() {
+    let mut rsp = TestRsp::new();
+    let a = [0x0000, 0x0001, 0x7FFF, 0x8000, 0x8000, 0x1234, 0xFFFF, 0xC000];
+    let b = [0x1234, 0xFFFF, 0x7FFF, 0x8001, 0x7FFF, 0x0010, 0xFFFF, 0x4000];
+    rsp.set_vreg(0, lanes(a));
+    rsp.set_vreg(1, lanes(b));
+    // Stale accumulator contents must be entirely replaced by VMUDH.
+    rsp.set_vreg(SpCop2::REG_ACCUM_LO, lanes([0xAAAA; 8]));
+    rsp.set_vreg(SpCop2::REG_ACCUM_MD, lanes([0x5555; 8]));
+    rsp.set_vreg(SpCop2::REG_ACCUM_HI, lanes([0xAAAA; 8]));
+
+    // Scalar model: the product is placed at bits 16-47 of the 48-bit
+    // accumulator, and vd is the signed clamp of bits 16-47.
+    let check = |rsp: &mut TestRsp, n: i64, what: &str| {
+        rsp.run(&[vu(VSAR, 3, 0, 0, 8), vu(VSAR, 4, 0, 0, 9), vu(VSAR, 5, 0, 0, 10)]);
+        let (mut res, mut hi, mut md, mut lo) = ([0u16; 8], [0u16; 8], [0u16; 8], [0u16; 8]);
+        for i in 0..8 {
+            let acc = (i64::from(a[i] as i16) * i64::from(b[i] as i16) * n) << 16;
+            res[i] = (acc >> 16).max(-0x8000).min(0x7FFF) as u16;
+            hi[i] = (acc >> 32) as u16;
+            md[i] = (acc >> 16) as u16;
+            lo[i] = acc as u16;
+        }
+        assert_eq!(rsp.vreg(2), lanes(res), "{}", what);
+        assert_eq!(rsp.vreg(3), lanes(hi), "{}", what);
+        assert_eq!(rsp.vreg(4), lanes(md), "{}", what);
+        assert_eq!(rsp.vreg(5), lanes(lo), "{}", what);
+    };
+
+    rsp.run(&[vu(VMUDH, 2, 0, 1, 0)]);
+    check(&mut rsp, 1, "vmudh");
+    rsp.run(&[vu(VMADH, 2, 0, 1, 0)]);
+    check(&mut rsp, 2, "vmadh");
+}
+
+#[test]
+fn dma_load_dmem_strided() {
+    let rsp = TestRsp::new();
+    let src: Vec<u8> = (0..64).collect();
+    for b in rsp.dmem().iter_mut() {
+        *b = 0xEE;
+    }
+
+    // 3 rows of 12 bytes (rounded up to 16), skipping 4 bytes after each
+    // row, to an unaligned address near the end of DMEM: the destination is
+    // aligned down, and the last row wraps around to the start of DMEM.
+    Sp::get_mut().dma_load_dmem(0xFE3, &src, 12, 3, 4);
+
+    let dmem = rsp.dmem();
+    assert_eq!(dmem[0xFDF], 0xEE);
+    assert_eq!(&dmem[0xFE0..0xFF0], &src[0..16]);
+    assert_eq!(&dmem[0xFF0..0x1000], &src[20..36]);
+    assert_eq!(&dmem[0x000..0x010], &src[40..56]);
+    assert_eq!(dmem[0x010], 0xEE);
+}
+
+#[test]
+fn vadd_accum_lo_wraps() {
+    let mut rsp = TestRsp::new();
+    let a = [0x7FFF, 0x7FFF, 0x4000, 0x8000, 0x8000, 0x8001, 0x0001, 0xFFFF];
+    let b = [0x0001, 0x7FFF, 0x4000, 0xFFFF, 0x8000, 0x8000, 0x0002, 0xFFFF];
+    rsp.set_vreg(0, lanes(a));
+    rsp.set_vreg(1, lanes(b));
+    // Carry set on lanes 0 and 4.
+    rsp.set_vreg(SpCop2::REG_VCO, 0x0011);
+    rsp.run(&[vu(VADD, 2, 0, 1, 0)]);
+
+    // vd is saturated to the signed 16-bit range, while the accumulator
+    // holds the plain 16-bit wraparound sum, carry included.
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x7FFF, 0x7FFF, 0x7FFF, 0x8000, 0x8000, 0x8000, 0x0003, 0xFFFE])
+    );
+    assert_eq!(
+        rsp.vreg(SpCop2::REG_ACCUM_LO),
+        lanes([0x8001, 0xFFFE, 0x8000, 0x7FFF, 0x0001, 0x0001, 0x0003, 0xFFFE])
+    );
+}
+
+#[test]
+fn dmem_view_byte_order() {
+    let rsp = TestRsp::new();
+    for (i, b) in rsp.dmem()[0xFF8..0x1000].iter_mut().enumerate() {
+        *b = 0x10 + i as u8;
+    }
+    for (i, b) in rsp.dmem()[0x000..0x008].iter_mut().enumerate() {
+        *b = 0x18 + i as u8;
+    }
+    let dmem: Vec<u8> = (0x10..0x20).collect();
+
+    // The region wraps around the end of DMEM.
+    let sp = Sp::get();
+    assert_eq!(sp.dmem_view::<u32, BigEndian>(0xFF8, 16), dmem);
+    assert_eq!(sp.dmem_view::<u8, LittleEndian>(0xFF8, 16), dmem);
+    assert_eq!(sp.dmem_view::<u16, LittleEndian>(0xFF8, 4), &[0x11, 0x10, 0x13, 0x12]);
+    let le = sp.dmem_view::<u32, LittleEndian>(0xFF8, 16);
+    for (be, le) in dmem.chunks(4).zip(le.chunks(4)) {
+        assert_eq!(le, &[be[3], be[2], be[1], be[0]]);
+    }
+    let le = sp.dmem_view::<u64, LittleEndian>(0xFF8, 16);
+    assert_eq!(&le[..8], &[0x17, 0x16, 0x15, 0x14, 0x13, 0x12, 0x11, 0x10]);
+
+    // DMEM itself is unchanged.
+    assert_eq!(&rsp.dmem()[0xFF8..0x1000], &dmem[..8]);
+}
+
+#[test]
+fn vmacf_chain_accum_range() {
+    let mut rsp = TestRsp::new();
+    let a = [0x7FFF, 0x8000, 0x8000, 0x7FFF, 0x1234, 0xFFFF, 0x0000, 0x4000];
+    let b = [0x7FFF, 0x8000, 0x7FFF, 0x8000, 0x5678, 0x0001, 0x7FFF, 0xC000];
+    rsp.set_vreg(0, lanes(a));
+    rsp.set_vreg(1, lanes(b));
+    for r in SpCop2::REG_ACCUM_LO..=SpCop2::REG_ACCUM_HI {
+        rsp.set_vreg(r, 0);
+    }
+
+    // Each VMACF adds 2*vs*vt to the accumulator, which stays well within
+    // the 48-bit signed range, so the debug checks must not fire.
+    const N: usize = 64;
+    rsp.run(&[vu(VMACF, 2, 0, 1, 0); N]);
+
+    let (mut lo, mut md, mut hi) = ([0u16; 8], [0u16; 8], [0u16; 8]);
+    for i in 0..8 {
+        let acc = i64::from(a[i] as i16) * i64::from(b[i] as i16) * 2 * N as i64;
+        assert!(acc >= -(1 << 47) && acc < (1 << 47));
+        lo[i] = acc as u16;
+        md[i] = (acc >> 16) as u16;
+        hi[i] = (acc >> 32) as u16;
+    }
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lanes(lo));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), lanes(md));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_HI), lanes(hi));
+}
+
+#[test]
+fn loadstore_effective_address() {
+    const LBV: u32 = 0x00;
+    let mut ctx = CpuContext::default();
+    ctx.regs[1] = 0x100;
+    ctx.regs[2] = 0x10C;
+    ctx.regs[3] = 0xFFFF_FFFF_FFFF_FFF5;
+
+    ctx.regs[4] = 0xFFC;
+    let ea = SpCop2::effective_address;
+
+    // LBV: offset is not scaled, and accesses a single byte.
+    assert_eq!(ea(lwc2(LBV, 0, 0, 3, 1), &ctx), (0x103, 1));
+    assert_eq!(ea(lwc2(LBV, 0, 0, -1, 1), &ctx), (0xFF, 1));
+    assert_eq!(ea(lwc2(LBV, 0, 0, 0, 3), &ctx), (0xFF5, 1));
+
+    // LQV: offset is scaled by 16, and accesses up to the end of the
+    // quadword. The base register wraps before masking to DMEM.
+    assert_eq!(ea(lwc2(LQV, 0, 0, 2, 1), &ctx), (0x120, 16));
+    assert_eq!(ea(lwc2(LQV, 0, 0, 1, 2), &ctx), (0x11C, 4));
+    assert_eq!(ea(lwc2(LQV, 0, 0, 1, 3), &ctx), (0x005, 11));
+    assert_eq!(ea(swc2(SQV, 0, 0, -1, 2), &ctx), (0x0FC, 4));
+
+    // LRV accesses the start of the quadword up to the address.
+    assert_eq!(ea(lwc2(LRV, 0, 0, 1, 2), &ctx), (0x110, 12));
+    assert_eq!(ea(lwc2(LRV, 0, 0, 0, 1), &ctx), (0x100, 0));
+
+    // LTV: offset is scaled by 16, and accesses 16 bytes from the
+    // double-word, wrapping around the end of DMEM.
+    assert_eq!(ea(lwc2(LTV, 0, 0, 1, 2), &ctx), (0x118, 16));
+    assert_eq!(ea(lwc2(LTV, 0, 0, 0, 1), &ctx), (0x100, 16));
+    assert_eq!(ea(lwc2(LTV, 0, 0, 0, 3), &ctx), (0xFF0, 16));
+    assert_eq!(ea(lwc2(LTV, 0, 0, 0, 4), &ctx), (0xFF8, 16));
+    assert_eq!(ea(lwc2(LTV, 0, 0, 0x3F, 2), &ctx), (0x4F8, 16));
+}
+
+#[test]
+fn vmacf_clamp_at_accum_extremes() {
+    let mut rsp = TestRsp::new();
+    // Accumulator (hi, md, lo), then vs and vt, for each lane.
+    let cases: [(u16, u16, u16, u16, u16); 8] = [
+        (0x7FFF, 0xFFFF, 0xFFFF, 0x0000, 0x0000), // +max
+        (0x7FFF, 0xFFFF, 0xFFFF, 0x0001, 0x0001), // +max, wraps to -max+1
+        (0x8000, 0x0000, 0x0000, 0x0000, 0x0000), // -max
+        (0x8000, 0x0000, 0x0000, 0xFFFF, 0x0001), // -max, wraps to +max-1
+        (0x0000, 0x8000, 0x0000, 0x0000, 0x0000), // just past i16 range
+        (0xFFFF, 0x7FFF, 0xFFFF, 0x0000, 0x0000), // just before i16 range
+        (0x7FFF, 0xFFFF, 0x0000, 0x0001, 0x7FFF), // close to +max
+        (0x8000, 0x0000, 0x0000, 0x7FFF, 0x7FFF), // close to -max
+    ];
+    let lane = |f: fn(&(u16, u16, u16, u16, u16)) -> u16| {
+        let mut l = [0u16; 8];
+        for (i, c) in cases.iter().enumerate() {
+            l[i] = f(c);
+        }
+        lanes(l)
+    };
+    rsp.set_vreg(SpCop2::REG_ACCUM_HI, lane(|c| c.0));
+    rsp.set_vreg(SpCop2::REG_ACCUM_MD, lane(|c| c.1));
+    rsp.set_vreg(SpCop2::REG_ACCUM_LO, lane(|c| c.2));
+    rsp.set_vreg(0, lane(|c| c.3));
+    rsp.set_vreg(1, lane(|c| c.4));
+    rsp.run(&[vu(VMACF, 2, 0, 1, 0)]);
+
+    // The accumulator wraps at 48 bits; vd is the signed clamp of its
+    // bits 16-47, so its sign always follows the accumulator.
+    let (mut res, mut hi, mut md, mut lo) = ([0u16; 8], [0u16; 8], [0u16; 8], [0u16; 8]);
+    for (i, &(h, m, l, a, b)) in cases.iter().enumerate() {
+        let acc = (i64::from(h) << 32) | (i64::from(m) << 16) | i64::from(l);
+        let acc = acc + i64::from(a as i16) * i64::from(b as i16) * 2;
+        let acc = (acc << 16) >> 16;
+        res[i] = (acc >> 16).max(-0x8000).min(0x7FFF) as u16;
+        hi[i] = (acc >> 32) as u16;
+        md[i] = (acc >> 16) as u16;
+        lo[i] = acc as u16;
+    }
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x7FFF, 0x8000, 0x8000, 0x7FFF, 0x7FFF, 0x8000, 0x7FFF, 0x8000])
+    );
+    assert_eq!(rsp.vreg(2), lanes(res));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_HI), lanes(hi));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), lanes(md));
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_LO), lanes(lo));
+}
+
+#[test]
+fn lpv_luv_element_wrap() {
+    let mut rsp = TestRsp::new();
+    for i in 0..0x20 {
+        rsp.dmem()[0x100 + i] = 0x40 + i as u8;
+    }
+
+    // Both addresses are 3 bytes into their double-word: the 16-byte window
+    // starts at 0x100 and 0x108 respectively.
+    for &addr in &[0x103, 0x10B] {
+        rsp.ctx.regs[1] = addr;
+        let window = addr as usize & !7;
+        for &(e, start) in &[(0, 3), (4, 15), (12, 7)] {
+            let bytes: Vec<u16> = (0..8)
+                .map(|i| u16::from(rsp.dmem()[window + ((start + i) & 15)]))
+                .collect();
+            let mut lpv = [0u16; 8];
+            let mut luv = [0u16; 8];
+            for (i, &b) in bytes.iter().enumerate() {
+                lpv[i] = b << 8;
+                luv[i] = b << 7;
+            }
+
+            rsp.run(&[lwc2(LPV, 2, e, 0, 1), lwc2(LUV, 3, e, 0, 1)]);
+            assert_eq!(rsp.vreg(2), lanes(lpv), "LPV addr={:x} e={}", addr, e);
+            assert_eq!(rsp.vreg(3), lanes(luv), "LUV addr={:x} e={}", addr, e);
+        }
+    }
+
+    // Element 4 at 0x103 starts at the last byte of the window, and wraps
+    // to its start.
+    rsp.ctx.regs[1] = 0x103;
+    rsp.run(&[lwc2(LPV, 2, 4, 0, 1)]);
+    assert_eq!(
+        rsp.vreg(2),
+        lanes([0x4F00, 0x4000, 0x4100, 0x4200, 0x4300, 0x4400, 0x4500, 0x4600])
+    );
+}
+
+#[test]
+fn lsv_ssv_element15() {
+    const LSV: u32 = 0x01;
+    let mut rsp = TestRsp::new();
+    let v1: u128 = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+    rsp.dmem()[0x100..0x102].copy_from_slice(&[0x12, 0x34]);
+    rsp.ctx.regs[1] = 0x100;
+
+    // LSV at element 15 only loads the first byte into register byte 15:
+    // the second byte is dropped and does not wrap to byte 0 (this matches
+    // the lsv_ssv golden test, recorded on hardware).
+    rsp.set_vreg(1, v1);
+    rsp.run(&[lwc2(LSV, 1, 15, 0, 1)]);
+    assert_eq!(rsp.vreg(1), (v1 & !0xFF) | 0x12);
+
+    // SSV at element 15 does wrap: it stores register bytes 15 and 0.
+    rsp.run(&[swc2(SSV, 1, 15, 1, 1)]);
+    assert_eq!(&rsp.dmem()[0x102..0x104], &[0x12, 0x00]);
+}
+
+#[test]
+fn strict_hw_quirks() {
+    const VMOV: u32 = 0x33;
+    const VRSQL: u32 = 0x35;
+    const VRSQH: u32 = 0x36;
+    let mut rsp = TestRsp::new();
+    let acc = lanes([0x1111; 8]);
+    let vs = lanes([0x0102, 0x0304, 0x0506, 0x0708, 0x090A, 0x0B0C, 0x0D0E, 0x0F10]);
+    let run = |rsp: &mut TestRsp| {
+        rsp.set_vreg(SpCop2::REG_ACCUM_MD, acc);
+        rsp.set_vreg(1, vs);
+        rsp.set_vreg(2, lanes([0x8000, 0, 0, 0, 0, 0, 0, 0]));
+        rsp.set_vreg(3, 0);
+        rsp.set_vreg(5, 0);
+        rsp.set_vreg(6, lanes([0xFFFF, 0x8000, 0, 0, 0, 0, 0, 0]));
+        rsp.set_vreg(8, lanes([0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17]));
+        rsp.set_vreg(9, 0);
+        rsp.run(&[
+            vu(VSAR, 4, 1, 0, 9),
+            vu(VRSQ, 3, 0, 2, 8),
+            vu(VRSQH, 3, 1, 2, 8),
+            // Double precision -32768: 0xFFFF in the high half, 0x8000 in
+            // the low half.
+            vu(VRSQH, 7, 0, 6, 8),
+            vu(VRSQL, 5, 0, 6, 9),
+            // VMOV v9[2] from v8, with element 1h.
+            vu(VMOV, 9, 2, 8, 5),
+        ]);
+    };
+
+    // Hardware: VSAR doesn't write the accumulator, VRSQ and VRSQL of -32768
+    // have a hardcoded result, and VMOV reads lane 1 (the lane that 1h
+    // broadcasts into lane 2).
+    run(&mut rsp);
+    assert_eq!(rsp.vreg(4), acc);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), acc);
+    assert_eq!(rsp.vreg(3), lanes([0x0000, 0xFFFF, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(rsp.vreg(5), 0);
+    assert_eq!(rsp.vreg(9), lanes([0, 0, 0x11, 0, 0, 0, 0, 0]));
+
+    // Documented behavior: VSAR writes VS into the accumulator, VRSQ and
+    // VRSQL of -32768 go through the ROM, and VMOV reads lane e (5).
+    RSPCPU::get_mut().cop2.set_strict_hw(false);
+    run(&mut rsp);
+    assert_eq!(rsp.vreg(4), acc);
+    assert_eq!(rsp.vreg(SpCop2::REG_ACCUM_MD), vs);
+    assert_eq!(rsp.vreg(3), lanes([0xFB7F, 0xFF4A, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(rsp.vreg(5), lanes([0xFB7F, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(rsp.vreg(9), lanes([0, 0, 0x15, 0, 0, 0, 0, 0]));
+    RSPCPU::get_mut().cop2.set_strict_hw(true);
+}
+
+#[test]
+fn subword_store_elements() {
+    const SBV: u32 = 0x00;
+    const SLV: u32 = 0x02;
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(1, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+    rsp.ctx.regs[1] = 0x200;
+
+    // Each store starts at register byte `e`, whatever its alignment, and
+    // wraps around the end of the register.
+    let cases: [(u32, usize, &[u8]); 9] = [
+        (SBV, 15, &[0xFF]),
+        (SSV, 1, &[0x11, 0x22]),
+        (SSV, 15, &[0xFF, 0x00]),
+        (SLV, 3, &[0x33, 0x44, 0x55, 0x66]),
+        (SLV, 14, &[0xEE, 0xFF, 0x00, 0x11]),
+        (SLV, 15, &[0xFF, 0x00, 0x11, 0x22]),
+        (SDV, 5, &[0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC]),
+        (SDV, 12, &[0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11, 0x22, 0x33]),
+        (SDV, 15, &[0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66]),
+    ];
+    for &(op, e, exp) in &cases {
+        for b in rsp.dmem()[0x200..0x210].iter_mut() {
+            *b = 0x99;
+        }
+        rsp.run(&[swc2(op, 1, e, 0, 1)]);
+        let dmem = &rsp.dmem()[0x200..0x210];
+        assert_eq!(&dmem[..exp.len()], exp, "op={} e={}", op, e);
+        assert!(dmem[exp.len()..].iter().all(|&b| b == 0x99), "op={} e={}", op, e);
+    }
+}
+
+#[test]
+fn ldv_sdv_high_elements_roundtrip() {
+    const LDV: u32 = 0x03;
+    let mut rsp = TestRsp::new();
+    rsp.set_vreg(1, 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF);
+    let prev = 0xA0A1_A2A3_A4A5_A6A7_A8A9_AAAB_ACAD_AEAFu128;
+    rsp.ctx.regs[1] = 0x100;
+
+    // SDV wraps around the end of the register, but LDV only writes the
+    // bytes up to the end of it and drops the rest, as in the ldv_sdv golden
+    // test: so loading back what was stored restores just bytes e..15.
+    let cases: [(usize, &[u8], u128); 2] = [
+        (
+            12,
+            &[0xCC, 0xDD, 0xEE, 0xFF, 0x00, 0x11, 0x22, 0x33],
+            0xA0A1_A2A3_A4A5_A6A7_A8A9_AAAB_CCDD_EEFF,
+        ),
+        (
+            14,
+            &[0xEE, 0xFF, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55],
+            0xA0A1_A2A3_A4A5_A6A7_A8A9_AAAB_ACAD_EEFF,
+        ),
+    ];
+    for &(e, stored, loaded) in &cases {
+        rsp.set_vreg(2, prev);
+        rsp.run(&[swc2(SDV, 1, e, 0, 1), lwc2(LDV, 2, e, 0, 1)]);
+        assert_eq!(&rsp.dmem()[0x100..0x108], stored, "e={}", e);
+        assert_eq!(rsp.vreg(2), loaded, "e={}", e);
+    }
+}
+
+#[test]
+fn mfc2_sign_extend() {
+    let mut rsp = TestRsp::new();
+    let v = [0x8000, 0xFFFF, 0x7FFF, 0x8001, 0x0000, 0xC0DE, 0x1234, 0x9ABC];
+    rsp.set_vreg(5, lanes(v));
+
+    // Every lane is sign-extended to 64 bits, whatever was in the GPR.
+    for (i, &x) in v.iter().enumerate() {
+        rsp.ctx.regs[1] = 0x5555_5555_5555_5555;
+        rsp.run(&[cop2_move(MFC2, 1, 5, i * 2)]);
+        assert_eq!(rsp.ctx.regs[1], i64::from(x as i16) as u64, "lane={}", i);
+    }
+
+    // Writes to $zero are dropped.
+    rsp.run(&[cop2_move(MFC2, 0, 5, 0)]);
+    assert_eq!(rsp.ctx.regs[0], 0);
+}
+
+#[test]
+fn invalid_reg_log_record() {
+    let (logger, records) = CaptureDrain::logger();
+    let cop2 = SpCop2::new("test", logger.new(o!("unit" => "vu"))).unwrap();
+    let ctx = CpuContext::default();
+
+    assert_eq!(cop2.reg(&ctx, 40), 0);
+    let errs = records.find("read of invalid COP2 register");
+    assert_eq!(errs.len(), 1);
+    assert_eq!(errs[0].level, Level::Error);
+    assert_eq!(errs[0].get("reg"), Some("40"));
+    assert_eq!(errs[0].get("unit"), Some("vu"));
+    assert_eq!(errs[0].get("val"), None);
+}
+
+#[test]
+fn log_dmem_region_dump() {
+    let (logger, records) = CaptureDrain::logger();
+    let rsp = TestRsp::with_logger(logger);
+    for (i, b) in rsp.dmem()[0x100..0x114].iter_mut().enumerate() {
+        *b = 0xE0 + i as u8;
+    }
+    for b in rsp.dmem()[0xFF8..0x1000].iter_mut() {
+        *b = 0x5A;
+    }
+
+    RSPCPU::get().cop2.log_dmem_region(0x100, 20);
+    let dump = records.find("DMEM dump");
+    assert_eq!(dump.len(), 2);
+    assert_eq!(dump[0].level, Level::Debug);
+    assert_eq!(dump[0].get("addr"), Some("0x00000100"));
+    assert_eq!(
+        dump[0].get("data"),
+        Some("e0 e1 e2 e3 e4 e5 e6 e7 e8 e9 ea eb ec ed ee ef")
+    );
+    assert_eq!(dump[1].get("addr"), Some("0x00000110"));
+    assert_eq!(dump[1].get("data"), Some("f0 f1 f2 f3"));
+
+    // The region is clamped to the end of DMEM.
+    RSPCPU::get().cop2.log_dmem_region(0xFF8, 0x100);
+    RSPCPU::get().cop2.log_dmem_region(0x1000, 0x10);
+    let dump = records.find("DMEM dump");
+    assert_eq!(dump.len(), 3);
+    assert_eq!(dump[2].get("addr"), Some("0x00000ff8"));
+    assert_eq!(dump[2].get("data"), Some("5a 5a 5a 5a 5a 5a 5a 5a"));
+}
+
+#[test]
+fn state_diff_reports_lane() {
+    let mut rsp = TestRsp::new();
+    let v5 = lanes([0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777, 0x8888]);
+    rsp.set_vreg(5, v5);
+    let before = RSPCPU::get().cop2.snapshot();
+    assert_eq!(before.diff(&before), None);
+
+    rsp.set_vreg(5, v5 ^ lanes([0, 0, 0, 0x0100, 0, 0, 0, 0]));
+    let after = RSPCPU::get().cop2.snapshot();
+    assert_ne!(before, after);
+    let diff = before.diff(&after).unwrap();
+    assert_eq!(
+        diff,
+        StateDiff {
+            field: "v5",
+            lane: Some(3),
+        }
+    );
+    assert_eq!(diff.to_string(), "v5 lane 3");
+    assert_eq!(after.diff(&before), Some(diff));
+
+    rsp.set_vreg(5, v5);
+    assert_eq!(RSPCPU::get().cop2.snapshot(), before);
+}